@@ -0,0 +1,246 @@
+use crate::lib::{Camera, Float, Hitable, ThinLensCamera, Vector3};
+use crate::render::{render, Settings};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Add, Mul, Range, Sub};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraKey {
+    pub time: Float,
+    pub lookfrom: Vector3<Float>,
+    pub lookat: Vector3<Float>,
+    pub vfov: Float,
+    pub focus_dist: Float,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // Catmull-Rom spline through the keys, with tangents scaled by the time
+    // between them so unevenly spaced keys don't make the speed jump.
+    Spline,
+}
+
+// A thin lens camera moving through keyframes. Before the first key and
+// after the last one it holds still.
+pub struct CameraPath {
+    pub keys: Vec<CameraKey>,
+    pub interpolation: Interpolation,
+    pub vup: Vector3<Float>,
+    pub aperture: Float,
+}
+
+fn hermite<T>(p0: T, m0: T, p1: T, m1: T, s: Float, dt: Float) -> T
+where
+    T: Copy + Add<Output = T> + Mul<Float, Output = T>,
+{
+    let (s2, s3) = (s * s, s * s * s);
+    p0 * (2.0 * s3 - 3.0 * s2 + 1.0)
+        + m0 * ((s3 - 2.0 * s2 + s) * dt)
+        + p1 * (3.0 * s2 - 2.0 * s3)
+        + m1 * ((s3 - s2) * dt)
+}
+
+impl CameraPath {
+    // Keys are sorted by time, which has to be finite.
+    pub fn new(
+        mut keys: Vec<CameraKey>,
+        interpolation: Interpolation,
+        vup: Vector3<Float>,
+        aperture: Float,
+    ) -> CameraPath {
+        assert!(!keys.is_empty(), "a camera path needs at least one key");
+        assert!(
+            keys.iter().all(|key| key.time.is_finite()),
+            "camera key times must be finite"
+        );
+        keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        CameraPath {
+            keys,
+            interpolation,
+            vup,
+            aperture,
+        }
+    }
+
+    fn interpolate<T, F>(&self, time: Float, value: F) -> T
+    where
+        T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Float, Output = T>,
+        F: Fn(&CameraKey) -> T,
+    {
+        let keys = &self.keys;
+        let last = keys.len() - 1;
+        if time <= keys[0].time {
+            return value(&keys[0]);
+        }
+        if time >= keys[last].time {
+            return value(&keys[last]);
+        }
+        let i = keys.iter().rposition(|key| key.time <= time).unwrap();
+        let dt = keys[i + 1].time - keys[i].time;
+        let s = (time - keys[i].time) / dt;
+        let (p0, p1) = (value(&keys[i]), value(&keys[i + 1]));
+        match self.interpolation {
+            Interpolation::Linear => p0 + (p1 - p0) * s,
+            Interpolation::Spline => {
+                let tangent = |k: usize| {
+                    let (a, b) = (k.saturating_sub(1), (k + 1).min(last));
+                    (value(&keys[b]) - value(&keys[a])) * (1.0 / (keys[b].time - keys[a].time))
+                };
+                hermite(p0, tangent(i), p1, tangent(i + 1), s, dt)
+            }
+        }
+    }
+
+    pub fn key_at(&self, time: Float) -> CameraKey {
+        CameraKey {
+            time,
+            lookfrom: self.interpolate(time, |key| key.lookfrom),
+            lookat: self.interpolate(time, |key| key.lookat),
+            vfov: self.interpolate(time, |key| key.vfov),
+            focus_dist: self.interpolate(time, |key| key.focus_dist),
+        }
+    }
+
+    pub fn camera_at(&self, time: Float, aspect: Float) -> ThinLensCamera {
+        let key = self.key_at(time);
+        ThinLensCamera::new(
+            key.lookfrom,
+            key.lookat,
+            self.vup,
+            key.vfov,
+            aspect,
+            self.aperture,
+            key.focus_dist,
+        )
+    }
+}
+
+// The file name for a frame, with the run of `#` in the pattern replaced by
+// the frame number padded to its length, as in `turntable_####.ppm`.
+pub fn frame_path(pattern: &str, frame: usize) -> io::Result<String> {
+    let start = pattern.find('#').ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sequence pattern needs # for the frame number",
+        )
+    })?;
+    let width = pattern[start..].chars().take_while(|&c| c == '#').count();
+    Ok(format!(
+        "{}{:0width$}{}",
+        &pattern[..start],
+        frame,
+        &pattern[start + width..],
+        width = width
+    ))
+}
+
+// Renders every frame in `frames` to its own PPM file. `camera` gives the
+// camera for a frame, such as `|frame| path.camera_at(frame as Float / fps,
+// aspect)`. Each frame gets its own seed so the noise doesn't stand still
+// while the picture moves.
+pub fn render_sequence<C, F>(
+    world: &dyn Hitable,
+    settings: &Settings,
+    frames: Range<usize>,
+    pattern: &str,
+    camera: F,
+) -> io::Result<()>
+where
+    C: Camera,
+    F: Fn(usize) -> C,
+{
+    for frame in frames {
+        let mut frame_settings = settings.clone();
+        frame_settings.seed = settings.seed.wrapping_add(frame as u64);
+        let image = render(world, &camera(frame), &frame_settings);
+        let mut out = BufWriter::new(File::create(frame_path(pattern, frame)?)?);
+        image.write_ppm(&mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(time: Float, x: Float) -> CameraKey {
+        CameraKey {
+            time,
+            lookfrom: Vector3 { x, y: 0.0, z: 0.0 },
+            lookat: Vector3 { x, y: 0.0, z: -1.0 },
+            vfov: 40.0,
+            focus_dist: 1.0,
+        }
+    }
+
+    fn up() -> Vector3<Float> {
+        Vector3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        }
+    }
+
+    #[test]
+    fn keys_are_sorted_by_time() {
+        let path = CameraPath::new(
+            vec![key(2.0, 4.0), key(0.0, 0.0), key(1.0, 1.0)],
+            Interpolation::Linear,
+            up(),
+            0.0,
+        );
+        let times: Vec<Float> = path.keys.iter().map(|key| key.time).collect();
+        assert_eq!(times, vec![0.0, 1.0, 2.0]);
+        assert_eq!(path.key_at(1.5).lookfrom.x, 2.5);
+        assert_eq!(path.key_at(-1.0).lookfrom.x, 0.0);
+        assert_eq!(path.key_at(3.0).lookfrom.x, 4.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "camera key times must be finite")]
+    fn nan_times_are_rejected() {
+        CameraPath::new(
+            vec![key(0.0, 0.0), key(Float::NAN, 1.0)],
+            Interpolation::Spline,
+            up(),
+            0.0,
+        );
+    }
+
+    // Unevenly spaced keys, so that the tangent scaling matters.
+    #[test]
+    fn splines_pass_through_the_keys() {
+        let keys = vec![key(0.0, 0.0), key(1.0, 2.0), key(3.0, 1.0), key(4.0, 5.0)];
+        let path = CameraPath::new(keys.clone(), Interpolation::Spline, up(), 0.0);
+        for key in keys.iter() {
+            let at = path.key_at(key.time);
+            assert!((at.lookfrom.x - key.lookfrom.x).abs() < 1e-5);
+            assert!((at.lookat.x - key.lookat.x).abs() < 1e-5);
+        }
+        // Between the keys it curves away from the straight line, and it
+        // keeps its speed through a key.
+        let linear = CameraPath::new(keys, Interpolation::Linear, up(), 0.0);
+        assert!((path.key_at(2.0).lookfrom.x - linear.key_at(2.0).lookfrom.x).abs() > 0.1);
+        let x = |time: Float| path.key_at(time).lookfrom.x;
+        let h = 1e-2;
+        let before = (x(1.0) - x(1.0 - h)) / h;
+        let after = (x(1.0 + h) - x(1.0)) / h;
+        assert!((before - after).abs() < 0.05, "{} then {}", before, after);
+    }
+
+    #[test]
+    fn frame_paths_pad_to_the_run_of_hashes() {
+        assert_eq!(frame_path("frame_####.ppm", 7).unwrap(), "frame_0007.ppm");
+        assert_eq!(
+            frame_path("frame_####.ppm", 12345).unwrap(),
+            "frame_12345.ppm"
+        );
+        assert_eq!(frame_path("#.ppm", 42).unwrap(), "42.ppm");
+        // Only the first run is the frame number.
+        assert_eq!(frame_path("take##_#.ppm", 3).unwrap(), "take03_#.ppm");
+        let error = frame_path("frame.ppm", 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::lib::{to_f32, Float, Hitable, InnerSpace, Ray, Vector3};
+use crate::render::{sky, Image, Region};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Aov {
+    Depth,
+    Position,
+    Normal,
+    Albedo,
+    ObjectId,
+    MaterialId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Position,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+        }
+    }
+
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Depth => &["Z"],
+            Aov::Position => &["X", "Y", "Z"],
+            Aov::Normal => &["X", "Y", "Z"],
+            Aov::Albedo => &["R", "G", "B"],
+            Aov::ObjectId => &["id"],
+            Aov::MaterialId => &["id"],
+        }
+    }
+}
+
+// A named image with an arbitrary number of interleaved float channels,
+// stored top row first.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub name: String,
+    pub channels: Vec<String>,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<Float>,
+}
+
+impl Layer {
+    pub fn new(name: &str, channels: &[&str], width: usize, height: usize) -> Layer {
+        Layer {
+            name: name.to_string(),
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            width,
+            height,
+            data: vec![0.0; width * height * channels.len()],
+        }
+    }
+
+    pub fn from_image(name: &str, image: &Image) -> Layer {
+        let mut layer = Layer::new(name, &["R", "G", "B"], image.width, image.height);
+        for (i, col) in image.pixels.iter().enumerate() {
+            layer.data[3 * i..3 * i + 3].copy_from_slice(&[col.x, col.y, col.z]);
+        }
+        layer
+    }
+
+    pub fn crop(&self, region: Region) -> Layer {
+        let region = region.clamp(self.width, self.height);
+        let channels: Vec<&str> = self.channels.iter().map(|c| c.as_str()).collect();
+        let mut layer = Layer::new(&self.name, &channels, region.width, region.height);
+        for y in 0..region.height {
+            for x in 0..region.width {
+                layer
+                    .pixel_mut(x, y)
+                    .copy_from_slice(self.pixel(region.x + x, region.y + y));
+            }
+        }
+        layer
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> &[Float] {
+        let n = self.channels.len();
+        let i = (y * self.width + x) * n;
+        &self.data[i..i + n]
+    }
+
+    pub fn pixel_mut(&mut self, x: usize, y: usize) -> &mut [Float] {
+        let n = self.channels.len();
+        let i = (y * self.width + x) * n;
+        &mut self.data[i..i + n]
+    }
+
+    // Portable float map, the simplest multi-channel float format around.
+    // Only one and three channel layers can be represented.
+    pub fn write_pfm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let magic = match self.channels.len() {
+            1 => "Pf",
+            3 => "PF",
+            n => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("pfm cannot store {} channels", n),
+                ))
+            }
+        };
+        write!(out, "{}\n{} {}\n-1.0\n", magic, self.width, self.height)?;
+        let row = self.width * self.channels.len();
+        for y in (0..self.height).rev() {
+            for value in self.data[y * row..(y + 1) * row].iter() {
+                out.write_all(&to_f32(*value).to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn material_ids(world: &dyn Hitable) -> HashMap<usize, usize> {
+    let mut materials = Vec::new();
+    world.materials(&mut materials);
+    let mut ids = HashMap::new();
+    for material in materials {
+        let next_id = ids.len();
+        ids.entry(material.identity()).or_insert(next_id);
+    }
+    ids
+}
+
+// Accumulates the enabled AOVs for the primary rays of a render. Depth,
+// position and normal describe the surface, so they are averaged over the
+// samples that hit it, and the normal is renormalized. Albedo is averaged
+// over all samples with the sky standing in for misses, which blends it by
+// coverage at silhouettes like the beauty pass. IDs come from the first
+// sample since averaging them is meaningless. Material IDs number the
+// world's materials in scene order, so they don't depend on which pixels are
+// rendered. Misses get an infinite depth, the sky as albedo and -1 as IDs.
+pub struct AovPass {
+    pub aovs: Vec<Aov>,
+    pub width: usize,
+    pub height: usize,
+    pub(crate) sums: Vec<PixelSums>,
+    material_ids: Option<HashMap<usize, usize>>,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct PixelSums {
+    pub(crate) samples: usize,
+    pub(crate) hits: usize,
+    pub(crate) depth: Float,
+    pub(crate) position: [Float; 3],
+    pub(crate) normal: [Float; 3],
+    pub(crate) albedo: [Float; 3],
+    pub(crate) object_id: Option<Float>,
+    pub(crate) material_id: Option<Float>,
+}
+
+fn accumulate(sum: &mut [Float; 3], value: Vector3<Float>) {
+    sum[0] += value.x;
+    sum[1] += value.y;
+    sum[2] += value.z;
+}
+
+fn average(out: &mut [Float], sum: &[Float; 3], count: Float) {
+    for (out, sum) in out.iter_mut().zip(sum.iter()) {
+        *out = sum / count;
+    }
+}
+
+impl AovPass {
+    pub fn new(aovs: &[Aov], width: usize, height: usize) -> AovPass {
+        let pixels = if aovs.is_empty() { 0 } else { width * height };
+        AovPass {
+            aovs: aovs.to_vec(),
+            width,
+            height,
+            sums: vec![PixelSums::default(); pixels],
+            material_ids: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aovs.is_empty()
+    }
+
+    // `pixel` is the index of the pixel in the layers, top row first.
+    pub fn add_sample(&mut self, pixel: usize, ray: &Ray, world: &dyn Hitable) {
+        let material_ids = self.material_ids.get_or_insert_with(|| material_ids(world));
+        let sums = &mut self.sums[pixel];
+        sums.samples += 1;
+        match world.hit(ray, 0.0, Float::MAX) {
+            Some(record) => {
+                sums.hits += 1;
+                sums.depth += record.t;
+                accumulate(&mut sums.position, record.p);
+                accumulate(&mut sums.normal, record.normal);
+                accumulate(&mut sums.albedo, record.material.albedo(&record));
+                if sums.object_id.is_none() {
+                    let material_id = material_ids
+                        .get(&record.material.identity())
+                        .map_or(-1.0, |&id| id as Float);
+                    sums.object_id = Some(record.object_id as Float);
+                    sums.material_id = Some(material_id);
+                }
+            }
+            None => {
+                accumulate(&mut sums.albedo, sky(ray));
+                if sums.object_id.is_none() {
+                    sums.object_id = Some(-1.0);
+                    sums.material_id = Some(-1.0);
+                }
+            }
+        }
+    }
+
+    pub fn layers(&self) -> Vec<Layer> {
+        let mut layers: Vec<Layer> = self
+            .aovs
+            .iter()
+            .map(|aov| Layer::new(aov.name(), aov.channels(), self.width, self.height))
+            .collect();
+        for (index, pixel) in self.sums.iter().enumerate() {
+            let samples = pixel.samples.max(1) as Float;
+            let hits = pixel.hits.max(1) as Float;
+            for (aov, layer) in self.aovs.iter().zip(layers.iter_mut()) {
+                let out = layer.pixel_mut(index % self.width, index / self.width);
+                match aov {
+                    Aov::Depth => {
+                        out[0] = if pixel.hits == 0 {
+                            Float::INFINITY
+                        } else {
+                            pixel.depth / hits
+                        }
+                    }
+                    Aov::Position => average(out, &pixel.position, hits),
+                    Aov::Normal => {
+                        let sum = Vector3 {
+                            x: pixel.normal[0],
+                            y: pixel.normal[1],
+                            z: pixel.normal[2],
+                        };
+                        if sum.magnitude2() > 0.0 {
+                            let normal = sum.normalize();
+                            out.copy_from_slice(&[normal.x, normal.y, normal.z]);
+                        }
+                    }
+                    Aov::Albedo => average(out, &pixel.albedo, samples),
+                    Aov::ObjectId => out[0] = pixel.object_id.unwrap_or(-1.0),
+                    Aov::MaterialId => out[0] = pixel.material_id.unwrap_or(-1.0),
+                }
+            }
+        }
+        layers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{Lambertian, Material, Sphere};
+    use std::rc::Rc;
+
+    fn sphere() -> Sphere {
+        Sphere {
+            center: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -2.0,
+            },
+            radius: 1.0,
+            material: Box::new(Lambertian {
+                albedo: Vector3 {
+                    x: 0.8,
+                    y: 0.4,
+                    z: 0.2,
+                },
+            }),
+        }
+    }
+
+    // A pixel on a silhouette, with one sample on the sphere and one past it.
+    #[test]
+    fn edge_pixel_keeps_unit_normal_and_blends_albedo() {
+        let world = sphere();
+        let hit = Ray::new(
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 0.3,
+                y: 0.0,
+                z: -1.0,
+            },
+        );
+        let miss = Ray::new(
+            hit.origin(),
+            Vector3 {
+                x: 0.0,
+                y: 2.0,
+                z: -1.0,
+            },
+        );
+        let mut pass = AovPass::new(&[Aov::Normal, Aov::Albedo, Aov::Depth], 1, 1);
+        pass.add_sample(0, &hit, &world);
+        pass.add_sample(0, &miss, &world);
+        let layers = pass.layers();
+
+        let record = world.hit(&hit, 0.0, Float::MAX).unwrap();
+        let normal = layers[0].pixel(0, 0);
+        assert!((normal[0] - record.normal.x).abs() < 1e-6);
+        assert!((normal[1] - record.normal.y).abs() < 1e-6);
+        assert!((normal[2] - record.normal.z).abs() < 1e-6);
+
+        let expected = (Vector3 {
+            x: 0.8,
+            y: 0.4,
+            z: 0.2,
+        } + sky(&miss))
+            / 2.0;
+        let albedo = layers[1].pixel(0, 0);
+        assert!((albedo[0] - expected.x).abs() < 1e-6);
+        assert!((albedo[1] - expected.y).abs() < 1e-6);
+        assert!((albedo[2] - expected.z).abs() < 1e-6);
+
+        assert!((layers[2].pixel(0, 0)[0] - record.t).abs() < 1e-6);
+    }
+
+    // Objects sharing a material through an Rc get one ID, numbered by where
+    // the material first appears in the world rather than where it's hit.
+    #[test]
+    fn material_ids_follow_scene_order() {
+        let shared: Rc<dyn Material> = Rc::new(Lambertian {
+            albedo: Vector3 {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            },
+        });
+        let at = |x: Float, material: Box<dyn Material>| Sphere {
+            center: Vector3 { x, y: 0.0, z: -2.0 },
+            radius: 0.4,
+            material,
+        };
+        let world = vec![
+            at(-1.0, Box::new(shared.clone())),
+            at(0.0, sphere().material),
+            at(1.0, Box::new(shared)),
+        ];
+        let mut pass = AovPass::new(&[Aov::ObjectId, Aov::MaterialId], 3, 1);
+        for (pixel, &x) in [1.0, 0.0, -1.0].iter().enumerate() {
+            let ray = Ray::new(
+                Vector3 { x, y: 0.0, z: 0.0 },
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+            );
+            pass.add_sample(pixel, &ray, &world);
+        }
+        let layers = pass.layers();
+        assert_eq!(layers[0].data, vec![2.0, 1.0, 0.0]);
+        assert_eq!(layers[1].data, vec![0.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::lib::{consts, random_in_unit_disk, Float};
+use crate::render::Image;
+use crate::rng;
+
+// Shape of a thin lens's aperture, which out-of-focus highlights take on.
+#[derive(Clone)]
+pub enum Aperture {
+    Circle,
+    // A diaphragm of straight blades, rotated by `rotation` degrees.
+    Polygon { blades: u32, rotation: Float },
+    Mask(ApertureMask),
+}
+
+// Aperture cut from an image, whose luminance is its transmission. The image
+// covers the square around the lens, with its top row up.
+#[derive(Clone)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    cdf: Vec<Float>,
+}
+
+impl ApertureMask {
+    pub fn new(image: &Image) -> ApertureMask {
+        let mut total = 0.0;
+        let cdf = image
+            .pixels
+            .iter()
+            .map(|p| {
+                total += (0.2126 * p.x + 0.7152 * p.y + 0.0722 * p.z).max(0.0);
+                total
+            })
+            .collect();
+        ApertureMask {
+            width: image.width,
+            height: image.height,
+            cdf,
+        }
+    }
+
+    // A pixel picked in proportion to its transmission, then a uniform point
+    // in it. An all black mask lets everything through.
+    fn sample(&self) -> (Float, Float) {
+        let total = self.cdf.last().cloned().unwrap_or(0.0);
+        if total <= 0.0 {
+            return (2.0 * rng::random() - 1.0, 2.0 * rng::random() - 1.0);
+        }
+        let target = rng::random() * total;
+        let pixel = self
+            .cdf
+            .partition_point(|&c| c <= target)
+            .min(self.cdf.len() - 1);
+        let (i, j) = (pixel % self.width, pixel / self.width);
+        let x = (i as Float + rng::random()) / self.width as Float;
+        let y = (j as Float + rng::random()) / self.height as Float;
+        (2.0 * x - 1.0, 1.0 - 2.0 * y)
+    }
+}
+
+impl Aperture {
+    // A point on the aperture, scaled to the unit disk. Masks cover the
+    // square around it.
+    pub fn sample(&self) -> (Float, Float) {
+        match self {
+            Aperture::Circle => {
+                let p = random_in_unit_disk();
+                (p.x, p.y)
+            }
+            Aperture::Polygon { blades, rotation } => {
+                let blades = (*blades).max(3);
+                let sector = ((rng::random() * blades as Float) as u32).min(blades - 1);
+                let step = 2.0 * consts::PI / blades as Float;
+                let angle = rotation.to_radians() + sector as Float * step;
+                // Uniform in the triangle between the center and the edge.
+                let (mut a, mut b) = (rng::random(), rng::random());
+                if a + b > 1.0 {
+                    a = 1.0 - a;
+                    b = 1.0 - b;
+                }
+                (
+                    a * angle.cos() + b * (angle + step).cos(),
+                    a * angle.sin() + b * (angle + step).sin(),
+                )
+            }
+            Aperture::Mask(mask) => mask.sample(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Vector3;
+
+    // Inside the regular polygon with a corner at `rotation` degrees when
+    // the point is within the apothem along every edge's normal.
+    fn inside_polygon(x: Float, y: Float, blades: u32, rotation: Float) -> bool {
+        let step = 2.0 * consts::PI / blades as Float;
+        let apothem = (step / 2.0).cos();
+        (0..blades).all(|k| {
+            let angle = rotation.to_radians() + (k as Float + 0.5) * step;
+            x * angle.cos() + y * angle.sin() <= apothem + 1e-5
+        })
+    }
+
+    #[test]
+    fn polygon_samples_stay_inside_the_diaphragm() {
+        for &(blades, rotation) in [(3, 0.0), (5, 18.0), (6, 7.5), (9, 0.0)].iter() {
+            let aperture = Aperture::Polygon { blades, rotation };
+            // Every sector gets its share.
+            let mut sectors = vec![0; blades as usize];
+            for _ in 0..2000 {
+                let (x, y) = aperture.sample();
+                assert!(inside_polygon(x, y, blades, rotation), "({}, {})", x, y);
+                let angle = (y.atan2(x) - rotation.to_radians()).rem_euclid(2.0 * consts::PI);
+                let sector = (angle / (2.0 * consts::PI) * blades as Float) as usize;
+                sectors[sector.min(blades as usize - 1)] += 1;
+            }
+            let expected = 2000 / blades as usize;
+            assert!(sectors.iter().all(|&n| n > expected / 2), "{:?}", sectors);
+        }
+        // Fewer than three blades make a triangle.
+        let aperture = Aperture::Polygon {
+            blades: 1,
+            rotation: 0.0,
+        };
+        for _ in 0..200 {
+            let (x, y) = aperture.sample();
+            assert!(inside_polygon(x, y, 3, 0.0));
+        }
+    }
+
+    fn mask(width: usize, height: usize, open: &[(usize, usize, Float)]) -> ApertureMask {
+        let mut image = Image::new(width, height);
+        for &(i, j, value) in open.iter() {
+            image.pixels[j * width + i] = Vector3 {
+                x: value,
+                y: value,
+                z: value,
+            };
+        }
+        ApertureMask::new(&image)
+    }
+
+    #[test]
+    fn mask_samples_stay_in_its_open_pixels() {
+        // The top right pixel of four, and a bottom left one letting a
+        // third as much through.
+        let aperture = Aperture::Mask(mask(4, 4, &[(3, 0, 0.9), (0, 3, 0.3)]));
+        let mut top_right = 0;
+        for _ in 0..4000 {
+            let (x, y) = aperture.sample();
+            let (high, low) = (0.5..=1.0, -1.0..=-0.5);
+            if high.contains(&x) && high.contains(&y) {
+                top_right += 1;
+            } else {
+                assert!(low.contains(&x) && low.contains(&y), "({}, {})", x, y);
+            }
+        }
+        assert!((top_right as Float / 4000.0 - 0.75).abs() < 0.03);
+
+        let aperture = Aperture::Mask(mask(3, 3, &[]));
+        for _ in 0..200 {
+            let (x, y) = aperture.sample();
+            assert!(x.abs() <= 1.0 && y.abs() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,409 @@
+// Renders scenes submitted over HTTP, one at a time in the order they come
+// in. Scenes are in the text format read by `Scene::read`.
+//
+//   POST /jobs             scene text, answers 201 with {"id":0}
+//   GET  /jobs             every job, as below
+//   GET  /jobs/0           {"id":0,"status":"rendering","passes":3,"samples":16}
+//   GET  /jobs/0/image     the image so far, as a PPM
+//   DELETE /jobs/0         stops the job if it's running and drops its image
+//
+// Scenes over a megabyte are refused with 413, and ones that don't read
+// or ask for more than `Scene::read` allows with 400. Only `MAX_JOBS` jobs
+// are kept; past that new ones get 503 until old ones are deleted. It
+// listens on 127.0.0.1:8000 unless given another address.
+use rtweekend::render::{accumulate_pass, Accumulator, Image};
+use rtweekend::scene::Scene;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Cursor, Read};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Queued,
+    Rendering,
+    Done,
+    Failed,
+}
+
+struct Job {
+    status: Status,
+    passes: usize,
+    samples: usize,
+    error: Option<String>,
+    image: Option<Image>,
+}
+
+// Ids aren't reused, so one that was deleted stays not found.
+#[derive(Default)]
+struct Store {
+    next: usize,
+    jobs: BTreeMap<usize, Job>,
+}
+
+type Jobs = Arc<Mutex<Store>>;
+
+const MAX_BODY: usize = 1 << 20;
+const MAX_JOBS: usize = 16;
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn job_json(id: usize, job: &Job) -> String {
+    let status = match job.status {
+        Status::Queued => "queued",
+        Status::Rendering => "rendering",
+        Status::Done => "done",
+        Status::Failed => "failed",
+    };
+    let mut json = format!(
+        "{{\"id\":{},\"status\":\"{}\",\"passes\":{},\"samples\":{}",
+        id, status, job.passes, job.samples
+    );
+    if let Some(ref error) = job.error {
+        json.push_str(&format!(",\"error\":{}", json_string(error)));
+    }
+    json.push('}');
+    json
+}
+
+fn respond(code: u16, content_type: &str, body: Vec<u8>) -> Response<Cursor<Vec<u8>>> {
+    let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+    Response::from_data(body)
+        .with_status_code(StatusCode(code))
+        .with_header(header)
+}
+
+fn json(code: u16, body: String) -> Response<Cursor<Vec<u8>>> {
+    respond(code, "application/json", body.into_bytes())
+}
+
+fn error(code: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json(code, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+// Renders queued jobs one pass at a time, so their progress and the image so
+// far can be looked at while they run. The scene is read again here because
+// the world can't be sent between threads. A job deleted while it waits or
+// renders is dropped after the pass in progress.
+fn work(jobs: Jobs, queue: mpsc::Receiver<(usize, String)>) {
+    for (id, text) in queue {
+        let scene = Scene::read(&mut text.as_bytes());
+        match (jobs.lock().unwrap().jobs.get_mut(&id), &scene) {
+            (Some(job), Ok(_)) => job.status = Status::Rendering,
+            (Some(job), Err(err)) => {
+                job.status = Status::Failed;
+                job.error = Some(err.to_string());
+            }
+            (None, _) => (),
+        }
+        let scene = match scene {
+            Ok(ref scene) if jobs.lock().unwrap().jobs.contains_key(&id) => scene,
+            _ => continue,
+        };
+        let settings = &scene.settings;
+        let mut accumulator = Accumulator::new(settings);
+        while accumulator.passes < settings.samples {
+            accumulate_pass(&scene.world, &scene.camera, settings, &mut accumulator);
+            let image = accumulator.image();
+            match jobs.lock().unwrap().jobs.get_mut(&id) {
+                Some(job) => {
+                    job.passes = accumulator.passes;
+                    job.image = Some(image);
+                }
+                None => break,
+            }
+        }
+        if let Some(job) = jobs.lock().unwrap().jobs.get_mut(&id) {
+            job.status = Status::Done;
+        }
+    }
+}
+
+fn handle(
+    request: &mut Request,
+    jobs: &Jobs,
+    queue: &Sender<(usize, String)>,
+) -> Response<Cursor<Vec<u8>>> {
+    let path: Vec<String> = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(String::from)
+        .collect();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    let id = |part: &str| {
+        part.parse::<usize>()
+            .ok()
+            .filter(|id| jobs.lock().unwrap().jobs.contains_key(id))
+    };
+
+    match (request.method(), path.as_slice()) {
+        (Method::Post, ["jobs"]) => {
+            if jobs.lock().unwrap().jobs.len() >= MAX_JOBS {
+                return error(503, "too many jobs, delete some first");
+            }
+            if request
+                .body_length()
+                .map_or(false, |length| length > MAX_BODY)
+            {
+                return error(413, "scene too large");
+            }
+            // The length isn't always given, so the read stops past the limit.
+            let mut body = Vec::new();
+            if request
+                .as_reader()
+                .take(MAX_BODY as u64 + 1)
+                .read_to_end(&mut body)
+                .is_err()
+            {
+                return error(400, "can't read scene");
+            }
+            if body.len() > MAX_BODY {
+                return error(413, "scene too large");
+            }
+            let text = match String::from_utf8(body) {
+                Ok(text) => text,
+                Err(_) => return error(400, "scene is not UTF-8"),
+            };
+            let scene = match Scene::read(&mut text.as_bytes()) {
+                Ok(scene) => scene,
+                Err(err) => return error(400, &err.to_string()),
+            };
+            let mut store = jobs.lock().unwrap();
+            // Another request may have filled the store while this one read.
+            if store.jobs.len() >= MAX_JOBS {
+                return error(503, "too many jobs, delete some first");
+            }
+            let id = store.next;
+            store.next += 1;
+            store.jobs.insert(
+                id,
+                Job {
+                    status: Status::Queued,
+                    passes: 0,
+                    samples: scene.settings.samples,
+                    error: None,
+                    image: None,
+                },
+            );
+            queue.send((id, text)).unwrap();
+            json(201, format!("{{\"id\":{}}}", id))
+        }
+        (Method::Get, ["jobs"]) => {
+            let store = jobs.lock().unwrap();
+            let list: Vec<String> = store
+                .jobs
+                .iter()
+                .map(|(&id, job)| job_json(id, job))
+                .collect();
+            json(200, format!("[{}]", list.join(",")))
+        }
+        (Method::Get, ["jobs", part]) => match id(part) {
+            Some(id) => json(200, job_json(id, &jobs.lock().unwrap().jobs[&id])),
+            None => error(404, "no such job"),
+        },
+        (Method::Delete, ["jobs", part]) => match id(part) {
+            Some(id) => {
+                jobs.lock().unwrap().jobs.remove(&id);
+                respond(204, "application/json", Vec::new())
+            }
+            None => error(404, "no such job"),
+        },
+        (Method::Get, ["jobs", part, "image"]) => match id(part) {
+            Some(id) => match jobs.lock().unwrap().jobs[&id].image {
+                Some(ref image) => {
+                    let mut ppm = Vec::new();
+                    image.write_ppm(&mut ppm).unwrap();
+                    respond(200, "image/x-portable-pixmap", ppm)
+                }
+                None => error(409, "no passes rendered yet"),
+            },
+            None => error(404, "no such job"),
+        },
+        (_, ["jobs"]) | (_, ["jobs", _]) | (_, ["jobs", _, "image"]) => {
+            error(405, "method not allowed")
+        }
+        _ => error(404, "not found"),
+    }
+}
+
+fn serve(server: Server) {
+    let jobs: Jobs = Arc::new(Mutex::new(Store::default()));
+    let (queue, receiver) = mpsc::channel();
+    let worker_jobs = Arc::clone(&jobs);
+    thread::spawn(move || work(worker_jobs, receiver));
+
+    for mut request in server.incoming_requests() {
+        let response = handle(&mut request, &jobs, &queue);
+        if let Err(err) = request.respond(response) {
+            eprintln!("can't respond: {}", err);
+        }
+    }
+}
+
+fn main() {
+    let address = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8000".to_string());
+    let server = Server::http(&address).unwrap_or_else(|err| {
+        eprintln!("can't listen on {}: {}", address, err);
+        std::process::exit(1);
+    });
+    eprintln!("listening on http://{}", address);
+    serve(server);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::{Duration, Instant};
+
+    // Sends one request and returns the status code and body.
+    fn request(address: SocketAddr, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8_lossy(&response[..end]).to_string();
+        let code = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (code, response[end + 4..].to_vec())
+    }
+
+    fn start() -> SocketAddr {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        thread::spawn(move || serve(server));
+        address
+    }
+
+    #[test]
+    fn renders_a_posted_scene() {
+        let address = start();
+        let scene =
+            b"size 8 6\nsamples 2\ncamera 0 0 3 0 0 0 40\nsphere 0 0 0 1 lambertian 0.5 0.2 0.2\n";
+        let (code, body) = request(address, "POST", "/jobs", scene);
+        assert_eq!(code, 201);
+        assert_eq!(body, b"{\"id\":0}");
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            let (code, body) = request(address, "GET", "/jobs/0", b"");
+            assert_eq!(code, 200);
+            let status = String::from_utf8(body).unwrap();
+            if status.contains("\"done\"") {
+                assert!(status.contains("\"passes\":2"), "{}", status);
+                break;
+            }
+            assert!(!status.contains("\"failed\""), "{}", status);
+            assert!(Instant::now() < deadline, "still {}", status);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (code, body) = request(address, "GET", "/jobs/0/image", b"");
+        assert_eq!(code, 200);
+        let image = Image::read_pnm(&mut &body[..]).unwrap();
+        assert_eq!((image.width, image.height), (8, 6));
+    }
+
+    #[test]
+    fn rejects_bad_scenes() {
+        let address = start();
+        let camera = "camera 0 0 3 0 0 0 40\n";
+        let huge = format!("size 100000 100000\n{}", camera);
+        let (code, body) = request(address, "POST", "/jobs", huge.as_bytes());
+        assert_eq!(code, 400);
+        assert_eq!(body, b"{\"error\":\"line 1: image too large\"}");
+
+        let padded = format!("{}{}", camera, "#".repeat(MAX_BODY));
+        let (code, _) = request(address, "POST", "/jobs", padded.as_bytes());
+        assert_eq!(code, 413);
+
+        let (code, _) = request(address, "GET", "/jobs", b"");
+        assert_eq!(code, 200);
+        let (code, _) = request(address, "GET", "/jobs/0", b"");
+        assert_eq!(code, 404);
+    }
+
+    #[test]
+    fn deleted_jobs_are_gone() {
+        let address = start();
+        // Long enough that it's still queued or rendering when deleted.
+        let scene = b"size 64 64\nsamples 4096\ncamera 0 0 3 0 0 0 40\n\
+                      sphere 0 0 0 1 lambertian 0.5 0.2 0.2\n";
+        let (code, _) = request(address, "POST", "/jobs", scene);
+        assert_eq!(code, 201);
+        let (code, body) = request(address, "DELETE", "/jobs/0", b"");
+        assert_eq!((code, body), (204, Vec::new()));
+        for path in ["/jobs/0", "/jobs/0/image"].iter() {
+            let (code, _) = request(address, "GET", path, b"");
+            assert_eq!(code, 404);
+        }
+        let (code, _) = request(address, "DELETE", "/jobs/0", b"");
+        assert_eq!(code, 404);
+
+        // The id isn't handed out again.
+        let (code, body) = request(
+            address,
+            "POST",
+            "/jobs",
+            b"size 1 1\nsamples 1\ncamera 0 0 3 0 0 0 40\n",
+        );
+        assert_eq!((code, body), (201, b"{\"id\":1}".to_vec()));
+        let (_, body) = request(address, "GET", "/jobs", b"");
+        let list = String::from_utf8(body).unwrap();
+        assert!(
+            list.starts_with("[{\"id\":1,") && !list.contains("\"id\":0"),
+            "{}",
+            list
+        );
+    }
+
+    #[test]
+    fn refuses_jobs_past_the_limit() {
+        let address = start();
+        let scene = b"size 1 1\nsamples 1\ncamera 0 0 3 0 0 0 40\n";
+        for _ in 0..MAX_JOBS {
+            let (code, _) = request(address, "POST", "/jobs", scene);
+            assert_eq!(code, 201);
+        }
+        let (code, _) = request(address, "POST", "/jobs", scene);
+        assert_eq!(code, 503);
+        let (code, _) = request(address, "DELETE", "/jobs/3", b"");
+        assert_eq!(code, 204);
+        let (code, body) = request(address, "POST", "/jobs", scene);
+        assert_eq!(code, 201);
+        assert_eq!(body, format!("{{\"id\":{}}}", MAX_JOBS).into_bytes());
+    }
+}
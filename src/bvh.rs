@@ -0,0 +1,242 @@
+use crate::lib::{Aabb, Float, HitRecord, Hitable, Material, Ray};
+
+const LEAF_SIZE: usize = 4;
+
+// A node covers `order[first..first + count]` when it is a leaf. Otherwise
+// its children are the next node and the node at `first`.
+struct Node {
+    bounds: Aabb,
+    first: usize,
+    count: usize,
+    axis: usize,
+}
+
+// Bounding volume hierarchy over primitives known only by index and box, so
+// that meshes and particle sets can use it as well as lists of objects.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    order: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * bounds.len()),
+            order: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    // Median split along the axis where the centroids are spread the most.
+    fn build(&mut self, bounds: &[Aabb], start: usize, end: usize) -> usize {
+        let index = self.nodes.len();
+        let items = &mut self.order[start..end];
+        let mut node_bounds = bounds[items[0]];
+        let mut centroid_bounds =
+            Aabb::new(bounds[items[0]].centroid(), bounds[items[0]].centroid());
+        for &item in items.iter() {
+            node_bounds = node_bounds.union(&bounds[item]);
+            let centroid = bounds[item].centroid();
+            centroid_bounds = centroid_bounds.union(&Aabb::new(centroid, centroid));
+        }
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        self.nodes.push(Node {
+            bounds: node_bounds,
+            first: start,
+            count: end - start,
+            axis,
+        });
+        if end - start <= LEAF_SIZE || extent[axis] <= 0.0 {
+            return index;
+        }
+
+        let middle = (start + end) / 2;
+        // Infinite boxes have NaN centroids, which sort to one end.
+        items.select_nth_unstable_by(middle - start, |&a, &b| {
+            bounds[a].centroid()[axis].total_cmp(&bounds[b].centroid()[axis])
+        });
+        self.build(bounds, start, middle);
+        let right = self.build(bounds, middle, end);
+        self.nodes[index].first = right;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    // Calls `hit` with every primitive whose leaf the ray reaches before the
+    // closest hit so far. It returns the distance of a new closest hit, which
+    // then bounds the rest of the traversal.
+    pub fn traverse<F>(&self, ray: &Ray, t_min: Float, mut t_max: Float, mut hit: F)
+    where
+        F: FnMut(usize, Float) -> Option<Float>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let direction = ray.direction();
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounds.hit(ray, t_min, t_max) {
+                continue;
+            }
+            if node.count > 0 {
+                for &item in self.order[node.first..node.first + node.count].iter() {
+                    if let Some(t) = hit(item, t_max) {
+                        t_max = t;
+                    }
+                }
+            } else if direction[node.axis] < 0.0 {
+                stack.push(index + 1);
+                stack.push(node.first);
+            } else {
+                stack.push(node.first);
+                stack.push(index + 1);
+            }
+        }
+    }
+}
+
+// A list of objects with a hierarchy over them. Like a `Vec` it reports the
+// index of the object hit as its object id. Unbounded objects are kept out of
+// the hierarchy and tested on every ray.
+pub struct BvhHitable<T> {
+    pub objects: Vec<T>,
+    bvh: Bvh,
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl<T: Hitable> BvhHitable<T> {
+    pub fn new(objects: Vec<T>) -> BvhHitable<T> {
+        let mut bounds = Vec::new();
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (index, object) in objects.iter().enumerate() {
+            match object.bounding_box() {
+                Some(aabb) => {
+                    bounds.push(aabb);
+                    bounded.push(index);
+                }
+                None => unbounded.push(index),
+            }
+        }
+        BvhHitable {
+            objects,
+            bvh: Bvh::new(&bounds),
+            bounded,
+            unbounded,
+        }
+    }
+}
+
+impl<T: Hitable> Hitable for BvhHitable<T> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hit: Option<HitRecord> = None;
+        let mut closest_so_far = t_max;
+        for &index in self.unbounded.iter() {
+            if let Some(mut record) = self.objects[index].hit(ray, t_min, closest_so_far) {
+                closest_so_far = record.t;
+                record.object_id = index;
+                hit = Some(record);
+            }
+        }
+        self.bvh
+            .traverse(ray, t_min, closest_so_far, |item, t_max| {
+                let index = self.bounded[item];
+                let mut record = self.objects[index].hit(ray, t_min, t_max)?;
+                record.object_id = index;
+                hit = Some(record);
+                Some(record.t)
+            });
+        hit
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.bvh.bounds()
+        } else {
+            None
+        }
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        self.objects.materials(materials);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Vector3;
+
+    fn cube(x: Float) -> Aabb {
+        Aabb::new(
+            Vector3 {
+                x,
+                y: -0.5,
+                z: -0.5,
+            },
+            Vector3 {
+                x: x + 1.0,
+                y: 0.5,
+                z: 0.5,
+            },
+        )
+    }
+
+    #[test]
+    fn builds_with_infinite_boxes() {
+        let infinite = Aabb::new(
+            Vector3 {
+                x: -Float::INFINITY,
+                y: -Float::INFINITY,
+                z: -Float::INFINITY,
+            },
+            Vector3 {
+                x: Float::INFINITY,
+                y: Float::INFINITY,
+                z: Float::INFINITY,
+            },
+        );
+        let mut bounds: Vec<Aabb> = (0..20).map(|i| cube(2.0 * i as Float)).collect();
+        bounds.insert(7, infinite);
+        bounds.push(infinite);
+        let bvh = Bvh::new(&bounds);
+
+        // A ray along the row of cubes reaches every primitive.
+        let ray = Ray::new(
+            Vector3 {
+                x: -10.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        );
+        let mut visited = Vec::new();
+        bvh.traverse(&ray, 0.0, Float::MAX, |item, _| {
+            visited.push(item);
+            None
+        });
+        visited.sort_unstable();
+        assert_eq!(visited, (0..bounds.len()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,368 @@
+use crate::lib::{consts, Camera, Float, InnerSpace, Ray, ThinLensCamera, Vector3};
+
+// The camera looks along -w, with u to the right and v up.
+pub(crate) fn basis(
+    lookfrom: Vector3<Float>,
+    lookat: Vector3<Float>,
+    vup: Vector3<Float>,
+) -> (Vector3<Float>, Vector3<Float>, Vector3<Float>) {
+    let w = (lookfrom - lookat).normalize();
+    let u = vup.cross(w).normalize();
+    let v = w.cross(u);
+    (u, v, w)
+}
+
+// Parallel rays, for technical views without perspective. `height` is the
+// size of the view in scene units.
+pub struct OrthographicCamera {
+    pub origin: Vector3<Float>,
+    pub horizontal: Vector3<Float>,
+    pub vertical: Vector3<Float>,
+    pub direction: Vector3<Float>,
+}
+
+impl OrthographicCamera {
+    pub fn new(
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+        height: Float,
+        aspect: Float,
+    ) -> OrthographicCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        OrthographicCamera {
+            origin: lookfrom,
+            horizontal: u * height * aspect,
+            vertical: v * height,
+            direction: -w,
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        Some(Ray::new(
+            self.origin + self.horizontal * (s - 0.5) + self.vertical * (t - 0.5),
+            self.direction,
+        ))
+    }
+}
+
+// Equidistant fisheye: the angle from the view direction grows linearly with
+// the distance from the image center, reaching `fov` / 2 at the edge of the
+// circle inscribed in the image. Points outside the circle continue the
+// mapping, up to straight behind the camera.
+pub struct FisheyeCamera {
+    pub origin: Vector3<Float>,
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+    pub fov: Float,
+    pub aspect: Float,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+        fov: Float,
+        aspect: Float,
+    ) -> FisheyeCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        FisheyeCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            fov,
+            aspect,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        let (mut x, mut y) = (2.0 * s - 1.0, 2.0 * t - 1.0);
+        if self.aspect > 1.0 {
+            x *= self.aspect;
+        } else {
+            y /= self.aspect;
+        }
+        let r = (x * x + y * y).sqrt();
+        let theta = (r * self.fov.to_radians() / 2.0).min(consts::PI);
+        let direction = if r > 0.0 {
+            (self.u * x + self.v * y) * (theta.sin() / r) - self.w * theta.cos()
+        } else {
+            -self.w
+        };
+        Some(Ray::new(self.origin, direction))
+    }
+}
+
+// Latitude-longitude panorama covering the whole sphere, as used for 360°
+// video and VR. The view direction is at the center of the image, and the
+// image should be twice as wide as it is high.
+pub struct EquirectangularCamera {
+    pub origin: Vector3<Float>,
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+}
+
+impl EquirectangularCamera {
+    pub fn new(
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+    ) -> EquirectangularCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        EquirectangularCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        let phi = (s - 0.5) * 2.0 * consts::PI;
+        let latitude = (t - 0.5) * consts::PI;
+        let direction = self.u * (phi.sin() * latitude.cos()) + self.v * latitude.sin()
+            - self.w * (phi.cos() * latitude.cos());
+        Some(Ray::new(self.origin, direction))
+    }
+}
+
+// The six faces of a cube map side by side in the order +x, -x, +y, -y, +z
+// and -z, oriented as OpenGL expects them, so the image should be six times
+// as wide as it is high. The axes are the camera's u, v and w, which puts
+// the view direction on the -z face.
+pub struct CubeMapCamera {
+    pub origin: Vector3<Float>,
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+}
+
+impl CubeMapCamera {
+    pub fn new(
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+    ) -> CubeMapCamera {
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        CubeMapCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+        }
+    }
+}
+
+impl Camera for CubeMapCamera {
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        let face = ((s * 6.0) as usize).min(5);
+        let a = 2.0 * (s * 6.0 - face as Float) - 1.0;
+        let b = 2.0 * t - 1.0;
+        let (x, y, z) = match face {
+            0 => (1.0, b, -a),
+            1 => (-1.0, b, a),
+            2 => (a, 1.0, -b),
+            3 => (a, -1.0, b),
+            4 => (a, b, 1.0),
+            _ => (-a, b, -1.0),
+        };
+        Some(Ray::new(self.origin, self.u * x + self.v * y + self.w * z))
+    }
+}
+
+// A thin lens camera described the way a photographer would. Lengths are in
+// millimeters and the scene is taken to be in meters, as for lens files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalCamera {
+    pub focal_length: Float,
+    pub sensor_width: Float,
+    pub sensor_height: Float,
+    pub f_stop: Float,
+    pub iso: Float,
+    // Seconds.
+    pub shutter: Float,
+}
+
+impl PhysicalCamera {
+    // A full frame sensor, exposed by the sunny 16 rule.
+    pub fn new(focal_length: Float, f_stop: Float) -> PhysicalCamera {
+        PhysicalCamera {
+            focal_length,
+            sensor_width: 36.0,
+            sensor_height: 24.0,
+            f_stop,
+            iso: 100.0,
+            shutter: f_stop * f_stop / 25_600.0,
+        }
+    }
+
+    // Aspect ratio the image should have to show the whole sensor.
+    pub fn aspect(&self) -> Float {
+        self.sensor_width / self.sensor_height
+    }
+
+    pub fn vfov(&self) -> Float {
+        2.0 * (self.sensor_height / (2.0 * self.focal_length))
+            .atan()
+            .to_degrees()
+    }
+
+    // Diameter of the entrance pupil, in meters.
+    pub fn aperture(&self) -> Float {
+        self.focal_length / self.f_stop * 0.001
+    }
+
+    // Relative to the sunny 16 rule, under which the sky of the scenes here
+    // comes out as it would with the default camera. Each stop of ISO,
+    // shutter or aperture doubles or halves it.
+    pub fn exposure(&self) -> Float {
+        self.shutter * self.iso / (self.f_stop * self.f_stop) * 256.0
+    }
+
+    pub fn thin_lens(
+        &self,
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+        focus_dist: Float,
+    ) -> ThinLensCamera {
+        let mut camera = ThinLensCamera::new(
+            lookfrom,
+            lookat,
+            vup,
+            self.vfov(),
+            self.aspect(),
+            self.aperture(),
+            focus_dist,
+        );
+        camera.exposure = self.exposure();
+        camera
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // Every camera here looks down -z from the origin with y up, so u, v and
+    // w are the x, y and z axes.
+    fn axes() -> (Vector3<Float>, Vector3<Float>, Vector3<Float>) {
+        (
+            point(0.0, 0.0, 0.0),
+            point(0.0, 0.0, -1.0),
+            point(0.0, 1.0, 0.0),
+        )
+    }
+
+    fn direction(camera: &dyn Camera, s: Float, t: Float) -> Vector3<Float> {
+        camera.get_ray(s, t).unwrap().direction().normalize()
+    }
+
+    fn assert_close(a: Vector3<Float>, b: Vector3<Float>) {
+        assert!((a - b.normalize()).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let (from, at, up) = axes();
+        let camera = OrthographicCamera::new(from, at, up, 2.0, 2.0);
+        for &(s, t, x, y) in [
+            (0.5, 0.5, 0.0, 0.0),
+            (0.0, 0.0, -2.0, -1.0),
+            (1.0, 0.5, 2.0, 0.0),
+            (0.5, 1.0, 0.0, 1.0),
+        ]
+        .iter()
+        {
+            let ray = camera.get_ray(s, t).unwrap();
+            assert_close(ray.direction(), point(0.0, 0.0, -1.0));
+            assert!((ray.origin() - point(x, y, 0.0)).magnitude() < 1e-6);
+        }
+    }
+
+    // At 180 degrees the inscribed circle's edge looks straight sideways,
+    // and the corners beyond it look partly behind.
+    #[test]
+    fn fisheye_angles_grow_with_the_radius() {
+        let (from, at, up) = axes();
+        let camera = FisheyeCamera::new(from, at, up, 180.0, 1.0);
+        assert_close(direction(&camera, 0.5, 0.5), point(0.0, 0.0, -1.0));
+        assert_close(direction(&camera, 1.0, 0.5), point(1.0, 0.0, 0.0));
+        assert_close(direction(&camera, 0.0, 0.5), point(-1.0, 0.0, 0.0));
+        assert_close(direction(&camera, 0.5, 1.0), point(0.0, 1.0, 0.0));
+        assert_close(direction(&camera, 0.75, 0.5), point(1.0, 0.0, -1.0));
+        let corner = direction(&camera, 1.0, 1.0);
+        let theta = (90.0 * (2.0 as Float).sqrt()).to_radians();
+        assert!((corner.z + theta.cos()).abs() < 1e-5 && corner.z > 0.0);
+        assert!((corner.x - corner.y).abs() < 1e-6);
+
+        // In a wide image the circle touches the top and bottom.
+        let camera = FisheyeCamera::new(from, at, up, 180.0, 2.0);
+        assert_close(direction(&camera, 0.5, 1.0), point(0.0, 1.0, 0.0));
+        assert_close(direction(&camera, 0.75, 0.5), point(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn equirectangular_covers_the_sphere() {
+        let (from, at, up) = axes();
+        let camera = EquirectangularCamera::new(from, at, up);
+        assert_close(direction(&camera, 0.5, 0.5), point(0.0, 0.0, -1.0));
+        assert_close(direction(&camera, 0.75, 0.5), point(1.0, 0.0, 0.0));
+        assert_close(direction(&camera, 0.25, 0.5), point(-1.0, 0.0, 0.0));
+        assert_close(direction(&camera, 0.0, 0.5), point(0.0, 0.0, 1.0));
+        assert_close(direction(&camera, 1.0, 0.5), point(0.0, 0.0, 1.0));
+        assert_close(direction(&camera, 0.3, 1.0), point(0.0, 1.0, 0.0));
+        assert_close(direction(&camera, 0.8, 0.0), point(0.0, -1.0, 0.0));
+        assert_close(
+            direction(&camera, 0.625, 0.75),
+            point(1.0, (2.0 as Float).sqrt(), -1.0),
+        );
+    }
+
+    #[test]
+    fn cube_map_faces_look_along_their_axes() {
+        let (from, at, up) = axes();
+        let camera = CubeMapCamera::new(from, at, up);
+        let centers = [
+            point(1.0, 0.0, 0.0),
+            point(-1.0, 0.0, 0.0),
+            point(0.0, 1.0, 0.0),
+            point(0.0, -1.0, 0.0),
+            point(0.0, 0.0, 1.0),
+            point(0.0, 0.0, -1.0),
+        ];
+        for (face, &center) in centers.iter().enumerate() {
+            assert_close(direction(&camera, (face as Float + 0.5) / 6.0, 0.5), center);
+        }
+        // Neighbouring faces meet along a shared edge: +z then +x, and -z
+        // then -x, side by side.
+        let edge = |face: usize, a: Float| (face as Float + a) / 6.0;
+        for &t in [0.1, 0.5, 0.9].iter() {
+            assert_close(
+                direction(&camera, edge(4, 1.0 - 1e-6), t),
+                direction(&camera, edge(0, 0.0), t),
+            );
+            assert_close(
+                direction(&camera, edge(5, 1.0), t),
+                direction(&camera, edge(1, 0.0), t),
+            );
+        }
+        // The top edge of +z is the bottom edge of +y.
+        assert_close(direction(&camera, edge(4, 0.3), 1.0), point(-0.4, 1.0, 1.0));
+        assert_close(direction(&camera, edge(2, 0.3), 0.0), point(-0.4, 1.0, 1.0));
+    }
+}
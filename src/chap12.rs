@@ -2,7 +2,7 @@ extern crate rand;
 
 use rtweekend::lib::{
     Camera, Dielectric, ElementWise, HitRecord, Hitable, InnerSpace, Lambertian, Metal, Ray,
-    Sphere, Vector3,
+    Sphere, ThinLensCamera, Vector3,
 };
 
 use std::f32;
@@ -147,7 +147,7 @@ fn main() {
         z: -1.0,
     };
 
-    let cam = Camera::new(
+    let cam = ThinLensCamera::new(
         lookfrom,
         lookat,
         Vector3 {
@@ -174,8 +174,9 @@ fn main() {
                 let u: f32 = (i as f32 + rand::random::<f32>()) / nx as f32;
                 let v: f32 = (j as f32 + rand::random::<f32>()) / ny as f32;
 
-                let ray = cam.get_ray(u, v);
-                col += color(&ray, &world, 0)
+                if let Some(ray) = cam.get_ray(u, v) {
+                    col += color(&ray, &world, 0)
+                }
             }
             col /= ns;
             println!(
@@ -0,0 +1,462 @@
+use crate::aov::{Aov, Layer, PixelSums};
+use crate::lib::{Camera, Float, Hitable, Vector3};
+use crate::render::{accumulate_pass, Accumulator, Image, Mode, Settings};
+use crate::rng;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"RTWCKPT\0";
+const VERSION: u32 = 4;
+
+// Periodically saves the accumulated sample buffers of a render to `path`.
+// Since every sample is seeded from its pixel and pass, the pass count is
+// all the RNG state there is, and a resumed render matches an uninterrupted
+// one bit for bit. Resuming with a different camera or scene is caught by
+// tracing a few rays through both; see `write_scene`.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_floats<W: Write>(out: &mut W, values: &[Float]) -> io::Result<()> {
+    for value in values {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_float<R: Read>(input: &mut R) -> io::Result<Float> {
+    let mut bytes = [0; std::mem::size_of::<Float>()];
+    input.read_exact(&mut bytes)?;
+    Ok(Float::from_le_bytes(bytes))
+}
+
+fn read_floats<R: Read>(input: &mut R, values: &mut [Float]) -> io::Result<()> {
+    for value in values.iter_mut() {
+        *value = read_float(input)?;
+    }
+    Ok(())
+}
+
+// Everything that changes the value of a sample has to match on resume.
+// The sample count does not: a finished render can be continued with more.
+fn write_settings<W: Write>(out: &mut W, settings: &Settings) -> io::Result<()> {
+    write_u64(out, settings.width as u64)?;
+    write_u64(out, settings.height as u64)?;
+    write_u32(out, settings.max_depth as u32)?;
+    write_u32(
+        out,
+        match settings.mode {
+            Mode::Rgb => 0,
+            Mode::Spectral => 1,
+        },
+    )?;
+    write_u64(out, settings.seed)?;
+    // Sums are stored at full precision, so f32 and f64 builds can't share
+    // checkpoints.
+    write_u32(out, std::mem::size_of::<Float>() as u32)?;
+    let region = settings.region();
+    for value in [region.x, region.y, region.width, region.height].iter() {
+        write_u64(out, *value as u64)?;
+    }
+    write_u32(out, settings.aovs.len() as u32)?;
+    for aov in settings.aovs.iter() {
+        write_u32(out, Aov::ALL.iter().position(|a| a == aov).unwrap() as u32)?;
+    }
+    Ok(())
+}
+
+// The camera and scene can't be compared directly, so a grid of rays from
+// the camera is traced into the scene and their origins, directions, hit
+// distances and normals, and the exposure, stand in for them. That catches
+// any change to the camera and anything in the scene the rays see; it
+// misses changes to materials and to objects between the rays, which are
+// up to the caller to keep the same.
+fn write_scene<W: Write>(out: &mut W, world: &dyn Hitable, camera: &dyn Camera) -> io::Result<()> {
+    const GRID: usize = 5;
+    rng::seed(0);
+    write_floats(out, &[camera.exposure()])?;
+    for j in 0..GRID {
+        for i in 0..GRID {
+            let s = (i as Float + 0.5) / GRID as Float;
+            let t = (j as Float + 0.5) / GRID as Float;
+            let ray = match camera.get_ray(s, t) {
+                Some(ray) => ray,
+                None => {
+                    write_floats(out, &[Float::NAN; 6])?;
+                    continue;
+                }
+            };
+            let (origin, direction) = (ray.origin(), ray.direction());
+            write_floats(
+                out,
+                &[
+                    origin.x,
+                    origin.y,
+                    origin.z,
+                    direction.x,
+                    direction.y,
+                    direction.z,
+                ],
+            )?;
+            match world.hit(&ray, 0.001, Float::MAX) {
+                Some(record) => write_floats(
+                    out,
+                    &[record.t, record.normal.x, record.normal.y, record.normal.z],
+                )?,
+                None => write_floats(out, &[Float::INFINITY])?,
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn save<W: Write>(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    accumulator: &Accumulator,
+    settings: &Settings,
+    out: &mut W,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    write_u32(out, VERSION)?;
+    write_settings(out, settings)?;
+    write_scene(out, world, camera)?;
+    write_u64(out, accumulator.passes as u64)?;
+    for sum in accumulator.beauty.iter() {
+        write_floats(out, &[sum.x, sum.y, sum.z])?;
+    }
+    for sums in accumulator.aovs.sums.iter() {
+        write_u64(out, sums.samples as u64)?;
+        write_u64(out, sums.hits as u64)?;
+        write_floats(out, &[sums.depth])?;
+        write_floats(out, &sums.position)?;
+        write_floats(out, &sums.normal)?;
+        write_floats(out, &sums.albedo)?;
+        // IDs are never NaN, so it can stand in for a pixel without one yet.
+        write_floats(
+            out,
+            &[
+                sums.object_id.unwrap_or(Float::NAN),
+                sums.material_id.unwrap_or(Float::NAN),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn load<R: Read>(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    settings: &Settings,
+    input: &mut R,
+) -> io::Result<Accumulator> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(input)? != VERSION {
+        return Err(invalid("not a checkpoint file"));
+    }
+
+    let mut expected = Vec::new();
+    write_settings(&mut expected, settings)?;
+    let mut found = vec![0; expected.len()];
+    input.read_exact(&mut found)?;
+    if found != expected {
+        return Err(invalid(
+            "checkpoint was made with different render settings",
+        ));
+    }
+    let mut expected = Vec::new();
+    write_scene(&mut expected, world, camera)?;
+    let mut found = vec![0; expected.len()];
+    input.read_exact(&mut found)?;
+    if found != expected {
+        return Err(invalid(
+            "checkpoint was made with a different camera or scene",
+        ));
+    }
+
+    let mut accumulator = Accumulator::new(settings);
+    accumulator.passes = read_u64(input)? as usize;
+    for sum in accumulator.beauty.iter_mut() {
+        let mut values = [0.0; 3];
+        read_floats(input, &mut values)?;
+        *sum = Vector3 {
+            x: values[0],
+            y: values[1],
+            z: values[2],
+        };
+    }
+    for sums in accumulator.aovs.sums.iter_mut() {
+        let mut values = [0.0; 12];
+        let samples = read_u64(input)? as usize;
+        let hits = read_u64(input)? as usize;
+        read_floats(input, &mut values)?;
+        let id = |value: Float| if value.is_nan() { None } else { Some(value) };
+        *sums = PixelSums {
+            samples,
+            hits,
+            depth: values[0],
+            position: [values[1], values[2], values[3]],
+            normal: [values[4], values[5], values[6]],
+            albedo: [values[7], values[8], values[9]],
+            object_id: id(values[10]),
+            material_id: id(values[11]),
+        };
+    }
+    Ok(accumulator)
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Checkpoint {
+        Checkpoint {
+            path,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    // Writes to a temporary file first so a kill during the save leaves the
+    // previous checkpoint intact.
+    pub fn save(
+        &self,
+        world: &dyn Hitable,
+        camera: &dyn Camera,
+        accumulator: &Accumulator,
+        settings: &Settings,
+    ) -> io::Result<()> {
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        {
+            let mut out = BufWriter::new(File::create(&temporary)?);
+            save(world, camera, accumulator, settings, &mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&temporary, &self.path)
+    }
+
+    pub fn load(
+        &self,
+        world: &dyn Hitable,
+        camera: &dyn Camera,
+        settings: &Settings,
+    ) -> io::Result<Option<Accumulator>> {
+        match File::open(&self.path) {
+            Ok(file) => load(world, camera, settings, &mut BufReader::new(file)).map(Some),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+// Like `render_passes`, but resumes from the checkpoint if there is one and
+// keeps it up to date while rendering. The checkpoint is left in place when
+// the render finishes so it can later be continued with more samples. The
+// world and camera have to be the ones the checkpoint was made with; most
+// differences are caught, but changed materials are not.
+pub fn render_resumable(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    settings: &Settings,
+    checkpoint: &Checkpoint,
+) -> io::Result<(Image, Vec<Layer>)> {
+    let mut accumulator = match checkpoint.load(world, camera, settings)? {
+        Some(accumulator) => accumulator,
+        None => Accumulator::new(settings),
+    };
+    let mut last_save = Instant::now();
+    while accumulator.passes < settings.samples {
+        accumulate_pass(world, camera, settings, &mut accumulator);
+        if last_save.elapsed() >= checkpoint.interval {
+            checkpoint.save(world, camera, &accumulator, settings)?;
+            last_save = Instant::now();
+        }
+    }
+    checkpoint.save(world, camera, &accumulator, settings)?;
+    Ok((accumulator.image(), accumulator.layers()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{Dielectric, Lambertian, Metal, Sphere, ThinLensCamera};
+    use crate::render::render_passes;
+
+    fn vector(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    fn scene() -> (Vec<Sphere>, ThinLensCamera, Settings) {
+        let world = vec![
+            Sphere {
+                center: vector(0.0, -100.5, -1.0),
+                radius: 100.0,
+                material: Box::new(Lambertian {
+                    albedo: vector(0.8, 0.8, 0.0),
+                }),
+            },
+            Sphere {
+                center: vector(0.0, 0.0, -1.0),
+                radius: 0.5,
+                material: Box::new(Metal {
+                    fuzz: 0.3,
+                    albedo: vector(0.8, 0.6, 0.2),
+                }),
+            },
+            Sphere {
+                center: vector(-1.0, 0.0, -1.0),
+                radius: 0.5,
+                material: Box::new(Dielectric { ref_idx: 1.5 }),
+            },
+        ];
+        let camera = ThinLensCamera::new(
+            vector(0.0, 0.5, 1.5),
+            vector(0.0, 0.0, -1.0),
+            vector(0.0, 1.0, 0.0),
+            60.0,
+            1.5,
+            0.1,
+            2.5,
+        );
+        let mut settings = Settings::new(12, 8, 6);
+        settings.max_depth = 8;
+        settings.seed = 17;
+        settings.aovs = Aov::ALL.to_vec();
+        (world, camera, settings)
+    }
+
+    fn temporary(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rtweekend-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted() {
+        let (world, camera, settings) = scene();
+        let (straight, straight_layers) = render_passes(&world, &camera, &settings);
+
+        let mut accumulator = Accumulator::new(&settings);
+        for _ in 0..2 {
+            accumulate_pass(&world, &camera, &settings, &mut accumulator);
+        }
+        let checkpoint = Checkpoint::new(temporary("resume.ckpt"));
+        checkpoint
+            .save(&world, &camera, &accumulator, &settings)
+            .unwrap();
+        let resumed = render_resumable(&world, &camera, &settings, &checkpoint);
+        fs::remove_file(&checkpoint.path).unwrap();
+        let (resumed, resumed_layers) = resumed.unwrap();
+
+        assert_eq!(straight.pixels, resumed.pixels);
+        for (a, b) in straight_layers.iter().zip(resumed_layers.iter()) {
+            // Infinite depth and missing IDs compare equal, NaN would not.
+            assert_eq!(a.data, b.data, "layer {}", a.name);
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_sums() {
+        let (world, camera, settings) = scene();
+        let mut accumulator = Accumulator::new(&settings);
+        for _ in 0..3 {
+            accumulate_pass(&world, &camera, &settings, &mut accumulator);
+        }
+        let mut bytes = Vec::new();
+        save(&world, &camera, &accumulator, &settings, &mut bytes).unwrap();
+        let loaded = load(&world, &camera, &settings, &mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded.passes, 3);
+        assert_eq!(loaded.beauty, accumulator.beauty);
+    }
+
+    #[test]
+    fn mismatched_settings_are_rejected() {
+        let (world, camera, settings) = scene();
+        let mut accumulator = Accumulator::new(&settings);
+        accumulate_pass(&world, &camera, &settings, &mut accumulator);
+        let mut bytes = Vec::new();
+        save(&world, &camera, &accumulator, &settings, &mut bytes).unwrap();
+
+        let mut other_seed = settings.clone();
+        other_seed.seed += 1;
+        let mut other_size = settings.clone();
+        other_size.width += 1;
+        let mut other_aovs = settings.clone();
+        other_aovs.aovs.pop();
+        for other in [other_seed, other_size, other_aovs].iter() {
+            let error = load(&world, &camera, other, &mut bytes.as_slice())
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // More samples is not a mismatch, the render just continues.
+        let mut more = settings.clone();
+        more.samples *= 2;
+        assert!(load(&world, &camera, &more, &mut bytes.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn different_cameras_and_scenes_are_rejected() {
+        let (world, camera, settings) = scene();
+        let mut accumulator = Accumulator::new(&settings);
+        accumulate_pass(&world, &camera, &settings, &mut accumulator);
+        let mut bytes = Vec::new();
+        save(&world, &camera, &accumulator, &settings, &mut bytes).unwrap();
+
+        let moved = ThinLensCamera::new(
+            vector(0.0, 0.5, 1.6),
+            vector(0.0, 0.0, -1.0),
+            vector(0.0, 1.0, 0.0),
+            60.0,
+            1.5,
+            0.1,
+            2.5,
+        );
+        let mut wider = camera.clone();
+        wider.horizontal *= 1.01;
+        for other in [moved, wider].iter() {
+            let error = load(&world, other, &settings, &mut bytes.as_slice())
+                .err()
+                .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("camera or scene"));
+        }
+
+        let mut smaller = scene().0;
+        smaller[1].radius = 0.45;
+        let error = load(&smaller, &camera, &settings, &mut bytes.as_slice())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("camera or scene"));
+
+        // The probe is the same every time, whatever the generator was left at.
+        rng::seed(99);
+        let (world, camera, _) = scene();
+        assert!(load(&world, &camera, &settings, &mut bytes.as_slice()).is_ok());
+    }
+}
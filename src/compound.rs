@@ -0,0 +1,339 @@
+use crate::lib::{
+    abs, gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Solid, Span, Vector3,
+};
+use cgmath::{Deg, Matrix, Matrix3, Matrix4, SquareMatrix, Vector4};
+
+// Objects rendered with one material. The group's material always replaces
+// the objects' own, so build them with `Inherit` rather than a material of
+// their own that would never be used.
+pub struct Group {
+    pub objects: Vec<Box<dyn Hitable>>,
+    pub material: Box<dyn Material>,
+}
+
+// Placeholder material for the objects of a group, which take the group's.
+// Outside a group it absorbs every ray, so a forgotten material shows up
+// black.
+pub struct Inherit;
+
+impl Material for Inherit {
+    fn scatter(&self, _ray: &Ray, _record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
+        None
+    }
+
+    fn albedo(&self, _record: &HitRecord) -> Vector3<Float> {
+        Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+}
+
+impl Hitable for Group {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.objects.hit(ray, t_min, t_max).map(|mut record| {
+            record.material = &*self.material;
+            record
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.objects.bounding_box()
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        // The objects' own materials are never used.
+        materials.push(&*self.material);
+    }
+}
+
+// Places an object with an affine transform from object to world space.
+// Transformed solids are solids too, so an oriented box can go into a `Csg`.
+pub struct Transform<T: ?Sized = dyn Hitable> {
+    pub object: Box<T>,
+    pub matrix: Matrix4<Float>,
+    pub inverse: Matrix4<Float>,
+}
+
+fn point(p: Vector3<Float>) -> Vector4<Float> {
+    p.extend(1.0)
+}
+
+fn vector(v: Vector3<Float>) -> Vector4<Float> {
+    v.extend(0.0)
+}
+
+impl<T: Hitable + ?Sized> Transform<T> {
+    // None if the matrix has no inverse, as when it scales an axis to 0.
+    pub fn new(object: Box<T>, matrix: Matrix4<Float>) -> Option<Transform<T>> {
+        let inverse = matrix.invert()?;
+        Some(Transform {
+            object,
+            matrix,
+            inverse,
+        })
+    }
+
+    pub fn translate(object: Box<T>, offset: Vector3<Float>) -> Transform<T> {
+        Transform {
+            object,
+            matrix: Matrix4::from_translation(offset),
+            inverse: Matrix4::from_translation(-offset),
+        }
+    }
+
+    pub fn rotate_x(object: Box<T>, degrees: Float) -> Transform<T> {
+        Transform {
+            object,
+            matrix: Matrix4::from_angle_x(Deg(degrees)),
+            inverse: Matrix4::from_angle_x(Deg(-degrees)),
+        }
+    }
+
+    pub fn rotate_y(object: Box<T>, degrees: Float) -> Transform<T> {
+        Transform {
+            object,
+            matrix: Matrix4::from_angle_y(Deg(degrees)),
+            inverse: Matrix4::from_angle_y(Deg(-degrees)),
+        }
+    }
+
+    pub fn rotate_z(object: Box<T>, degrees: Float) -> Transform<T> {
+        Transform {
+            object,
+            matrix: Matrix4::from_angle_z(Deg(degrees)),
+            inverse: Matrix4::from_angle_z(Deg(-degrees)),
+        }
+    }
+
+    // None if any factor is 0.
+    pub fn scale(object: Box<T>, factors: Vector3<Float>) -> Option<Transform<T>> {
+        Transform::new(
+            object,
+            Matrix4::from_nonuniform_scale(factors.x, factors.y, factors.z),
+        )
+    }
+
+    // Normals transform with the inverse transpose to stay perpendicular.
+    fn normal_matrix(&self) -> Matrix3<Float> {
+        let i = self.inverse;
+        Matrix3::new(
+            i.x.x, i.x.y, i.x.z, i.y.x, i.y.y, i.y.z, i.z.x, i.z.y, i.z.z,
+        )
+        .transpose()
+    }
+
+    // An affine map keeps the ray parameter, so t needs no conversion either
+    // way.
+    fn to_local(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            (self.inverse * point(ray.origin())).truncate(),
+            (self.inverse * vector(ray.direction())).truncate(),
+        )
+    }
+
+    fn to_world<'a>(&self, mut record: HitRecord<'a>) -> HitRecord<'a> {
+        // Transforming the local point keeps it on the surface, with the
+        // rounding of the matrix product added to its error.
+        let m = self.matrix;
+        let magnitude = Matrix3::new(
+            m.x.x.abs(),
+            m.x.y.abs(),
+            m.x.z.abs(),
+            m.y.x.abs(),
+            m.y.y.abs(),
+            m.y.z.abs(),
+            m.z.x.abs(),
+            m.z.y.abs(),
+            m.z.z.abs(),
+        );
+        record.error = magnitude * (record.error * (1.0 + gamma(3)) + abs(record.p) * gamma(3))
+            + abs(m.w.truncate()) * gamma(3);
+        record.p = (m * point(record.p)).truncate();
+        let normal_matrix = self.normal_matrix();
+        record.normal = (normal_matrix * record.normal).normalize();
+        record.geometric_normal = (normal_matrix * record.geometric_normal).normalize();
+        if record.tangent.magnitude2() > 0.0 {
+            record.tangent = (self.matrix * vector(record.tangent))
+                .truncate()
+                .normalize();
+        }
+        record
+    }
+}
+
+impl<T: Hitable + ?Sized> Hitable for Transform<T> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.object
+            .hit(&self.to_local(ray), t_min, t_max)
+            .map(|record| self.to_world(record))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let aabb = self.object.bounding_box()?;
+        let mut corners = Vec::with_capacity(8);
+        for &x in [aabb.min.x, aabb.max.x].iter() {
+            for &y in [aabb.min.y, aabb.max.y].iter() {
+                for &z in [aabb.min.z, aabb.max.z].iter() {
+                    corners.push((self.matrix * point(Vector3 { x, y, z })).truncate());
+                }
+            }
+        }
+        Some(Aabb::from_points(&corners))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        self.object.materials(materials);
+    }
+}
+
+// The inverse transpose keeps outward normals outward, even through a
+// mirroring scale.
+impl<T: Solid + ?Sized> Solid for Transform<T> {
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        self.object
+            .spans(&self.to_local(ray))
+            .into_iter()
+            .map(|span| Span {
+                enter: self.to_world(span.enter),
+                exit: self.to_world(span.exit),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csg::Csg;
+    use crate::lib::{Lambertian, Sphere};
+    use crate::shapes::Cuboid;
+
+    fn grey() -> Box<dyn Material> {
+        Box::new(Lambertian {
+            albedo: Vector3 {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            },
+        })
+    }
+
+    fn sphere(radius: Float) -> Box<Sphere> {
+        Box::new(Sphere {
+            center: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            radius,
+            material: grey(),
+        })
+    }
+
+    // A unit cube turned 45 degrees about y, so its cross section in the
+    // xz plane is the square |x| + |z| < √2 / 2.
+    fn diamond() -> Transform<Cuboid> {
+        let corner = Vector3 {
+            x: 0.5,
+            y: 0.5,
+            z: 0.5,
+        };
+        Transform::rotate_y(Box::new(Cuboid::new(-corner, corner, grey())), 45.0)
+    }
+
+    // Along -x at height 0, offset by z.
+    fn ray(z: Float) -> Ray {
+        Ray::new(
+            Vector3 { x: 5.0, y: 0.0, z },
+            Vector3 {
+                x: -1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    #[test]
+    fn group_material_replaces_inherit() {
+        let sphere = |x: Float| -> Box<dyn Hitable> {
+            Box::new(Sphere {
+                center: Vector3 { x, y: 0.0, z: 0.0 },
+                radius: 0.5,
+                material: Box::new(Inherit),
+            })
+        };
+        let albedo = Vector3 {
+            x: 0.2,
+            y: 0.4,
+            z: 0.6,
+        };
+        let group = Group {
+            objects: vec![sphere(-1.0), sphere(1.0)],
+            material: Box::new(Lambertian { albedo }),
+        };
+        let ray = Ray::new(
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 5.0,
+            },
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        );
+        let record = group.hit(&ray, 0.0, Float::MAX).unwrap();
+        assert_eq!(record.material.albedo(&record), albedo);
+    }
+
+    #[test]
+    fn singular_matrices_are_rejected() {
+        let flat = Vector3 {
+            x: 1.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        assert!(Transform::scale(sphere(1.0), flat).is_none());
+        assert!(Transform::new(sphere(1.0), Matrix4::from_scale(0.0)).is_none());
+        assert!(Transform::scale(sphere(1.0), flat * 2.0 + Vector3::unit_y()).is_some());
+    }
+
+    #[test]
+    fn rotated_boxes_are_solids() {
+        let half_diagonal = (0.5 as Float).sqrt();
+        let diamond = diamond();
+        let spans = diamond.spans(&ray(0.2));
+        assert_eq!(spans.len(), 1);
+        let edge = half_diagonal - 0.2;
+        assert!((spans[0].enter.t - (5.0 - edge)).abs() < 1e-5);
+        assert!((spans[0].exit.t - (5.0 + edge)).abs() < 1e-5);
+        // Outward normals of the faces facing +x and -x.
+        let normal = spans[0].enter.normal;
+        assert!((normal.x - half_diagonal).abs() < 1e-5 && normal.y.abs() < 1e-5);
+        assert!((spans[0].exit.normal.x + half_diagonal).abs() < 1e-5);
+        assert!(diamond.spans(&ray(0.8)).is_empty());
+    }
+
+    // A sphere with the diamond cut out of it: the ray crosses the shell
+    // on either side of the hole, whose walls face into it.
+    #[test]
+    fn rotated_boxes_cut_holes_in_csg() {
+        let solid = Csg::difference(sphere(1.0), Box::new(diamond()));
+        let spans = solid.spans(&ray(0.2));
+        assert_eq!(spans.len(), 2);
+        let shell = (1.0 - 0.2 * 0.2 as Float).sqrt();
+        let hole = (0.5 as Float).sqrt() - 0.2;
+        let expected = [(5.0 - shell, 5.0 - hole), (5.0 + hole, 5.0 + shell)];
+        for (span, &(enter, exit)) in spans.iter().zip(expected.iter()) {
+            assert!((span.enter.t - enter).abs() < 1e-5);
+            assert!((span.exit.t - exit).abs() < 1e-5);
+        }
+        assert!(spans[0].exit.normal.x < 0.0);
+        assert!(spans[1].enter.normal.x > 0.0);
+        let record = solid.hit(&ray(0.2), 0.0, Float::MAX).unwrap();
+        assert!((record.t - (5.0 - shell)).abs() < 1e-5);
+    }
+}
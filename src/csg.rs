@@ -0,0 +1,327 @@
+use crate::lib::{Aabb, Float, HitRecord, Hitable, Material, Ray, Solid, Span, Vector3};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl Operation {
+    fn apply(self, inside_left: bool, inside_right: bool) -> bool {
+        match self {
+            Operation::Union => inside_left || inside_right,
+            Operation::Intersection => inside_left && inside_right,
+            Operation::Difference => inside_left && !inside_right,
+        }
+    }
+}
+
+// Boolean combination of two solids. Surfaces keep the material of the
+// operand they come from, so a lens cut from a glass sphere stays glass.
+pub struct Csg {
+    pub operation: Operation,
+    pub left: Box<dyn Solid>,
+    pub right: Box<dyn Solid>,
+}
+
+impl Csg {
+    pub fn union(left: Box<dyn Solid>, right: Box<dyn Solid>) -> Csg {
+        Csg {
+            operation: Operation::Union,
+            left,
+            right,
+        }
+    }
+
+    pub fn intersection(left: Box<dyn Solid>, right: Box<dyn Solid>) -> Csg {
+        Csg {
+            operation: Operation::Intersection,
+            left,
+            right,
+        }
+    }
+
+    pub fn difference(left: Box<dyn Solid>, right: Box<dyn Solid>) -> Csg {
+        Csg {
+            operation: Operation::Difference,
+            left,
+            right,
+        }
+    }
+}
+
+struct Event<'a> {
+    record: HitRecord<'a>,
+    left: bool,
+    enter: bool,
+}
+
+// A degenerate ray or shape can cross at a NaN distance. Dropping the whole
+// span keeps every entry paired with its exit.
+fn events<'a>(spans: Vec<Span<'a>>, left: bool) -> impl Iterator<Item = Event<'a>> {
+    spans
+        .into_iter()
+        .filter(|span| !span.enter.t.is_nan() && !span.exit.t.is_nan())
+        .flat_map(move |span| {
+            vec![
+                Event {
+                    record: span.enter,
+                    left,
+                    enter: true,
+                },
+                Event {
+                    record: span.exit,
+                    left,
+                    enter: false,
+                },
+            ]
+        })
+}
+
+fn intersect_boxes(a: Aabb, b: Aabb) -> Aabb {
+    Aabb::new(
+        Vector3 {
+            x: a.min.x.max(b.min.x),
+            y: a.min.y.max(b.min.y),
+            z: a.min.z.max(b.min.z),
+        },
+        Vector3 {
+            x: a.max.x.min(b.max.x),
+            y: a.max.y.min(b.max.y),
+            z: a.max.z.min(b.max.z),
+        },
+    )
+}
+
+impl Solid for Csg {
+    // Sweeps the crossings of both operands along the ray and keeps those
+    // where the result switches between inside and outside.
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        let mut events: Vec<Event> = events(self.left.spans(ray), true)
+            .chain(events(self.right.spans(ray), false))
+            .collect();
+        events.sort_by(|a, b| a.record.t.total_cmp(&b.record.t));
+
+        let mut spans = Vec::new();
+        let mut enter: Option<HitRecord> = None;
+        let mut inside_left = false;
+        let mut inside_right = false;
+        for event in events {
+            let was_inside = self.operation.apply(inside_left, inside_right);
+            if event.left {
+                inside_left = event.enter;
+            } else {
+                inside_right = event.enter;
+            }
+            let inside = self.operation.apply(inside_left, inside_right);
+            if inside == was_inside {
+                continue;
+            }
+
+            // Outward normals of an operand point inward for the result
+            // when entering the operand means leaving the result.
+            let mut record = event.record;
+            if event.enter != inside {
+                record.normal = -record.normal;
+                record.geometric_normal = -record.geometric_normal;
+            }
+            if inside {
+                enter = Some(record);
+            } else if let Some(enter) = enter.take() {
+                spans.push(Span {
+                    enter,
+                    exit: record,
+                });
+            }
+        }
+        spans
+    }
+}
+
+impl Hitable for Csg {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.spans(ray)
+            .into_iter()
+            .flat_map(|span| vec![span.enter, span.exit])
+            .find(|record| record.t > t_min && record.t < t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            Operation::Union => Some(left?.union(&right?)),
+            Operation::Intersection => match (left, right) {
+                (Some(left), Some(right)) => Some(intersect_boxes(left, right)),
+                (left, right) => left.or(right),
+            },
+            Operation::Difference => left,
+        }
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        self.left.materials(materials);
+        self.right.materials(materials);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{Lambertian, Sphere};
+
+    fn sphere(x: Float) -> Box<Sphere> {
+        Box::new(Sphere {
+            center: Vector3 { x, y: 0.0, z: 0.0 },
+            radius: 1.0,
+            material: Box::new(Lambertian {
+                albedo: Vector3 {
+                    x: 0.5,
+                    y: 0.5,
+                    z: 0.5,
+                },
+            }),
+        })
+    }
+
+    // Along the x axis from x = -5, so t is x + 5.
+    fn ray() -> Ray {
+        Ray::new(
+            Vector3 {
+                x: -5.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    // Spans as (enter t, exit t, enter normal x, exit normal x).
+    fn spans(csg: &Csg) -> Vec<(Float, Float, Float, Float)> {
+        csg.spans(&ray())
+            .iter()
+            .map(|span| {
+                (
+                    span.enter.t,
+                    span.exit.t,
+                    span.enter.normal.x,
+                    span.exit.normal.x,
+                )
+            })
+            .collect()
+    }
+
+    fn assert_spans(found: Vec<(Float, Float, Float, Float)>, expected: &[(Float, Float)]) {
+        assert_eq!(found.len(), expected.len(), "{:?}", found);
+        for (span, expected) in found.iter().zip(expected.iter()) {
+            assert!((span.0 - expected.0).abs() < 1e-4, "{:?}", found);
+            assert!((span.1 - expected.1).abs() < 1e-4, "{:?}", found);
+            // Normals point out of the result at both ends.
+            assert!(span.2 < 0.0 && span.3 > 0.0, "{:?}", found);
+        }
+    }
+
+    // The spheres overlap between x = 0 and x = 1.
+    #[test]
+    fn overlapping_operands() {
+        assert_spans(spans(&Csg::union(sphere(0.0), sphere(1.0))), &[(4.0, 7.0)]);
+        assert_spans(
+            spans(&Csg::intersection(sphere(0.0), sphere(1.0))),
+            &[(5.0, 6.0)],
+        );
+        assert_spans(
+            spans(&Csg::difference(sphere(0.0), sphere(1.0))),
+            &[(4.0, 5.0)],
+        );
+        assert_spans(
+            spans(&Csg::difference(sphere(1.0), sphere(0.0))),
+            &[(6.0, 7.0)],
+        );
+    }
+
+    #[test]
+    fn disjoint_operands() {
+        assert_spans(
+            spans(&Csg::union(sphere(0.0), sphere(3.0))),
+            &[(4.0, 6.0), (7.0, 9.0)],
+        );
+        assert_spans(spans(&Csg::intersection(sphere(0.0), sphere(3.0))), &[]);
+        assert_spans(
+            spans(&Csg::difference(sphere(0.0), sphere(3.0))),
+            &[(4.0, 6.0)],
+        );
+    }
+
+    #[test]
+    fn nested_operands() {
+        // A hollow shell: the big sphere minus a small one inside it.
+        let inner = Box::new(Sphere {
+            center: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            radius: 0.5,
+            material: Box::new(Lambertian {
+                albedo: Vector3 {
+                    x: 0.5,
+                    y: 0.5,
+                    z: 0.5,
+                },
+            }),
+        });
+        let shell = Csg::difference(sphere(0.0), inner);
+        assert_spans(spans(&shell), &[(4.0, 4.5), (5.5, 6.0)]);
+        let record = shell.hit(&ray(), 4.2, Float::MAX).unwrap();
+        assert!((record.t - 4.5).abs() < 1e-4);
+    }
+
+    // A sphere whose entry is at NaN, as a degenerate shape can produce.
+    struct Broken(Box<Sphere>);
+
+    impl Hitable for Broken {
+        fn hit(&self, _ray: &Ray, _t_min: Float, _t_max: Float) -> Option<HitRecord<'_>> {
+            None
+        }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            None
+        }
+
+        fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+            self.0.materials(materials);
+        }
+    }
+
+    impl Solid for Broken {
+        fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+            let mut span = self.0.spans(ray)[0];
+            span.enter.t = Float::NAN;
+            vec![span]
+        }
+    }
+
+    #[test]
+    fn nan_crossings_are_ignored() {
+        assert_spans(
+            spans(&Csg::union(sphere(0.0), Box::new(Broken(sphere(0.0))))),
+            &[(4.0, 6.0)],
+        );
+        assert_spans(
+            spans(&Csg::difference(sphere(0.0), Box::new(Broken(sphere(0.0))))),
+            &[(4.0, 6.0)],
+        );
+        assert_spans(
+            spans(&Csg::intersection(
+                sphere(0.0),
+                Box::new(Broken(sphere(0.0))),
+            )),
+            &[],
+        );
+    }
+}
@@ -0,0 +1,655 @@
+use crate::aov::Layer;
+use crate::lib::to_f32;
+use std::io::{self, Write};
+
+const MAGIC: u32 = 20_000_630;
+const VERSION: u32 = 2;
+const TILED_FLAG: u32 = 0x200;
+const LONG_NAMES_FLAG: u32 = 0x400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelType {
+    Half,
+    Float,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Rle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+    Scanline,
+    Tiled { width: usize, height: usize },
+}
+
+// Single-part OpenEXR writer. Every layer becomes a set of channels named
+// `layer.channel`; a layer with an empty name is written without prefix,
+// which is where viewers look for the beauty pass.
+#[derive(Clone, Debug)]
+pub struct ExrWriter {
+    pub pixel_type: PixelType,
+    pub compression: Compression,
+    pub storage: Storage,
+}
+
+impl Default for ExrWriter {
+    fn default() -> ExrWriter {
+        ExrWriter {
+            pixel_type: PixelType::Half,
+            compression: Compression::Rle,
+            storage: Storage::Scanline,
+        }
+    }
+}
+
+struct Block {
+    tile: Option<(usize, usize)>,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+struct Channel<'a> {
+    name: String,
+    layer: &'a Layer,
+    offset: usize,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Round to nearest even, with overflow to infinity and gradual underflow.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x007f_ffff;
+
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x0200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x0080_0000;
+        let shift = (14 - exponent) as u32;
+        let half = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round = remainder > halfway || (remainder == halfway && half & 1 == 1);
+        return sign | (half + round as u32) as u16;
+    }
+
+    let half = ((exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round = remainder > 0x1000 || (remainder == 0x1000 && half & 1 == 1);
+    // A carry out of the mantissa correctly bumps the exponent.
+    sign | (half + round as u32) as u16
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    let mut value = Vec::new();
+    for v in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        value.extend_from_slice(&v.to_le_bytes());
+    }
+    value
+}
+
+// Byte reordering and delta predictor applied before run-length encoding.
+fn predict(raw: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(raw.len());
+    data.extend(raw.iter().step_by(2));
+    data.extend(raw.iter().skip(1).step_by(2));
+    if data.is_empty() {
+        return data;
+    }
+    let mut previous = data[0];
+    for byte in data.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    data
+}
+
+fn rle_compress(data: &[u8]) -> Vec<u8> {
+    const MIN_RUN: usize = 3;
+    const MAX_RUN: usize = 127;
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let mut end = start + 1;
+        while end < data.len() && data[end] == data[start] && end - start <= MAX_RUN {
+            end += 1;
+        }
+        if end - start >= MIN_RUN {
+            out.push((end - start - 1) as u8);
+            out.push(data[start]);
+        } else {
+            let repeats_at =
+                |i: usize| i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2];
+            while end < data.len() && !repeats_at(end) && end - start < MAX_RUN {
+                end += 1;
+            }
+            out.push((-((end - start) as i32)) as u8);
+            out.extend_from_slice(&data[start..end]);
+        }
+        start = end;
+    }
+    out
+}
+
+impl ExrWriter {
+    pub fn write<W: Write>(&self, layers: &[Layer], out: &mut W) -> io::Result<()> {
+        let (width, height) = match layers.first() {
+            Some(layer) => (layer.width, layer.height),
+            None => return Err(invalid("no layers to write".to_string())),
+        };
+        if let Storage::Tiled {
+            width: tile_width,
+            height: tile_height,
+        } = self.storage
+        {
+            if tile_width == 0 || tile_height == 0 {
+                return Err(invalid(format!(
+                    "tiles of {}x{} pixels",
+                    tile_width, tile_height
+                )));
+            }
+        }
+
+        let mut channels = Vec::new();
+        for layer in layers {
+            if layer.width != width || layer.height != height {
+                return Err(invalid(format!(
+                    "layer {} has a different size",
+                    layer.name
+                )));
+            }
+            for (offset, channel) in layer.channels.iter().enumerate() {
+                let name = if layer.name.is_empty() {
+                    channel.clone()
+                } else {
+                    format!("{}.{}", layer.name, channel)
+                };
+                channels.push(Channel {
+                    name,
+                    layer,
+                    offset,
+                });
+            }
+        }
+        // The channel list, and therefore the pixel data, is sorted by name.
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        for pair in channels.windows(2) {
+            if pair[0].name == pair[1].name {
+                return Err(invalid(format!("duplicate channel {}", pair[0].name)));
+            }
+        }
+
+        let header = self.header(&channels, width, height);
+        let chunks: Vec<Vec<u8>> = self
+            .blocks(width, height)
+            .iter()
+            .map(|block| self.chunk(&channels, block))
+            .collect();
+
+        let mut offset = (header.len() + 8 * chunks.len()) as u64;
+        let mut table = Vec::with_capacity(8 * chunks.len());
+        for chunk in chunks.iter() {
+            table.extend_from_slice(&offset.to_le_bytes());
+            offset += chunk.len() as u64;
+        }
+
+        out.write_all(&header)?;
+        out.write_all(&table)?;
+        for chunk in chunks.iter() {
+            out.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn header(&self, channels: &[Channel], width: usize, height: usize) -> Vec<u8> {
+        let mut version = VERSION;
+        if let Storage::Tiled { .. } = self.storage {
+            version |= TILED_FLAG;
+        }
+        if channels.iter().any(|channel| channel.name.len() > 31) {
+            version |= LONG_NAMES_FLAG;
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+
+        let mut chlist = Vec::new();
+        for channel in channels {
+            chlist.extend_from_slice(channel.name.as_bytes());
+            chlist.push(0);
+            let pixel_type: i32 = match self.pixel_type {
+                PixelType::Half => 1,
+                PixelType::Float => 2,
+            };
+            chlist.extend_from_slice(&pixel_type.to_le_bytes());
+            chlist.extend_from_slice(&[0, 0, 0, 0]);
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+        write_attribute(&mut header, "channels", "chlist", &chlist);
+
+        let compression = match self.compression {
+            Compression::None => 0,
+            Compression::Rle => 1,
+        };
+        write_attribute(&mut header, "compression", "compression", &[compression]);
+        write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+        write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+        write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        write_attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        let mut center = Vec::new();
+        center.extend_from_slice(&0f32.to_le_bytes());
+        center.extend_from_slice(&0f32.to_le_bytes());
+        write_attribute(&mut header, "screenWindowCenter", "v2f", &center);
+        write_attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        if let Storage::Tiled { width, height } = self.storage {
+            let mut tiles = Vec::new();
+            tiles.extend_from_slice(&(width as u32).to_le_bytes());
+            tiles.extend_from_slice(&(height as u32).to_le_bytes());
+            // ONE_LEVEL, ROUND_DOWN
+            tiles.push(0);
+            write_attribute(&mut header, "tiles", "tiledesc", &tiles);
+        }
+        header.push(0);
+        header
+    }
+
+    fn blocks(&self, width: usize, height: usize) -> Vec<Block> {
+        match self.storage {
+            Storage::Scanline => (0..height)
+                .map(|y| Block {
+                    tile: None,
+                    x: 0,
+                    y,
+                    width,
+                    height: 1,
+                })
+                .collect(),
+            Storage::Tiled {
+                width: tile_width,
+                height: tile_height,
+            } => {
+                let mut blocks = Vec::new();
+                for (ty, y) in (0..height).step_by(tile_height).enumerate() {
+                    for (tx, x) in (0..width).step_by(tile_width).enumerate() {
+                        blocks.push(Block {
+                            tile: Some((tx, ty)),
+                            x,
+                            y,
+                            width: tile_width.min(width - x),
+                            height: tile_height.min(height - y),
+                        });
+                    }
+                }
+                blocks
+            }
+        }
+    }
+
+    fn chunk(&self, channels: &[Channel], block: &Block) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for y in block.y..block.y + block.height {
+            for channel in channels {
+                for x in block.x..block.x + block.width {
+                    // Layers hold whatever precision the renderer was built
+                    // with; EXR stores at most single precision.
+                    let value = to_f32(channel.layer.pixel(x, y)[channel.offset]);
+                    match self.pixel_type {
+                        PixelType::Half => raw.extend_from_slice(&f32_to_half(value).to_le_bytes()),
+                        PixelType::Float => raw.extend_from_slice(&value.to_le_bytes()),
+                    }
+                }
+            }
+        }
+
+        let data = match self.compression {
+            Compression::None => raw,
+            Compression::Rle => {
+                // Chunks that do not shrink are stored uncompressed.
+                let compressed = rle_compress(&predict(&raw));
+                if compressed.len() < raw.len() {
+                    compressed
+                } else {
+                    raw
+                }
+            }
+        };
+
+        let mut chunk = Vec::with_capacity(data.len() + 20);
+        match block.tile {
+            Some((tx, ty)) => {
+                for v in [tx as i32, ty as i32, 0, 0].iter() {
+                    chunk.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            None => chunk.extend_from_slice(&(block.y as i32).to_le_bytes()),
+        }
+        chunk.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        chunk.extend_from_slice(&data);
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_nan(half: u16) -> bool {
+        half & 0x7c00 == 0x7c00 && half & 0x03ff != 0
+    }
+
+    #[test]
+    fn half_zero_and_sign() {
+        assert_eq!(f32_to_half(0.0), 0x0000);
+        assert_eq!(f32_to_half(-0.0), 0x8000);
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(-2.0), 0xc000);
+    }
+
+    #[test]
+    fn half_subnormals() {
+        let tiny = 2f32.powi(-24);
+        assert_eq!(f32_to_half(tiny), 0x0001);
+        assert_eq!(f32_to_half(1023.0 * tiny), 0x03ff);
+        assert_eq!(f32_to_half(2f32.powi(-14)), 0x0400);
+        // Halfway cases round to the even neighbour.
+        assert_eq!(f32_to_half(0.5 * tiny), 0x0000);
+        assert_eq!(f32_to_half(1.5 * tiny), 0x0002);
+        assert_eq!(f32_to_half(2.5 * tiny), 0x0002);
+        // Anything below half the smallest subnormal flushes to zero.
+        assert_eq!(f32_to_half(0.4 * tiny), 0x0000);
+        assert_eq!(f32_to_half(-1e-30), 0x8000);
+    }
+
+    #[test]
+    fn half_rounds_to_even() {
+        let ulp = 2f32.powi(-10);
+        assert_eq!(f32_to_half(1.0 + 0.5 * ulp), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 1.5 * ulp), 0x3c02);
+        assert_eq!(f32_to_half(1.0 + 0.51 * ulp), 0x3c01);
+        // A carry out of the mantissa moves up to the next power of two.
+        assert_eq!(f32_to_half(2.0 - 0.25 * ulp), 0x4000);
+    }
+
+    #[test]
+    fn half_overflow_and_nan() {
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        assert_eq!(f32_to_half(65519.0), 0x7bff);
+        assert_eq!(f32_to_half(65520.0), 0x7c00);
+        assert_eq!(f32_to_half(1e10), 0x7c00);
+        assert_eq!(f32_to_half(-1e10), 0xfc00);
+        assert_eq!(f32_to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_half(f32::NEG_INFINITY), 0xfc00);
+        assert!(is_nan(f32_to_half(f32::NAN)));
+        assert!(is_nan(f32_to_half(-f32::NAN)));
+    }
+
+    // The decoder of the OpenEXR library, written out independently.
+    fn rle_decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let count = data[i] as i8;
+            i += 1;
+            if count < 0 {
+                let n = -(count as i32) as usize;
+                out.extend_from_slice(&data[i..i + n]);
+                i += n;
+            } else {
+                for _ in 0..=count {
+                    out.push(data[i]);
+                }
+                i += 1;
+            }
+        }
+        out
+    }
+
+    fn unpredict(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for i in 1..data.len() {
+            data[i] = data[i - 1].wrapping_add(data[i]).wrapping_sub(128);
+        }
+        let half = data.len() - data.len() / 2;
+        let mut raw = Vec::with_capacity(data.len());
+        for i in 0..half {
+            raw.push(data[i]);
+            if half + i < data.len() {
+                raw.push(data[half + i]);
+            }
+        }
+        raw
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let mut long_run = vec![7u8; 300];
+        long_run.extend_from_slice(&[1, 2, 3]);
+        let literals: Vec<u8> = (0..=255u8).chain(0..=100u8).collect();
+        let mut mixed = Vec::new();
+        for i in 0..50u8 {
+            mixed.extend_from_slice(&[i, i, i.wrapping_mul(31), 9, 9, 9, 9]);
+        }
+        let cases: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![5],
+            vec![5, 5],
+            vec![5, 5, 5],
+            vec![0; 127],
+            vec![0; 128],
+            vec![0; 129],
+            long_run,
+            literals,
+            mixed,
+        ];
+        for case in cases.iter() {
+            let compressed = rle_compress(case);
+            assert_eq!(&rle_decompress(&compressed), case);
+            assert_eq!(&unpredict(&predict(case)), case);
+        }
+    }
+
+    #[test]
+    fn rle_splits_long_runs_and_literals() {
+        // 300 equal bytes: two runs of 128 and one of 44.
+        assert_eq!(rle_compress(&[4; 300]), vec![127, 4, 127, 4, 43, 4]);
+        // 200 distinct bytes: literal runs of at most 127.
+        let literals: Vec<u8> = (0..200u8).collect();
+        let compressed = rle_compress(&literals);
+        assert_eq!(compressed[0] as i8, -127);
+        assert_eq!(compressed[128] as i8, -73);
+        assert_eq!(compressed.len(), 202);
+    }
+
+    fn attribute(name: &str, kind: &str, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(kind.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    fn ints(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn expected_header(version: u32, width: i32, height: i32, tiles: Option<&[u8]>) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&20_000_630u32.to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        let mut chlist = b"Y\0".to_vec();
+        chlist.extend(ints(&[2, 0, 1, 1]));
+        chlist.push(0);
+        header.extend(attribute("channels", "chlist", &chlist));
+        header.extend(attribute("compression", "compression", &[0]));
+        header.extend(attribute(
+            "dataWindow",
+            "box2i",
+            &ints(&[0, 0, width - 1, height - 1]),
+        ));
+        header.extend(attribute(
+            "displayWindow",
+            "box2i",
+            &ints(&[0, 0, width - 1, height - 1]),
+        ));
+        header.extend(attribute("lineOrder", "lineOrder", &[0]));
+        header.extend(attribute("pixelAspectRatio", "float", &1f32.to_le_bytes()));
+        header.extend(attribute("screenWindowCenter", "v2f", &[0; 8]));
+        header.extend(attribute("screenWindowWidth", "float", &1f32.to_le_bytes()));
+        if let Some(tiles) = tiles {
+            header.extend(attribute("tiles", "tiledesc", tiles));
+        }
+        header.push(0);
+        header
+    }
+
+    fn layer(width: usize, height: usize) -> Layer {
+        let mut layer = Layer::new("", &["Y"], width, height);
+        for (i, value) in layer.data.iter_mut().enumerate() {
+            *value = i as crate::lib::Float;
+        }
+        layer
+    }
+
+    fn offsets(file: &[u8], start: usize, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|i| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&file[start + 8 * i..start + 8 * i + 8]);
+                u64::from_le_bytes(bytes)
+            })
+            .collect()
+    }
+
+    fn int_at(file: &[u8], at: usize) -> i32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&file[at..at + 4]);
+        i32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn scanline_header_and_offsets() {
+        let writer = ExrWriter {
+            pixel_type: PixelType::Float,
+            compression: Compression::None,
+            storage: Storage::Scanline,
+        };
+        let mut file = Vec::new();
+        writer.write(&[layer(2, 3)], &mut file).unwrap();
+
+        let header = expected_header(2, 2, 3, None);
+        assert_eq!(&file[..header.len()], &header[..]);
+        // Each line is its y, its size and two floats.
+        let table = offsets(&file, header.len(), 3);
+        let first = (header.len() + 3 * 8) as u64;
+        assert_eq!(table, vec![first, first + 16, first + 32]);
+        assert_eq!(file.len() as u64, first + 48);
+        for (y, &offset) in table.iter().enumerate() {
+            let at = offset as usize;
+            assert_eq!(int_at(&file, at), y as i32);
+            assert_eq!(int_at(&file, at + 4), 8);
+            let value =
+                f32::from_le_bytes([file[at + 8], file[at + 9], file[at + 10], file[at + 11]]);
+            assert_eq!(value, (2 * y) as f32);
+        }
+    }
+
+    #[test]
+    fn tiled_header_and_offsets() {
+        let writer = ExrWriter {
+            pixel_type: PixelType::Float,
+            compression: Compression::None,
+            storage: Storage::Tiled {
+                width: 2,
+                height: 2,
+            },
+        };
+        let mut file = Vec::new();
+        writer.write(&[layer(3, 3)], &mut file).unwrap();
+
+        let mut tiles = ints(&[2, 2]);
+        tiles.push(0);
+        let header = expected_header(2 | 0x200, 3, 3, Some(&tiles));
+        assert_eq!(&file[..header.len()], &header[..]);
+        // Tiles are 2x2, 1x2, 2x1 and 1x1 pixels, each after tile
+        // coordinates, level and size.
+        let table = offsets(&file, header.len(), 4);
+        let first = (header.len() + 4 * 8) as u64;
+        let sizes = [16, 8, 8, 4];
+        let mut expected = vec![first];
+        for size in sizes.iter().take(3) {
+            expected.push(expected.last().unwrap() + 20 + size);
+        }
+        assert_eq!(table, expected);
+        assert_eq!(file.len() as u64, expected[3] + 20 + 4);
+        let coordinates = [(0, 0), (1, 0), (0, 1), (1, 1)];
+        for ((&offset, &(tx, ty)), &size) in table.iter().zip(coordinates.iter()).zip(sizes.iter())
+        {
+            let at = offset as usize;
+            assert_eq!(int_at(&file, at), tx);
+            assert_eq!(int_at(&file, at + 4), ty);
+            assert_eq!(int_at(&file, at + 8), 0);
+            assert_eq!(int_at(&file, at + 12), 0);
+            assert_eq!(int_at(&file, at + 16), size as i32);
+        }
+    }
+
+    #[test]
+    fn empty_tiles_are_rejected() {
+        for &(width, height) in [(0, 2), (2, 0), (0, 0)].iter() {
+            let writer = ExrWriter {
+                storage: Storage::Tiled { width, height },
+                ..ExrWriter::default()
+            };
+            let mut file = Vec::new();
+            let error = writer.write(&[layer(3, 3)], &mut file).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(file.is_empty());
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use crate::lib::consts::{PI, SQRT_2};
+use crate::lib::{
+    Aabb, ElementWise, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3,
+};
+use crate::rng;
+use crate::shapes::Frame;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveKind {
+    // A flat strip that always faces the ray, for grass blades and distant
+    // fur.
+    Ribbon,
+    // Shaded as a tube, with the normal turning across the width.
+    Cylinder,
+}
+
+// Cubic Bezier curve whose width changes linearly from one end to the other.
+// The width is assumed small next to the length, as with hair and grass.
+pub struct Curve {
+    pub control: [Vector3<Float>; 4],
+    pub width: [Float; 2],
+    pub kind: CurveKind,
+    pub material: Box<dyn Material>,
+}
+
+fn lerp(a: Vector3<Float>, b: Vector3<Float>, t: Float) -> Vector3<Float> {
+    a + (b - a) * t
+}
+
+fn split(p: &[Vector3<Float>; 4]) -> ([Vector3<Float>; 4], [Vector3<Float>; 4]) {
+    let ab = lerp(p[0], p[1], 0.5);
+    let bc = lerp(p[1], p[2], 0.5);
+    let cd = lerp(p[2], p[3], 0.5);
+    let abc = lerp(ab, bc, 0.5);
+    let bcd = lerp(bc, cd, 0.5);
+    let m = lerp(abc, bcd, 0.5);
+    ([p[0], ab, abc, m], [m, bcd, cd, p[3]])
+}
+
+// Point and derivative of a cubic Bezier curve.
+fn evaluate(p: &[Vector3<Float>; 4], t: Float) -> (Vector3<Float>, Vector3<Float>) {
+    let ab = lerp(p[0], p[1], t);
+    let bc = lerp(p[1], p[2], t);
+    let cd = lerp(p[2], p[3], t);
+    let abc = lerp(ab, bc, t);
+    let bcd = lerp(bc, cd, t);
+    (lerp(abc, bcd, t), (bcd - abc) * 3.0)
+}
+
+struct CurveHit {
+    z: Float,
+    u: Float,
+    v: Float,
+    // From the curve's center line to the ray, in the ray's frame.
+    offset: (Float, Float),
+}
+
+// The closest hit so far and the depth range still left to search.
+struct Search {
+    z_min: Float,
+    z_max: Float,
+    hit: Option<CurveHit>,
+}
+
+impl Curve {
+    fn width_at(&self, u: Float) -> Float {
+        self.width[0] + (self.width[1] - self.width[0]) * u
+    }
+
+    // Intersects the curve in a space where the ray starts at the origin and
+    // runs along z, by splitting it until the pieces are close to straight.
+    fn intersect(
+        &self,
+        p: &[Vector3<Float>; 4],
+        u0: Float,
+        u1: Float,
+        depth: usize,
+        search: &mut Search,
+    ) {
+        let radius = self.width_at(u0).max(self.width_at(u1)) / 2.0;
+        let aabb = Aabb::from_points(p);
+        if aabb.min.x - radius > 0.0
+            || aabb.max.x + radius < 0.0
+            || aabb.min.y - radius > 0.0
+            || aabb.max.y + radius < 0.0
+            || aabb.min.z - radius > search.z_max
+            || aabb.max.z + radius < search.z_min
+        {
+            return;
+        }
+
+        if depth > 0 {
+            let (left, right) = split(p);
+            let middle = (u0 + u1) / 2.0;
+            self.intersect(&left, u0, middle, depth - 1, search);
+            self.intersect(&right, middle, u1, depth - 1, search);
+            return;
+        }
+
+        // The ray has to pass between the planes through the end points
+        // perpendicular to the curve there.
+        if (p[1].y - p[0].y) * -p[0].y + p[0].x * (p[0].x - p[1].x) < 0.0
+            || (p[2].y - p[3].y) * -p[3].y + p[3].x * (p[3].x - p[2].x) < 0.0
+        {
+            return;
+        }
+
+        let (dx, dy) = (p[3].x - p[0].x, p[3].y - p[0].y);
+        let denominator = dx * dx + dy * dy;
+        if denominator == 0.0 {
+            return;
+        }
+        let w = ((-p[0].x * dx - p[0].y * dy) / denominator).clamp(0.0, 1.0);
+        let u = u0 + (u1 - u0) * w;
+        let width = self.width_at(u);
+        let (pc, dpcdw) = evaluate(p, w);
+        let distance2 = pc.x * pc.x + pc.y * pc.y;
+        if distance2 > width * width / 4.0 || pc.z < search.z_min || pc.z > search.z_max {
+            return;
+        }
+        let distance = distance2.sqrt() / width;
+        let side = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+        search.z_max = pc.z;
+        search.hit = Some(CurveHit {
+            z: pc.z,
+            u,
+            v: if side > 0.0 {
+                0.5 + distance
+            } else {
+                0.5 - distance
+            },
+            offset: (-pc.x / (width / 2.0), -pc.y / (width / 2.0)),
+        });
+    }
+
+    // Enough splits for the pieces to deviate from a line by a fraction of
+    // the width.
+    fn depth(&self, p: &[Vector3<Float>; 4]) -> usize {
+        let mut l0: Float = 0.0;
+        for i in 0..2 {
+            let d = p[i] - p[i + 1] * 2.0 + p[i + 2];
+            l0 = l0.max(d.x.abs()).max(d.y.abs()).max(d.z.abs());
+        }
+        let epsilon = self.width[0].max(self.width[1]) * 0.05;
+        let r0 = (SQRT_2 * 6.0 * l0 / (8.0 * epsilon)).log2() / 2.0;
+        if r0.is_finite() {
+            r0.clamp(0.0, 10.0) as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Hitable for Curve {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let direction = ray.direction();
+        let length = direction.magnitude();
+        let frame = Frame::new(ray.origin(), direction);
+        let local = [
+            frame.to_local(self.control[0]),
+            frame.to_local(self.control[1]),
+            frame.to_local(self.control[2]),
+            frame.to_local(self.control[3]),
+        ];
+        let mut search = Search {
+            z_min: t_min * length,
+            z_max: t_max.min(Float::MAX / length) * length,
+            hit: None,
+        };
+        self.intersect(&local, 0.0, 1.0, self.depth(&local), &mut search);
+        let hit = search.hit?;
+
+        let (_, derivative) = evaluate(&self.control, hit.u);
+        if derivative.magnitude2() == 0.0 {
+            return None;
+        }
+        let tangent = derivative.normalize();
+        let to_ray = -direction / length;
+        let facing = to_ray - tangent * to_ray.dot(tangent);
+        if facing.magnitude2() == 0.0 {
+            return None;
+        }
+        let facing = facing.normalize();
+        let normal = match self.kind {
+            CurveKind::Ribbon => facing,
+            CurveKind::Cylinder => {
+                // The offset from the center line is the sine of the angle
+                // between the normal of the tube and the facing direction.
+                let offset = frame.to_world_vector(Vector3 {
+                    x: hit.offset.0,
+                    y: hit.offset.1,
+                    z: 0.0,
+                });
+                let across = offset - tangent * offset.dot(tangent);
+                let sin = across.magnitude().min(1.0);
+                if sin > 0.0 {
+                    (facing * (1.0 - sin * sin).sqrt() + across.normalize() * sin).normalize()
+                } else {
+                    facing
+                }
+            }
+        };
+
+        // The point is on the flattened piece of curve the ray met, which
+        // can be off the true surface by a fraction of the width. As in
+        // Physically Based Rendering, twice the width bounds that along with
+        // the rounding.
+        let t = hit.z / length;
+        let width = self.width_at(hit.u);
+        Some(HitRecord {
+            t,
+            p: ray.point_at_parameter(t),
+            normal,
+            geometric_normal: facing,
+            tangent,
+            error: Vector3 {
+                x: 2.0 * width,
+                y: 2.0 * width,
+                z: 2.0 * width,
+            },
+            u: hit.u,
+            v: hit.v,
+            material: &*self.material,
+            object_id: 0,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let aabb = Aabb::from_points(&self.control);
+        let radius = self.width[0].max(self.width[1]) / 2.0;
+        let padding = Vector3 {
+            x: radius,
+            y: radius,
+            z: radius,
+        };
+        Some(Aabb::new(aabb.min - padding, aabb.max + padding))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+// Hair fiber scattering after d'Eon et al. and Chiang et al.: light reflects
+// off the cuticle (R), passes through the fiber (TT), or reflects once
+// inside it (TRT), with the rest lumped into one isotropic lobe. The fiber
+// direction comes from the tangent of the hit, and where the ray hits across
+// the fiber from how far the normal is turned away from facing the ray.
+pub struct Hair {
+    // Absorption coefficient of the fiber interior, per unit of radius.
+    pub sigma_a: Vector3<Float>,
+    pub eta: Float,
+    // Longitudinal and azimuthal roughness, in [0, 1].
+    pub beta_m: Float,
+    pub beta_n: Float,
+    // Tilt of the cuticle scales in degrees, which shifts the highlights.
+    pub alpha: Float,
+}
+
+impl Hair {
+    pub fn new(sigma_a: Vector3<Float>) -> Hair {
+        Hair {
+            sigma_a,
+            eta: 1.55,
+            beta_m: 0.3,
+            beta_n: 0.3,
+            alpha: 2.0,
+        }
+    }
+
+    // Absorption that gives roughly the color asked for after multiple
+    // scattering, with the default azimuthal roughness.
+    pub fn from_color(color: Vector3<Float>) -> Hair {
+        let mut hair = Hair::new(Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        });
+        let b = hair.beta_n;
+        let denominator = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3)
+            + 5.574 * b.powi(4)
+            + 0.245 * b.powi(5);
+        let sigma = |c: Float| (c.max(1e-4).ln() / denominator).powi(2);
+        hair.sigma_a = Vector3 {
+            x: sigma(color.x),
+            y: sigma(color.y),
+            z: sigma(color.z),
+        };
+        hair
+    }
+
+    // Natural hair colors from the concentration of the two melanin
+    // pigments: eumelanin makes hair brown to black, pheomelanin red.
+    pub fn from_melanin(eumelanin: Float, pheomelanin: Float) -> Hair {
+        Hair::new(Vector3 {
+            x: eumelanin * 0.419 + pheomelanin * 0.187,
+            y: eumelanin * 0.697 + pheomelanin * 0.4,
+            z: eumelanin * 1.37 + pheomelanin * 1.05,
+        })
+    }
+}
+
+fn fresnel(cos_i: Float, eta: Float) -> Float {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+fn logistic_cdf(x: Float, s: Float) -> Float {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+// Logistic distribution restricted to [-pi, pi].
+fn sample_trimmed_logistic(u: Float, s: Float) -> Float {
+    let low = logistic_cdf(-PI, s);
+    let k = logistic_cdf(PI, s) - low;
+    let x = -s * (1.0 / (u * k + low) - 1.0).ln();
+    x.clamp(-PI, PI)
+}
+
+fn exp(v: Vector3<Float>) -> Vector3<Float> {
+    Vector3 {
+        x: v.x.exp(),
+        y: v.y.exp(),
+        z: v.z.exp(),
+    }
+}
+
+fn mean(v: Vector3<Float>) -> Float {
+    (v.x + v.y + v.z) / 3.0
+}
+
+// Where across the fiber the ray hit, from -1 at one edge to 1 at the other.
+// It comes from v because on ribbons the normal always faces the ray.
+fn offset(record: &HitRecord) -> Float {
+    (-1.0 + 2.0 * record.v).clamp(-0.999, 0.999)
+}
+
+impl Hair {
+    // Attenuation of the R, TT, TRT and residual lobes for a ray at offset h
+    // across the fiber, and the angle of the refracted ray.
+    fn lobes(&self, sin_theta_o: Float, h: Float) -> ([Vector3<Float>; 4], Float) {
+        let cos_theta_o = (1.0 - sin_theta_o * sin_theta_o).sqrt();
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = (1.0 - sin_theta_t * sin_theta_t).sqrt();
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = (h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = (1.0 - sin_gamma_t * sin_gamma_t).sqrt();
+        let gamma_t = sin_gamma_t.asin();
+        let transmittance = exp(-self.sigma_a * (2.0 * cos_gamma_t / cos_theta_t));
+        let f = fresnel(cos_theta_o * (1.0 - h * h).sqrt(), self.eta);
+        let one = Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        };
+        let r = one * f;
+        let tt = transmittance * (1.0 - f) * (1.0 - f);
+        let trt = tt.mul_element_wise(transmittance) * f;
+        let residual = Vector3 {
+            x: trt.x * f * transmittance.x / (1.0 - f * transmittance.x),
+            y: trt.y * f * transmittance.y / (1.0 - f * transmittance.y),
+            z: trt.z * f * transmittance.z / (1.0 - f * transmittance.z),
+        };
+        ([r, tt, trt, residual], gamma_t)
+    }
+}
+
+impl Material for Hair {
+    fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
+        let wo = -ray.direction().normalize();
+        let x = if record.tangent.magnitude2() > 0.0 {
+            record.tangent
+        } else {
+            Frame::new(record.p, record.normal).u
+        };
+        let z = wo - x * wo.dot(x);
+        if z.magnitude2() == 0.0 {
+            return None;
+        }
+        let z = z.normalize();
+        let y = z.cross(x);
+
+        let sin_theta_o = wo.dot(x).clamp(-1.0, 1.0);
+        let cos_theta_o = (1.0 - sin_theta_o * sin_theta_o).sqrt();
+        let phi_o = wo.dot(y).atan2(wo.dot(z));
+        let h = offset(record);
+        let gamma_o = h.asin();
+
+        let (lobes, gamma_t) = self.lobes(sin_theta_o, h);
+        let weights = [
+            mean(lobes[0]),
+            mean(lobes[1]),
+            mean(lobes[2]),
+            mean(lobes[3]),
+        ];
+        let total: Float = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut pick = rng::random() * total;
+        let mut p = 0;
+        while p < 3 && pick >= weights[p] {
+            pick -= weights[p];
+            p += 1;
+        }
+        let attenuation = lobes[p] * (total / weights[p]);
+
+        // Longitudinal direction, with the highlight shifted by the tilt of
+        // the scales: twice for R, back once for TT and four times for TRT.
+        let v0 = (0.726 * self.beta_m + 0.812 * self.beta_m.powi(2) + 3.7 * self.beta_m.powi(20))
+            .powi(2);
+        let (v, shift) = match p {
+            0 => (v0, -2.0),
+            1 => (v0 / 4.0, 1.0),
+            _ => (v0 * 4.0, 4.0),
+        };
+        let alpha = (self.alpha * shift).to_radians();
+        let sin_theta_op = sin_theta_o * alpha.cos() + cos_theta_o * alpha.sin();
+        let cos_theta_op = (cos_theta_o * alpha.cos() - sin_theta_o * alpha.sin()).abs();
+        let u = rng::random().max(1e-5);
+        let cos_theta = 1.0 + v * (u + (1.0 - u) * (-2.0 / v).exp()).ln();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let cos_phi = (2.0 * PI * rng::random()).cos();
+        let sin_theta_i =
+            (-cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op).clamp(-1.0, 1.0);
+        let cos_theta_i = (1.0 - sin_theta_i * sin_theta_i).sqrt();
+
+        // Azimuthal direction, relative to the one the refraction geometry
+        // predicts for the lobe.
+        let s = (PI / 8.0).sqrt()
+            * (0.265 * self.beta_n + 1.194 * self.beta_n.powi(2) + 5.372 * self.beta_n.powi(22));
+        let dphi = if p < 3 {
+            let p = p as Float;
+            2.0 * p * gamma_t - 2.0 * gamma_o + p * PI + sample_trimmed_logistic(rng::random(), s)
+        } else {
+            2.0 * PI * rng::random()
+        };
+        let phi_i = phi_o + dphi;
+
+        let direction =
+            x * sin_theta_i + y * (cos_theta_i * phi_i.sin()) + z * (cos_theta_i * phi_i.cos());
+        Some((attenuation, record.spawn_ray(direction)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // A straight ribbon along x, 0.2 wide, with evenly spaced control points
+    // so that it is parametrized uniformly.
+    fn ribbon() -> Curve {
+        Curve {
+            control: [
+                point(-1.0, 0.0, 0.0),
+                point(-1.0 / 3.0, 0.0, 0.0),
+                point(1.0 / 3.0, 0.0, 0.0),
+                point(1.0, 0.0, 0.0),
+            ],
+            width: [0.2, 0.2],
+            kind: CurveKind::Ribbon,
+            material: Box::new(Lambertian {
+                albedo: point(0.5, 0.5, 0.5),
+            }),
+        }
+    }
+
+    #[test]
+    fn ribbon_hits_report_the_point_on_the_ray() {
+        let curve = ribbon();
+        let ray = Ray::new(point(0.3, 0.07, 5.0), point(0.0, 0.0, -1.0));
+        let record = curve.hit(&ray, 0.0, Float::MAX).unwrap();
+        assert_eq!(record.p, ray.point_at_parameter(record.t));
+        assert!((record.p - point(0.3, 0.07, 0.0)).magnitude() < 1e-5);
+        assert!(record.error.x >= 0.2);
+
+        for &direction in [point(0.0, 0.0, 1.0), point(0.0, 0.0, -1.0)].iter() {
+            let spawned = record.spawn_ray(direction);
+            assert!(curve.hit(&spawned, 0.0, Float::MAX).is_none());
+        }
+    }
+
+    // The ribbon's normal faces the ray wherever it's hit, so only v tells
+    // a hit near the edge from one through the middle.
+    #[test]
+    fn offset_across_the_fiber_comes_from_v() {
+        let curve = ribbon();
+        let hair = Hair::from_melanin(0.5, 0.0);
+        let hit = |y: Float| {
+            let ray = Ray::new(point(0.3, y, 5.0), point(0.0, 0.0, -1.0));
+            let record = curve.hit(&ray, 0.0, Float::MAX).unwrap();
+            assert!((record.normal - point(0.0, 0.0, 1.0)).magnitude() < 1e-6);
+            offset(&record)
+        };
+        let center = hit(0.0);
+        let edge = hit(0.095);
+        assert!(center.abs() < 0.01, "h = {}", center);
+        assert!((edge.abs() - 0.95).abs() < 0.01, "h = {}", edge);
+        assert!((hit(-0.095) + edge).abs() < 1e-6);
+
+        let (center_lobes, _) = hair.lobes(0.0, center);
+        let (edge_lobes, _) = hair.lobes(0.0, edge);
+        // Grazing hits reflect more and pass less through the fiber.
+        assert!(mean(edge_lobes[0]) > 2.0 * mean(center_lobes[0]));
+        assert!(mean(edge_lobes[1]) < mean(center_lobes[1]));
+    }
+}
@@ -0,0 +1,375 @@
+use crate::lib::{gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3};
+use crate::mesh::{barycentric_point, intersect_triangle};
+use crate::render::Image;
+use crate::shapes::facing;
+use std::io;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Terrain from a grid of height samples, split into two triangles per cell.
+// Rays walk the cells they cross in order, so the cost grows with the
+// resolution along the ray rather than with the number of samples.
+pub struct Heightfield {
+    // Samples along x and along z.
+    pub width: usize,
+    pub depth: usize,
+    // Row by row, each row running along x.
+    pub heights: Vec<Float>,
+    // Corner of the grid at height zero, and the extent along x and z, with
+    // `size.y` the height of a sample of one.
+    pub origin: Vector3<Float>,
+    pub size: Vector3<Float>,
+    pub material: Box<dyn Material>,
+    normals: Vec<Vector3<Float>>,
+    bounds: Aabb,
+}
+
+impl Heightfield {
+    pub fn new(
+        width: usize,
+        depth: usize,
+        heights: Vec<Float>,
+        origin: Vector3<Float>,
+        size: Vector3<Float>,
+        material: Box<dyn Material>,
+    ) -> io::Result<Heightfield> {
+        if width < 2 || depth < 2 {
+            return Err(invalid(format!(
+                "heightfield needs at least 2x2 samples, not {}x{}",
+                width, depth
+            )));
+        }
+        if heights.len() != width * depth {
+            return Err(invalid(format!(
+                "{}x{} heightfield given {} samples",
+                width,
+                depth,
+                heights.len()
+            )));
+        }
+        let mut heightfield = Heightfield {
+            width,
+            depth,
+            heights,
+            origin,
+            size,
+            material,
+            normals: Vec::new(),
+            bounds: Aabb::new(origin, origin),
+        };
+
+        let mut low = Float::MAX;
+        let mut high = Float::MIN;
+        for &h in heightfield.heights.iter() {
+            low = low.min(h * size.y);
+            high = high.max(h * size.y);
+        }
+        heightfield.bounds = Aabb::new(
+            Vector3 {
+                x: origin.x,
+                y: origin.y + low,
+                z: origin.z,
+            },
+            Vector3 {
+                x: origin.x + size.x,
+                y: origin.y + high,
+                z: origin.z + size.z,
+            },
+        );
+
+        // Vertex normals from central differences, one sided at the edges.
+        let (dx, dz) = heightfield.cell_size();
+        for j in 0..depth {
+            for i in 0..width {
+                let (i0, i1) = (i.saturating_sub(1), (i + 1).min(width - 1));
+                let (j0, j1) = (j.saturating_sub(1), (j + 1).min(depth - 1));
+                let slope_x = (heightfield.height(i1, j) - heightfield.height(i0, j))
+                    / ((i1 - i0) as Float * dx);
+                let slope_z = (heightfield.height(i, j1) - heightfield.height(i, j0))
+                    / ((j1 - j0) as Float * dz);
+                heightfield.normals.push(
+                    Vector3 {
+                        x: -slope_x,
+                        y: 1.0,
+                        z: -slope_z,
+                    }
+                    .normalize(),
+                );
+            }
+        }
+        Ok(heightfield)
+    }
+
+    // Heights from the luminance of an image, with its top row at the
+    // smallest z.
+    pub fn from_image(
+        image: &Image,
+        origin: Vector3<Float>,
+        size: Vector3<Float>,
+        material: Box<dyn Material>,
+    ) -> io::Result<Heightfield> {
+        let heights = image
+            .pixels
+            .iter()
+            .map(|p| 0.2126 * p.x + 0.7152 * p.y + 0.0722 * p.z)
+            .collect();
+        Heightfield::new(image.width, image.height, heights, origin, size, material)
+    }
+
+    fn cell_size(&self) -> (Float, Float) {
+        (
+            self.size.x / (self.width - 1) as Float,
+            self.size.z / (self.depth - 1) as Float,
+        )
+    }
+
+    fn height(&self, i: usize, j: usize) -> Float {
+        self.heights[j * self.width + i] * self.size.y
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Vector3<Float> {
+        let (dx, dz) = self.cell_size();
+        Vector3 {
+            x: self.origin.x + i as Float * dx,
+            y: self.origin.y + self.height(i, j),
+            z: self.origin.z + j as Float * dz,
+        }
+    }
+
+    fn hit_cell(
+        &self,
+        ray: &Ray,
+        i: usize,
+        j: usize,
+        t_min: Float,
+        t_max: Float,
+    ) -> Option<HitRecord<'_>> {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        let mut hit = None;
+        let mut closest_so_far = t_max;
+        for triangle in [[0, 1, 2], [0, 2, 3]].iter() {
+            let [a, b, c] = [
+                corners[triangle[0]],
+                corners[triangle[1]],
+                corners[triangle[2]],
+            ];
+            let [p0, p1, p2] = [
+                self.vertex(a.0, a.1),
+                self.vertex(b.0, b.1),
+                self.vertex(c.0, c.1),
+            ];
+            let (t, u, v) = match intersect_triangle(ray, p0, p1, p2) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            if t <= t_min || t >= closest_so_far {
+                continue;
+            }
+            closest_so_far = t;
+            let normal = |(i, j): (usize, usize)| self.normals[j * self.width + i];
+            let normal = normal(a) * (1.0 - u - v) + normal(b) * u + normal(c) * v;
+            let (p, error) = barycentric_point(p0, p1, p2, u, v);
+            hit = Some(HitRecord {
+                t,
+                p,
+                normal: facing(normal.normalize(), ray.direction()),
+                geometric_normal: facing((p1 - p0).cross(p2 - p0).normalize(), ray.direction()),
+                tangent: Vector3 {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                error,
+                u: (p.x - self.origin.x) / self.size.x,
+                v: (p.z - self.origin.z) / self.size.z,
+                material: &*self.material,
+                object_id: 0,
+            });
+        }
+        hit
+    }
+}
+
+impl Hitable for Heightfield {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (t_start, t_end) = self.bounds.intersect(ray, t_min, t_max)?;
+        let (dx, dz) = self.cell_size();
+        let o = ray.origin();
+        let d = ray.direction();
+
+        let entry = ray.point_at_parameter(t_start);
+        let cell = |p: Float, o: Float, size: Float, count: usize| {
+            (((p - o) / size).floor().max(0.0) as usize).min(count - 2)
+        };
+        let mut i = cell(entry.x, self.origin.x, dx, self.width);
+        let mut j = cell(entry.z, self.origin.z, dz, self.depth);
+
+        // Distance along the ray to the next cell boundary on each axis, and
+        // between boundaries.
+        let boundary =
+            |index: usize, step: Float, o_axis: Float, d_axis: Float, grid_origin: Float| {
+                if d_axis > 0.0 {
+                    (grid_origin + (index + 1) as Float * step - o_axis) / d_axis
+                } else if d_axis < 0.0 {
+                    (grid_origin + index as Float * step - o_axis) / d_axis
+                } else {
+                    Float::INFINITY
+                }
+            };
+        let mut next_x = boundary(i, dx, o.x, d.x, self.origin.x);
+        let mut next_z = boundary(j, dz, o.z, d.z, self.origin.z);
+        let delta_x = (dx / d.x).abs();
+        let delta_z = (dz / d.z).abs();
+
+        let mut t_enter = t_start;
+        loop {
+            let t_exit = next_x.min(next_z).min(t_end);
+            // Skip cells the ray passes entirely above or below, allowing for
+            // the rounding in the heights along the ray.
+            let (y0, y1) = (o.y + d.y * t_enter, o.y + d.y * t_exit);
+            let slack = (o.y.abs() + (d.y * t_enter).abs().max((d.y * t_exit).abs())) * gamma(5);
+            let heights = [
+                self.height(i, j),
+                self.height(i + 1, j),
+                self.height(i, j + 1),
+                self.height(i + 1, j + 1),
+            ];
+            let low = self.origin.y + heights.iter().cloned().fold(Float::MAX, Float::min);
+            let high = self.origin.y + heights.iter().cloned().fold(Float::MIN, Float::max);
+            if y0.min(y1) - slack <= high && y0.max(y1) + slack >= low {
+                if let Some(record) = self.hit_cell(ray, i, j, t_min, t_max) {
+                    return Some(record);
+                }
+            }
+            if t_exit >= t_end {
+                return None;
+            }
+            t_enter = t_exit;
+            if next_x < next_z {
+                if d.x > 0.0 && i + 2 < self.width {
+                    i += 1;
+                } else if d.x < 0.0 && i > 0 {
+                    i -= 1;
+                } else {
+                    return None;
+                }
+                next_x += delta_x;
+            } else {
+                if d.z > 0.0 && j + 2 < self.depth {
+                    j += 1;
+                } else if d.z < 0.0 && j > 0 {
+                    j -= 1;
+                } else {
+                    return None;
+                }
+                next_z += delta_z;
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    fn material() -> Box<dyn Material> {
+        Box::new(Lambertian {
+            albedo: point(0.5, 0.5, 0.5),
+        })
+    }
+
+    // A ridge along z, as in the mesh test: the smoothed normal at the top
+    // points up while the faces slope at 45 degrees.
+    #[test]
+    fn rays_leave_along_the_geometric_normal() {
+        let field = Heightfield::new(
+            3,
+            2,
+            vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            point(-1.0, 0.0, 0.0),
+            point(2.0, 1.0, 1.0),
+            material(),
+        )
+        .unwrap();
+        let ray = Ray::new(point(-0.05, 5.0, 0.5), point(0.0, -1.0, 0.0));
+        let record = field.hit(&ray, 0.0, Float::MAX).unwrap();
+        let face = point(-1.0, 1.0, 0.0).normalize();
+        assert!((record.geometric_normal - face).magnitude() < 1e-6);
+
+        let direction = point(-1.0, -0.2, 0.0);
+        assert!(direction.dot(record.normal) < 0.0);
+        assert!(direction.dot(face) > 0.0);
+        assert!(field
+            .hit(&record.spawn_ray(direction), 0.0, Float::MAX)
+            .is_none());
+    }
+
+    #[test]
+    fn flat_fields_hit_at_any_scale() {
+        for &scale in [1e-6, 1.0, 1e6].iter() {
+            let field = Heightfield::new(
+                4,
+                4,
+                vec![0.5; 16],
+                point(-scale, 0.0, -scale),
+                point(2.0 * scale, 2.0 * scale, 2.0 * scale),
+                material(),
+            )
+            .unwrap();
+            let ray = Ray::new(
+                point(0.3 * scale, 5.0 * scale, 0.2 * scale),
+                point(0.05, -1.0, 0.02),
+            );
+            let record = field.hit(&ray, 0.0, Float::MAX).unwrap();
+            assert!((record.p.y - scale).abs() <= record.error.y);
+            assert!((record.p - ray.point_at_parameter(record.t)).magnitude() < 1e-5 * scale);
+        }
+    }
+
+    // A one pixel wide or tall image has no cells to triangulate.
+    #[test]
+    fn thin_images_are_rejected() {
+        for &(width, height) in [(1, 4), (4, 1), (0, 0)].iter() {
+            let image = Image {
+                width,
+                height,
+                pixels: vec![point(0.5, 0.5, 0.5); width * height],
+            };
+            let error = Heightfield::from_image(
+                &image,
+                point(0.0, 0.0, 0.0),
+                point(1.0, 1.0, 1.0),
+                material(),
+            )
+            .err()
+            .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        let error = Heightfield::new(
+            2,
+            2,
+            vec![0.0; 3],
+            point(0.0, 0.0, 0.0),
+            point(1.0, 1.0, 1.0),
+            material(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::lib::{abs, gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3};
+use crate::sdf::gradient;
+
+// The surface f(p) = 0 of any continuous function, negative inside. Unlike a
+// distance field the value says nothing about how far the surface is, so the
+// part of the ray inside `bounds` is sampled at `steps` even intervals and a
+// sign change is refined by bisection. Features thinner than a step can be
+// missed.
+pub struct ImplicitSurface {
+    pub function: Box<dyn Fn(Vector3<Float>) -> Float>,
+    pub bounds: Aabb,
+    pub steps: usize,
+    pub refinements: usize,
+    // Step for the finite difference normals, and how close to the surface
+    // rounding in the function may move a point.
+    pub epsilon: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl ImplicitSurface {
+    pub fn new<F>(function: F, bounds: Aabb, material: Box<dyn Material>) -> ImplicitSurface
+    where
+        F: Fn(Vector3<Float>) -> Float + 'static,
+    {
+        ImplicitSurface {
+            function: Box::new(function),
+            bounds,
+            steps: 256,
+            refinements: 32,
+            epsilon: 1e-4,
+            material,
+        }
+    }
+
+    // Bisection between two points where the function has opposite signs,
+    // returning the middle of the final bracket and its half width.
+    fn refine(&self, ray: &Ray, mut a: Float, mut b: Float, fa: Float) -> (Float, Float) {
+        let negative_at_a = fa < 0.0;
+        for _ in 0..self.refinements {
+            let middle = (a + b) / 2.0;
+            if ((self.function)(ray.point_at_parameter(middle)) < 0.0) == negative_at_a {
+                a = middle;
+            } else {
+                b = middle;
+            }
+        }
+        ((a + b) / 2.0, (b - a) / 2.0)
+    }
+}
+
+impl Hitable for ImplicitSurface {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (t_start, t_end) = self.bounds.intersect(ray, t_min, t_max)?;
+        let step = (t_end - t_start) / self.steps as Float;
+        let mut t = t_start;
+        let mut f = (self.function)(ray.point_at_parameter(t));
+        for k in 1..=self.steps {
+            let t_next = t_start + step * k as Float;
+            let f_next = (self.function)(ray.point_at_parameter(t_next));
+            if (f < 0.0) != (f_next < 0.0) {
+                let (t, half_width) = self.refine(ray, t, t_next, f);
+                let p = ray.point_at_parameter(t);
+                let normal = gradient(&*self.function, p, self.epsilon);
+                let normal = if normal.magnitude2() > 0.0 {
+                    normal.normalize()
+                } else {
+                    -ray.direction().normalize()
+                };
+                return Some(HitRecord {
+                    t,
+                    p,
+                    normal,
+                    geometric_normal: normal,
+                    tangent: Vector3 {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    // The function's own rounding can shift its zero by a
+                    // little, which epsilon is taken to cover.
+                    error: abs(ray.direction()) * half_width
+                        + abs(p) * gamma(4)
+                        + Vector3 {
+                            x: self.epsilon,
+                            y: self.epsilon,
+                            z: self.epsilon,
+                        },
+                    u: 0.0,
+                    v: 0.0,
+                    material: &*self.material,
+                    object_id: 0,
+                });
+            }
+            t = t_next;
+            f = f_next;
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // The unit sphere as x² + y² + z² - 1, scaled to show that only the sign
+    // of the function matters.
+    fn sphere(scale: Float) -> ImplicitSurface {
+        ImplicitSurface::new(
+            move |p: Vector3<Float>| scale * (p.magnitude2() - 1.0),
+            Aabb::new(point(-2.0, -2.0, -2.0), point(2.0, 2.0, 2.0)),
+            Box::new(Lambertian {
+                albedo: point(0.5, 0.5, 0.5),
+            }),
+        )
+    }
+
+    #[test]
+    fn rays_find_the_zero_of_the_function() {
+        for &scale in [1e-3, 1.0, 1e3].iter() {
+            let surface = sphere(scale);
+            let ray = Ray::new(point(0.0, 0.0, 5.0), point(0.0, 0.0, -1.0));
+            let record = surface.hit(&ray, 0.0, Float::MAX).unwrap();
+            assert!((record.t - 4.0).abs() < 1e-4, "t = {}", record.t);
+            assert!((record.p - point(0.0, 0.0, 1.0)).magnitude() <= record.error.magnitude());
+            assert!((record.normal - point(0.0, 0.0, 1.0)).magnitude() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn rays_from_inside_leave_through_the_surface() {
+        let surface = sphere(1.0);
+        let ray = Ray::new(point(0.0, 0.0, 0.0), point(1.0, 0.0, 0.0));
+        let record = surface.hit(&ray, 0.0, Float::MAX).unwrap();
+        assert!((record.t - 1.0).abs() < 1e-4);
+        // The gradient points out whichever side the ray came from.
+        assert!((record.normal - point(1.0, 0.0, 0.0)).magnitude() < 1e-3);
+    }
+
+    #[test]
+    fn rays_past_the_surface_miss() {
+        let surface = sphere(1.0);
+        let ray = Ray::new(point(0.0, 1.5, 5.0), point(0.0, 0.0, -1.0));
+        assert!(surface.hit(&ray, 0.0, Float::MAX).is_none());
+        // The sphere lies beyond t_max.
+        let ray = Ray::new(point(0.0, 0.0, 5.0), point(0.0, 0.0, -1.0));
+        assert!(surface.hit(&ray, 0.0, 3.0).is_none());
+    }
+}
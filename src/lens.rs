@@ -0,0 +1,393 @@
+use crate::camera::basis;
+use crate::lib::{consts, refract, Camera, Float, InnerSpace, Ray, Vector3};
+use crate::rng;
+use std::io::{self, BufRead};
+
+// One surface of a lens, listed from the front of the lens to the back.
+// `thickness` is the distance along the axis to the next surface, or to the
+// film for the last one, and `eta` the index of refraction behind the
+// surface, with 0 meaning air. A radius of 0 is the aperture stop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LensElement {
+    pub radius: Float,
+    pub thickness: Float,
+    pub eta: Float,
+    pub aperture_radius: Float,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reads a lens prescription in the format of the lens files shipped with
+// pbrt: one surface per line as curvature radius, thickness, index of
+// refraction and aperture diameter, all lengths in millimeters, with `#`
+// starting a comment. Lengths are converted to meters, so scenes rendered
+// through real lenses should be modelled in meters.
+pub fn read_lens<R: BufRead>(input: &mut R) -> io::Result<Vec<LensElement>> {
+    let mut elements = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or("");
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = line
+            .split_whitespace()
+            .map(|field| {
+                field
+                    .parse::<Float>()
+                    .map_err(|_| invalid(format!("line {}: bad number {}", number + 1, field)))
+            })
+            .collect::<io::Result<Vec<Float>>>()?;
+        if fields.len() != 4 {
+            return Err(invalid(format!(
+                "line {}: expected radius, thickness, eta and aperture",
+                number + 1
+            )));
+        }
+        elements.push(LensElement {
+            radius: fields[0] * 0.001,
+            thickness: fields[1] * 0.001,
+            eta: fields[2],
+            aperture_radius: fields[3] * 0.001 / 2.0,
+        });
+    }
+    if elements.is_empty() {
+        return Err(invalid("lens has no elements".to_string()));
+    }
+    Ok(elements)
+}
+
+fn medium(eta: Float) -> Float {
+    if eta == 0.0 {
+        1.0
+    } else {
+        eta
+    }
+}
+
+// Camera that traces rays through the surfaces of a real lens, which gives
+// the bokeh, vignetting and distortion of that lens. It works in the lens's
+// own space, with the film at z = 0, the lens in front of it along -z and
+// the image upside down on the film. Exposure depends on the lens and its
+// stop, as it does for a real camera.
+pub struct RealisticCamera {
+    pub origin: Vector3<Float>,
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+    pub elements: Vec<LensElement>,
+    pub film_width: Float,
+    pub film_height: Float,
+}
+
+impl RealisticCamera {
+    // `lookfrom` is the position of the film, and `focus_dist` is measured
+    // from it. The field of view follows from the film size and the focal
+    // length of the lens.
+    pub fn new(
+        lookfrom: Vector3<Float>,
+        lookat: Vector3<Float>,
+        vup: Vector3<Float>,
+        elements: Vec<LensElement>,
+        film_diagonal: Float,
+        aspect: Float,
+        focus_dist: Float,
+    ) -> io::Result<RealisticCamera> {
+        if elements.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "lens has no elements",
+            ));
+        }
+        let (u, v, w) = basis(lookfrom, lookat, vup);
+        let film_height = film_diagonal / (1.0 + aspect * aspect).sqrt();
+        let mut camera = RealisticCamera {
+            origin: lookfrom,
+            u,
+            v,
+            w,
+            elements,
+            film_width: film_height * aspect,
+            film_height,
+        };
+        camera.focus(focus_dist)?;
+        Ok(camera)
+    }
+
+    // Position of each surface's vertex on the axis.
+    fn vertices(&self) -> Vec<Float> {
+        let mut z = 0.0;
+        let mut vertices = vec![0.0; self.elements.len()];
+        for (vertex, element) in vertices.iter_mut().zip(self.elements.iter()).rev() {
+            z -= element.thickness;
+            *vertex = z;
+        }
+        vertices
+    }
+
+    // Moves the film so that a point on the axis `distance` in front of it is
+    // in focus. Since moving the film also moves the point relative to the
+    // lens, this repeats until a paraxial ray from the point converges on
+    // the film. Fails, leaving the film where it was, if the lens has no
+    // image of the point behind it.
+    pub fn focus(&mut self, distance: Float) -> io::Result<()> {
+        let object = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: -distance,
+        };
+        let last = self.elements.len() - 1;
+        let thickness = self.elements[last].thickness;
+        for _ in 0..32 {
+            let shift = match self.film_shift(object) {
+                Some(shift) => shift,
+                None => {
+                    self.elements[last].thickness = thickness;
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("lens cannot focus at {}", distance),
+                    ));
+                }
+            };
+            self.elements[last].thickness += shift;
+            if shift.abs() < 1e-9 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // How far the film has to move for a paraxial ray from `object` to
+    // converge on it. None if the ray doesn't make it through the lens or
+    // converges in front of the rear surface.
+    fn film_shift(&self, object: Vector3<Float>) -> Option<Float> {
+        let target = Vector3 {
+            x: self.elements[0].aperture_radius * 0.01,
+            y: 0.0,
+            z: self.vertices()[0],
+        };
+        let ray = self.trace(&Ray::new(object, target - object), false)?;
+        if ray.direction().x == 0.0 {
+            return None;
+        }
+        let t = -ray.origin().x / ray.direction().x;
+        let shift = ray.point_at_parameter(t).z;
+        if self.elements[self.elements.len() - 1].thickness + shift <= 0.0 {
+            return None;
+        }
+        Some(shift)
+    }
+
+    // Follows a ray through the surfaces, from the film to the scene or the
+    // other way round. None if it hits the barrel or reflects internally.
+    fn trace(&self, ray: &Ray, from_film: bool) -> Option<Ray> {
+        let vertices = self.vertices();
+        let n = self.elements.len();
+        let mut origin = ray.origin();
+        let mut direction = ray.direction().normalize();
+        for step in 0..n {
+            let i = if from_film { n - 1 - step } else { step };
+            let element = self.elements[i];
+            let (p, normal) = intersect(element.radius, vertices[i], origin, direction)?;
+            if p.x * p.x + p.y * p.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            origin = p;
+            if element.radius != 0.0 {
+                let behind = medium(element.eta);
+                let in_front = if i == 0 {
+                    1.0
+                } else {
+                    medium(self.elements[i - 1].eta)
+                };
+                let ratio = if from_film {
+                    behind / in_front
+                } else {
+                    in_front / behind
+                };
+                direction = refract(direction, normal, ratio)?;
+            }
+        }
+        Some(Ray::new(origin, direction))
+    }
+}
+
+// Where a ray meets the surface with its vertex at z on the axis, with the
+// normal facing the ray. Of the two places a ray meets a sphere, the lens
+// surface is the one on the vertex's side of the center.
+fn intersect(
+    radius: Float,
+    z: Float,
+    origin: Vector3<Float>,
+    direction: Vector3<Float>,
+) -> Option<(Vector3<Float>, Vector3<Float>)> {
+    let axis = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    };
+    if radius == 0.0 {
+        let t = (z - origin.z) / direction.z;
+        if t <= 0.0 || !t.is_finite() {
+            return None;
+        }
+        let normal = if direction.z > 0.0 { -axis } else { axis };
+        return Some((origin + direction * t, normal));
+    }
+    let center = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: z + radius,
+    };
+    let oc = origin - center;
+    let b = oc.dot(direction);
+    let c = oc.magnitude2() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let t = [-b - root, -b + root]
+        .iter()
+        .cloned()
+        .find(|&t| t > 0.0 && (origin.z + direction.z * t - center.z) * radius < 0.0)?;
+    let p = origin + direction * t;
+    let normal = (p - center) / radius.abs();
+    let normal = if normal.dot(direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    };
+    Some((p, normal))
+}
+
+impl Camera for RealisticCamera {
+    // Aims at a uniform point on the rear surface. The cos^4 falloff of
+    // irradiance on the film is applied by discarding rays with the
+    // complementary probability, which keeps the estimate unbiased.
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        let film = Vector3 {
+            x: (0.5 - s) * self.film_width,
+            y: (0.5 - t) * self.film_height,
+            z: 0.0,
+        };
+        let rear = self.elements[self.elements.len() - 1];
+        let r = rear.aperture_radius * rng::random().sqrt();
+        let phi = 2.0 * consts::PI * rng::random();
+        let target = Vector3 {
+            x: r * phi.cos(),
+            y: r * phi.sin(),
+            z: -rear.thickness,
+        };
+        let direction = (target - film).normalize();
+        if rng::random() > direction.z.powi(4) {
+            return None;
+        }
+        let ray = self.trace(&Ray::new(film, direction), true)?;
+        let (o, d) = (ray.origin(), ray.direction());
+        Some(Ray::new(
+            self.origin + self.u * o.x + self.v * o.y + self.w * o.z,
+            self.u * d.x + self.v * d.y + self.w * d.z,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // A symmetric biconvex singlet behind a stop, with a focal length of
+    // about 100mm.
+    const SINGLET: &str = "# radius thickness eta aperture
+        0     5  0    16
+
+        100   2  1.5  20  # front
+        -100  90 0    20
+    ";
+
+    fn singlet(focus_dist: Float) -> io::Result<RealisticCamera> {
+        let elements = read_lens(&mut SINGLET.as_bytes()).unwrap();
+        RealisticCamera::new(
+            point(0.0, 0.0, 0.0),
+            point(0.0, 0.0, -1.0),
+            point(0.0, 1.0, 0.0),
+            elements,
+            0.035,
+            1.5,
+            focus_dist,
+        )
+    }
+
+    #[test]
+    fn prescriptions_are_read_in_meters() {
+        let elements = read_lens(&mut SINGLET.as_bytes()).unwrap();
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].radius, 0.0);
+        assert!((elements[0].aperture_radius - 0.008).abs() < 1e-7);
+        assert!((elements[1].radius - 0.1).abs() < 1e-7);
+        assert!((elements[1].thickness - 0.002).abs() < 1e-7);
+        assert_eq!(elements[1].eta, 1.5);
+        assert!((elements[2].radius + 0.1).abs() < 1e-7);
+        assert_eq!(elements[2].eta, 0.0);
+
+        for &text in ["", "# nothing\n", "100 2 1.5\n", "100 2 glass 20\n"].iter() {
+            let error = read_lens(&mut text.as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    // After focusing, paraxial rays from the point on the axis meet on the
+    // film, and for a far point the film ends up near the focal length.
+    #[test]
+    fn focus_makes_paraxial_rays_converge_on_the_film() {
+        for &distance in [0.5, 1.0, 1000.0].iter() {
+            let camera = singlet(distance).unwrap();
+            let object = point(0.0, 0.0, -distance);
+            let front = camera.vertices()[0];
+            for &height in [0.001, -0.0005].iter() {
+                let target = point(0.0, height, front);
+                let ray = camera
+                    .trace(&Ray::new(object, target - object), false)
+                    .unwrap();
+                let t = -ray.origin().y / ray.direction().y;
+                let z = ray.point_at_parameter(t).z;
+                assert!(z.abs() < 1e-4, "z = {} at {}", z, distance);
+            }
+        }
+        let far = singlet(1000.0).unwrap().elements[2].thickness;
+        let near = singlet(0.5).unwrap().elements[2].thickness;
+        assert!((far - 0.1).abs() < 0.002, "back focus {}", far);
+        assert!(near > far);
+    }
+
+    #[test]
+    fn lenses_that_cannot_focus_are_errors() {
+        let error = RealisticCamera::new(
+            point(0.0, 0.0, 0.0),
+            point(0.0, 0.0, -1.0),
+            point(0.0, 1.0, 0.0),
+            Vec::new(),
+            0.035,
+            1.5,
+            1.0,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // 15cm from the film the point is within the focal length of the
+        // lens, which then only forms a virtual image.
+        let error = singlet(0.15).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let mut camera = singlet(1.0).unwrap();
+        let thickness = camera.elements[2].thickness;
+        assert!(camera.focus(0.15).is_err());
+        assert_eq!(camera.elements[2].thickness, thickness);
+    }
+}
@@ -1,92 +1,418 @@
+pub mod animation;
+pub mod aov;
+pub mod aperture;
+pub mod bvh;
+pub mod camera;
+pub mod checkpoint;
+pub mod compound;
+pub mod csg;
+pub mod denoise;
+pub mod exr;
+pub mod hair;
+pub mod heightfield;
+pub mod implicit;
+pub mod lens;
+pub mod mesh;
+pub mod particles;
+pub mod patch;
+#[cfg(feature = "preview")]
+pub mod preview;
+pub mod render;
+pub mod rng;
+pub mod scene;
+pub mod sdf;
+pub mod shapes;
+pub mod spectral;
+pub mod stereo;
+pub mod terminal;
+
 pub mod lib {
+    use crate::aperture::Aperture;
+    use crate::rng;
+    use crate::spectral::{rgb_to_spectrum, RefractiveIndex, SampledSpectrum, SampledWavelengths};
     pub use cgmath::prelude::{ElementWise, InnerSpace};
     pub use cgmath::Vector3;
-    use rand;
-    use std::f32;
+    use cgmath::{Deg, Matrix3};
+    use std::rc::Rc;
+
+    // Scalar type of all geometry and color math. Building with the `f64`
+    // feature trades speed and memory for precision in scenes that span
+    // large distances.
+    #[cfg(not(feature = "f64"))]
+    pub type Float = f32;
+    #[cfg(feature = "f64")]
+    pub type Float = f64;
+    #[cfg(not(feature = "f64"))]
+    pub use std::f32::consts;
+    #[cfg(feature = "f64")]
+    pub use std::f64::consts;
+
+    // Conversions where precision is fixed regardless of the build: the
+    // polynomial solvers work in f64 and image files store f32.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_f64(x: Float) -> f64 {
+        x as f64
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    pub fn to_f32(x: Float) -> f32 {
+        x as f32
+    }
 
     pub struct Ray {
-        pub a: Vector3<f32>,
-        pub b: Vector3<f32>,
+        pub a: Vector3<Float>,
+        pub b: Vector3<Float>,
     }
 
     impl Ray {
-        pub fn new(a: Vector3<f32>, b: Vector3<f32>) -> Ray {
+        pub fn new(a: Vector3<Float>, b: Vector3<Float>) -> Ray {
             Ray { a, b }
         }
 
-        pub fn origin(&self) -> Vector3<f32> {
+        pub fn origin(&self) -> Vector3<Float> {
             self.a
         }
 
-        pub fn direction(&self) -> Vector3<f32> {
+        pub fn direction(&self) -> Vector3<Float> {
             self.b
         }
 
-        pub fn point_at_parameter(&self, t: f32) -> Vector3<f32> {
+        pub fn point_at_parameter(&self, t: Float) -> Vector3<Float> {
             self.a + self.b * t
         }
     }
 
+    #[derive(Clone, Copy)]
     pub struct HitRecord<'a> {
-        pub t: f32,
-        pub p: Vector3<f32>,
-        pub normal: Vector3<f32>,
-        pub material: &'a Box<Material>,
+        pub t: Float,
+        pub p: Vector3<Float>,
+        pub normal: Vector3<Float>,
+        // Normal of the surface actually intersected, on the same side as
+        // `normal`. The two differ where shading normals are interpolated,
+        // and rays leave along this one.
+        pub geometric_normal: Vector3<Float>,
+        // Direction of increasing u, or zero for shapes that don't track it.
+        pub tangent: Vector3<Float>,
+        // Bound on the absolute rounding error of `p`, per axis.
+        pub error: Vector3<Float>,
+        pub u: Float,
+        pub v: Float,
+        pub material: &'a dyn Material,
+        pub object_id: usize,
+    }
+
+    impl<'a> HitRecord<'a> {
+        // A ray leaving the surface, from an origin moved off it far enough
+        // that the ray can't hit the surface again through rounding error.
+        // This is what lets the integrators trace with a t_min of zero.
+        pub fn spawn_ray(&self, direction: Vector3<Float>) -> Ray {
+            Ray::new(
+                offset_ray_origin(self.p, self.error, self.geometric_normal, direction),
+                direction,
+            )
+        }
+    }
+
+    // Bound on the relative error of n rounded floating point operations, as
+    // in Physically Based Rendering, section 3.9.
+    pub fn gamma(n: i32) -> Float {
+        let epsilon = Float::EPSILON * 0.5;
+        (n as Float * epsilon) / (1.0 - n as Float * epsilon)
+    }
+
+    pub fn abs(v: Vector3<Float>) -> Vector3<Float> {
+        Vector3 {
+            x: v.x.abs(),
+            y: v.y.abs(),
+            z: v.z.abs(),
+        }
+    }
+
+    fn next_float_up(v: Float) -> Float {
+        if v.is_infinite() && v > 0.0 {
+            return v;
+        }
+        let v = if v == 0.0 { 0.0 } else { v };
+        let bits = v.to_bits();
+        Float::from_bits(if v >= 0.0 { bits + 1 } else { bits - 1 })
+    }
+
+    fn next_float_down(v: Float) -> Float {
+        -next_float_up(-v)
+    }
+
+    // Moves p along the normal by the error bound projected onto it, towards
+    // the side direction w leaves on, then rounds away from the surface so
+    // the addition itself can't land back inside the bound.
+    pub fn offset_ray_origin(
+        p: Vector3<Float>,
+        error: Vector3<Float>,
+        normal: Vector3<Float>,
+        w: Vector3<Float>,
+    ) -> Vector3<Float> {
+        let distance = abs(normal).dot(error);
+        let mut offset = normal * distance;
+        if w.dot(normal) < 0.0 {
+            offset = -offset;
+        }
+        let mut origin = p + offset;
+        for axis in 0..3 {
+            if offset[axis] > 0.0 {
+                origin[axis] = next_float_up(origin[axis]);
+            } else if offset[axis] < 0.0 {
+                origin[axis] = next_float_down(origin[axis]);
+            }
+        }
+        origin
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Aabb {
+        pub min: Vector3<Float>,
+        pub max: Vector3<Float>,
+    }
+
+    impl Aabb {
+        pub fn new(min: Vector3<Float>, max: Vector3<Float>) -> Aabb {
+            Aabb { min, max }
+        }
+
+        pub fn from_points(points: &[Vector3<Float>]) -> Aabb {
+            let mut aabb = Aabb::new(points[0], points[0]);
+            for point in points[1..].iter() {
+                aabb = aabb.union(&Aabb::new(*point, *point));
+            }
+            aabb
+        }
+
+        pub fn union(&self, other: &Aabb) -> Aabb {
+            Aabb {
+                min: Vector3 {
+                    x: self.min.x.min(other.min.x),
+                    y: self.min.y.min(other.min.y),
+                    z: self.min.z.min(other.min.z),
+                },
+                max: Vector3 {
+                    x: self.max.x.max(other.max.x),
+                    y: self.max.y.max(other.max.y),
+                    z: self.max.z.max(other.max.z),
+                },
+            }
+        }
+
+        pub fn centroid(&self) -> Vector3<Float> {
+            (self.min + self.max) * 0.5
+        }
+
+        pub fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+            self.intersect(ray, t_min, t_max).is_some()
+        }
+
+        // The part of [t_min, t_max] where the ray is inside the box. The far
+        // distances are rounded up by their error bound, as in Physically
+        // Based Rendering, section 3.9.2, so boxes without thickness, like
+        // those of flat shapes, are still hit.
+        pub fn intersect(
+            &self,
+            ray: &Ray,
+            mut t_min: Float,
+            mut t_max: Float,
+        ) -> Option<(Float, Float)> {
+            let origin = ray.origin();
+            let direction = ray.direction();
+            for axis in 0..3 {
+                let inv_d = 1.0 / direction[axis];
+                let mut t0 = (self.min[axis] - origin[axis]) * inv_d;
+                let mut t1 = (self.max[axis] - origin[axis]) * inv_d;
+                if inv_d < 0.0 {
+                    std::mem::swap(&mut t0, &mut t1);
+                }
+                t1 *= 1.0 + 2.0 * gamma(3);
+                t_min = if t0 > t_min { t0 } else { t_min };
+                t_max = if t1 < t_max { t1 } else { t_max };
+                if t_max < t_min {
+                    return None;
+                }
+            }
+            Some((t_min, t_max))
+        }
     }
 
     pub trait Hitable {
-        fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+        fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+
+        // None for unbounded geometry such as infinite planes.
+        fn bounding_box(&self) -> Option<Aabb>;
+
+        // Every material the object can report in a hit, in scene order.
+        fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>);
+    }
+
+    // Where a ray is inside a solid, with outward normals at both ends.
+    #[derive(Clone, Copy)]
+    pub struct Span<'a> {
+        pub enter: HitRecord<'a>,
+        pub exit: HitRecord<'a>,
+    }
+
+    // A closed shape that can report every interval a ray spends inside it,
+    // over the whole line rather than just an interval of t. This is what
+    // constructive solid geometry operates on.
+    pub trait Solid: Hitable {
+        fn spans(&self, ray: &Ray) -> Vec<Span<'_>>;
     }
 
     pub struct Sphere {
-        pub center: Vector3<f32>,
-        pub radius: f32,
-        pub material: Box<Material>,
+        pub center: Vector3<Float>,
+        pub radius: Float,
+        pub material: Box<dyn Material>,
     }
 
-    impl Hitable for Sphere {
-        fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    impl Sphere {
+        fn roots(&self, ray: &Ray) -> Option<(Float, Float)> {
             let oc = ray.origin() - self.center;
             let a = ray.direction().dot(ray.direction());
             let b = oc.dot(ray.direction());
             let c = oc.dot(oc) - self.radius * self.radius;
             let discriminant = b * b - a * c;
             if discriminant > 0.0 {
-                let mut temp = (-b - discriminant.sqrt()) / a;
-                if temp < t_max && temp > t_min {
-                    let point = ray.point_at_parameter(temp);
-                    let hit = HitRecord {
-                        t: temp,
-                        p: point,
-                        normal: (point - self.center) / self.radius,
-                        material: &self.material,
-                    };
-                    return Some(hit);
-                }
+                return Some((
+                    (-b - discriminant.sqrt()) / a,
+                    (-b + discriminant.sqrt()) / a,
+                ));
+            }
+            None
+        }
 
-                temp = (-b + discriminant.sqrt()) / a;
-                if temp < t_max && temp > t_min {
-                    let point = ray.point_at_parameter(temp);
-                    let hit = HitRecord {
-                        t: temp,
-                        p: point,
-                        normal: (point - self.center) / self.radius,
-                        material: &self.material,
-                    };
-                    return Some(hit);
+        fn record(&self, ray: &Ray, t: Float) -> HitRecord<'_> {
+            // Projecting the hit back onto the sphere bounds its error far
+            // more tightly than the rounding in t would.
+            let offset = ray.point_at_parameter(t) - self.center;
+            let offset = offset * (self.radius.abs() / offset.magnitude());
+            let point = self.center + offset;
+            let normal = offset / self.radius;
+            let (u, v) = sphere_uv(normal);
+            HitRecord {
+                t,
+                p: point,
+                normal,
+                geometric_normal: normal,
+                tangent: Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                error: abs(offset) * gamma(5) + abs(self.center) * gamma(1),
+                u,
+                v,
+                material: &*self.material,
+                object_id: 0,
+            }
+        }
+    }
+
+    impl Hitable for Sphere {
+        fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+            if let Some((near, far)) = self.roots(ray) {
+                for &temp in [near, far].iter() {
+                    if temp < t_max && temp > t_min {
+                        return Some(self.record(ray, temp));
+                    }
                 }
             }
             None
         }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            let extent = Vector3 {
+                x: self.radius.abs(),
+                y: self.radius.abs(),
+                z: self.radius.abs(),
+            };
+            Some(Aabb::new(self.center - extent, self.center + extent))
+        }
+
+        fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+            materials.push(&*self.material);
+        }
+    }
+
+    impl Solid for Sphere {
+        fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+            match self.roots(ray) {
+                Some((near, far)) => vec![Span {
+                    enter: self.record(ray, near),
+                    exit: self.record(ray, far),
+                }],
+                None => Vec::new(),
+            }
+        }
+    }
+
+    // Longitude and latitude of a point on the unit sphere, both in [0, 1].
+    pub fn sphere_uv(p: Vector3<Float>) -> (Float, Float) {
+        let phi = p.z.atan2(p.x);
+        let theta = p.y.clamp(-1.0, 1.0).asin();
+        (
+            1.0 - (phi + consts::PI) / (2.0 * consts::PI),
+            (theta + consts::FRAC_PI_2) / consts::PI,
+        )
+    }
+
+    impl<T: Hitable> Hitable for Vec<T> {
+        fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+            let mut hit: Option<HitRecord> = None;
+            let mut closest_so_far = t_max;
+            for (index, object) in self.iter().enumerate() {
+                if let Some(mut record) = object.hit(ray, t_min, closest_so_far) {
+                    closest_so_far = record.t;
+                    record.object_id = index;
+                    hit = Some(record);
+                }
+            }
+            hit
+        }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            let mut boxes = self.iter().map(|object| object.bounding_box());
+            let first = boxes.next()??;
+            boxes.try_fold(first, |aabb, other| Some(aabb.union(&other?)))
+        }
+
+        fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+            for object in self.iter() {
+                object.materials(materials);
+            }
+        }
+    }
+
+    impl<T: Hitable + ?Sized> Hitable for Box<T> {
+        fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+            (**self).hit(ray, t_min, t_max)
+        }
+
+        fn bounding_box(&self) -> Option<Aabb> {
+            (**self).bounding_box()
+        }
+
+        fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+            (**self).materials(materials);
+        }
     }
 
-    fn random_in_unit_disk() -> Vector3<f32> {
-        let mut p: Vector3<f32>;
+    impl<T: Solid + ?Sized> Solid for Box<T> {
+        fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+            (**self).spans(ray)
+        }
+    }
+
+    pub(crate) fn random_in_unit_disk() -> Vector3<Float> {
+        let mut p: Vector3<Float>;
         loop {
             p =
                 2.0 * Vector3 {
-                    x: rand::random::<f32>(),
-                    y: rand::random::<f32>(),
+                    x: rng::random(),
+                    y: rng::random(),
                     z: 0.0,
                 } - Vector3 {
                     x: 1.0,
@@ -94,35 +420,58 @@ pub mod lib {
                     z: 0.0,
                 };
 
-            if p.dot(p) >= 1.0 {
+            if p.dot(p) < 1.0 {
                 break;
             }
         }
         p
     }
 
-    pub struct Camera {
-        pub origin: Vector3<f32>,
-        pub lower_left_corner: Vector3<f32>,
-        pub horizontal: Vector3<f32>,
-        pub vertical: Vector3<f32>,
-        pub u: Vector3<f32>,
-        pub v: Vector3<f32>,
-        pub w: Vector3<f32>,
-        pub lens_radius: f32,
+    // Maps image coordinates to primary rays. s runs from the left edge to the
+    // right one and t from the bottom edge to the top one, both over [0, 1].
+    // None is a ray blocked inside the camera, which counts as black.
+    pub trait Camera {
+        fn get_ray(&self, s: Float, t: Float) -> Option<Ray>;
+
+        // Scale applied to the radiance the camera sees.
+        fn exposure(&self) -> Float {
+            1.0
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct ThinLensCamera {
+        pub origin: Vector3<Float>,
+        pub lower_left_corner: Vector3<Float>,
+        pub horizontal: Vector3<Float>,
+        pub vertical: Vector3<Float>,
+        pub u: Vector3<Float>,
+        pub v: Vector3<Float>,
+        pub w: Vector3<Float>,
+        pub lens_radius: Float,
+        pub aperture: Aperture,
+        // Clipping of the aperture by the lens barrel towards the edges of
+        // the frame, which turns bokeh into cat's eyes. 0 turns it off, and
+        // at 1 the barrel is off by the aperture's radius in the corners,
+        // which leaves them about 39% of the aperture.
+        pub cats_eye: Float,
+        // Normal of a tilted plane of focus through the center of the usual
+        // one, as set by `tilt`.
+        pub focal_plane: Option<Vector3<Float>>,
+        pub exposure: Float,
     }
 
-    impl Camera {
+    impl ThinLensCamera {
         pub fn new(
-            lookfrom: Vector3<f32>,
-            lookat: Vector3<f32>,
-            vup: Vector3<f32>,
-            vfov: f32,
-            aspect: f32,
-            aperture: f32,
-            focus_dist: f32,
-        ) -> Camera {
-            let theta = vfov * f32::consts::PI / 180.0;
+            lookfrom: Vector3<Float>,
+            lookat: Vector3<Float>,
+            vup: Vector3<Float>,
+            vfov: Float,
+            aspect: Float,
+            aperture: Float,
+            focus_dist: Float,
+        ) -> ThinLensCamera {
+            let theta = vfov * consts::PI / 180.0;
             let half_height = (theta / 2.0).tan();
             let half_width = aspect * half_height;
 
@@ -131,7 +480,7 @@ pub mod lib {
             let u = vup.cross(w).normalize();
             let v = w.cross(u);
 
-            Camera {
+            ThinLensCamera {
                 origin,
                 lower_left_corner: origin
                     - half_width * focus_dist * u
@@ -143,47 +492,115 @@ pub mod lib {
                 v,
                 w,
                 lens_radius: aperture / 2.0,
+                aperture: Aperture::Circle,
+                cats_eye: 0.0,
+                focal_plane: None,
+                exposure: 1.0,
             }
         }
 
-        pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-            let rd = self.lens_radius * random_in_unit_disk();
-            let offset = self.u * rd.x + self.v * rd.y;
-            Ray {
-                a: self.origin + offset,
-                b: self.lower_left_corner + s * self.horizontal + t * self.vertical
-                    - self.origin
-                    - offset,
+        pub fn focus_dist(&self) -> Float {
+            let center = self.lower_left_corner + 0.5 * self.horizontal + 0.5 * self.vertical;
+            (self.origin - center).dot(self.w)
+        }
+
+        // Moves the plane of focus to the depth of whatever is seen through
+        // the image point (s, t), like a camera's autofocus point. Returns
+        // the new focus distance, or None if nothing is there.
+        pub fn autofocus(&mut self, world: &dyn Hitable, s: Float, t: Float) -> Option<Float> {
+            let old = self.focus_dist();
+            let direction =
+                self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin;
+            let record = world.hit(&Ray::new(self.origin, direction), 0.0, Float::MAX)?;
+            let new = record.t * old;
+            let scale = new / old;
+            self.lower_left_corner = self.origin + (self.lower_left_corner - self.origin) * scale;
+            self.horizontal *= scale;
+            self.vertical *= scale;
+            Some(new)
+        }
+
+        // Tilts the plane of focus by `tilt` degrees about the horizontal
+        // axis and `swing` degrees about the vertical one, as a tilt-shift
+        // lens does. A positive tilt brings the near part of the plane down
+        // and its far part up, so the plane can lie along the ground.
+        pub fn tilt(&mut self, tilt: Float, swing: Float) {
+            let normal = Matrix3::from_axis_angle(self.u, Deg(-tilt)) * self.w;
+            self.focal_plane = Some(Matrix3::from_axis_angle(self.v, Deg(swing)) * normal);
+        }
+    }
+
+    impl Camera for ThinLensCamera {
+        fn exposure(&self) -> Float {
+            self.exposure
+        }
+
+        fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+            let (x, y) = self.aperture.sample();
+            if self.cats_eye > 0.0 {
+                // The barrel is a second circle, moved off the aperture
+                // towards the corners of the frame.
+                let h = self.horizontal.magnitude();
+                let v = self.vertical.magnitude();
+                let scale = 2.0 * self.cats_eye / (h * h + v * v).sqrt();
+                let (dx, dy) = (x - (s - 0.5) * h * scale, y - (t - 0.5) * v * scale);
+                if dx * dx + dy * dy > 1.0 {
+                    return None;
+                }
+            }
+            let offset = self.u * (self.lens_radius * x) + self.v * (self.lens_radius * y);
+            let mut target = self.lower_left_corner + s * self.horizontal + t * self.vertical;
+            if let Some(normal) = self.focal_plane {
+                let center = self.lower_left_corner + 0.5 * self.horizontal + 0.5 * self.vertical;
+                let chief = target - self.origin;
+                let k = (center - self.origin).dot(normal) / chief.dot(normal);
+                if k <= 0.0 || !k.is_finite() {
+                    // The pixel looks away from the plane, so it focuses
+                    // at infinity.
+                    return Some(Ray {
+                        a: self.origin + offset,
+                        b: chief,
+                    });
+                }
+                target = self.origin + chief * k;
             }
+            Some(Ray {
+                a: self.origin + offset,
+                b: target - self.origin - offset,
+            })
         }
     }
 
-    fn random_in_unit_sphere() -> Vector3<f32> {
-        let mut point: Vector3<f32>;
+    fn random_in_unit_sphere() -> Vector3<Float> {
+        let mut point: Vector3<Float>;
         loop {
             point =
                 2.0 * Vector3 {
-                    x: rand::random::<f32>(),
-                    y: rand::random::<f32>(),
-                    z: rand::random::<f32>(),
+                    x: rng::random(),
+                    y: rng::random(),
+                    z: rng::random(),
                 } - Vector3 {
                     x: 1.0,
                     y: 1.0,
                     z: 1.0,
                 };
 
-            if point.magnitude2() >= 1.0 {
+            if point.magnitude2() < 1.0 {
                 break;
             }
         }
         point
     }
 
-    fn reflect(v: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    fn reflect(v: Vector3<Float>, n: Vector3<Float>) -> Vector3<Float> {
         v - 2.0 * v.dot(n) * n
     }
 
-    fn refract(v: Vector3<f32>, n: Vector3<f32>, ni_over_nt: f32) -> Option<Vector3<f32>> {
+    pub(crate) fn refract(
+        v: Vector3<Float>,
+        n: Vector3<Float>,
+        ni_over_nt: Float,
+    ) -> Option<Vector3<Float>> {
         let uv = v.normalize();
         let dt = uv.dot(n);
         let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
@@ -194,56 +611,105 @@ pub mod lib {
         None
     }
 
-    fn schlick(cosine: f32, ref_idx: f32) -> f32 {
+    fn schlick(cosine: Float, ref_idx: Float) -> Float {
         let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
         r0 *= r0;
-        r0 + (1.0 - r0) * f32::powf(1.0 - cosine, 5.0)
+        r0 + (1.0 - r0) * Float::powf(1.0 - cosine, 5.0)
     }
 
     pub trait Material {
-        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<f32>, Ray)>;
+        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)>;
+
+        fn scatter_spectral(
+            &self,
+            ray: &Ray,
+            record: &HitRecord,
+            wavelengths: &mut SampledWavelengths,
+        ) -> Option<(SampledSpectrum, Ray)> {
+            self.scatter(ray, record).map(|(attenuation, scattered)| {
+                (rgb_to_spectrum(attenuation, wavelengths), scattered)
+            })
+        }
+
+        fn albedo(&self, _record: &HitRecord) -> Vector3<Float> {
+            Vector3 {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            }
+        }
+
+        // Tells materials apart, with every handle to a shared one agreeing.
+        fn identity(&self) -> usize {
+            self as *const Self as *const u8 as usize
+        }
+    }
+
+    // Lets several objects share one material through `Box::new(rc.clone())`.
+    impl<M: Material + ?Sized> Material for Rc<M> {
+        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
+            (**self).scatter(ray, record)
+        }
+
+        fn scatter_spectral(
+            &self,
+            ray: &Ray,
+            record: &HitRecord,
+            wavelengths: &mut SampledWavelengths,
+        ) -> Option<(SampledSpectrum, Ray)> {
+            (**self).scatter_spectral(ray, record, wavelengths)
+        }
+
+        fn albedo(&self, record: &HitRecord) -> Vector3<Float> {
+            (**self).albedo(record)
+        }
+
+        fn identity(&self) -> usize {
+            (**self).identity()
+        }
     }
 
     pub struct Lambertian {
-        pub albedo: Vector3<f32>,
+        pub albedo: Vector3<Float>,
     }
 
     impl Material for Lambertian {
-        fn scatter(&self, _ray: &Ray, record: &HitRecord) -> Option<(Vector3<f32>, Ray)> {
+        fn scatter(&self, _ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
             // let mut rng = thread_rng();
-            let target = record.p + record.normal + random_in_unit_sphere();
-            let scattered = Ray {
-                a: record.p,
-                b: target - record.p,
-            };
+            let scattered = record.spawn_ray(record.normal + random_in_unit_sphere());
             if scattered.direction().dot(record.normal) > 0.0 {
                 return Some((self.albedo, scattered));
             }
             None
         }
+
+        fn albedo(&self, _record: &HitRecord) -> Vector3<Float> {
+            self.albedo
+        }
     }
 
     pub struct Metal {
-        pub fuzz: f32,
-        pub albedo: Vector3<f32>,
+        pub fuzz: Float,
+        pub albedo: Vector3<Float>,
     }
 
     impl Material for Metal {
-        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<f32>, Ray)> {
+        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
             let reflected = reflect(ray.direction().normalize(), record.normal);
-            let scattered = Ray {
-                a: record.p,
-                b: reflected + self.fuzz * random_in_unit_sphere(),
-            };
+            let scattered = record.spawn_ray(reflected + self.fuzz * random_in_unit_sphere());
             if scattered.direction().dot(record.normal) > 0.0 {
                 return Some((self.albedo, scattered));
             }
             None
         }
+
+        fn albedo(&self, _record: &HitRecord) -> Vector3<Float> {
+            self.albedo
+        }
     }
 
     pub struct Dielectric {
-        pub ref_idx: f32,
+        pub ref_idx: Float,
     }
 
     impl PartialEq for Ray {
@@ -252,48 +718,130 @@ pub mod lib {
         }
     }
 
-    impl Material for Dielectric {
-        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<f32>, Ray)> {
-            let ni_over_nt: f32;
-            let outward_normal: Vector3<f32>;
-            let reflected = reflect(ray.direction(), record.normal);
-            let cosine: f32;
-            let angle = ray.direction().dot(record.normal);
-            if angle > 0.0 {
-                outward_normal = -record.normal;
-                ni_over_nt = self.ref_idx;
-                cosine = self.ref_idx * angle / ray.direction().magnitude2();
-            } else {
-                outward_normal = record.normal;
-                ni_over_nt = 1.0 / self.ref_idx;
-                cosine = -angle / ray.direction().magnitude2();
-            }
+    fn dielectric_scatter(ray: &Ray, record: &HitRecord, ref_idx: Float) -> Ray {
+        let ni_over_nt: Float;
+        let outward_normal: Vector3<Float>;
+        let reflected = reflect(ray.direction(), record.normal);
+        let cosine: Float;
+        let angle = ray.direction().dot(record.normal);
+        if angle > 0.0 {
+            outward_normal = -record.normal;
+            ni_over_nt = ref_idx;
+            cosine = ref_idx * angle / ray.direction().magnitude2();
+        } else {
+            outward_normal = record.normal;
+            ni_over_nt = 1.0 / ref_idx;
+            cosine = -angle / ray.direction().magnitude2();
+        }
 
-            let mut scattered: Option<Ray> = None::<Ray>;
-            if let Some(refracted) = refract(ray.direction(), outward_normal, ni_over_nt) {
-                if rand::random::<f32>() < schlick(cosine, self.ref_idx) {
-                    scattered = Some(Ray {
-                        a: record.p,
-                        b: refracted,
-                    });
-                }
+        let mut scattered: Option<Ray> = None::<Ray>;
+        if let Some(refracted) = refract(ray.direction(), outward_normal, ni_over_nt) {
+            if rng::random() < schlick(cosine, ref_idx) {
+                scattered = Some(record.spawn_ray(refracted));
             }
+        }
 
-            if scattered == None::<Ray> {
-                scattered = Some(Ray {
-                    a: record.p,
-                    b: reflected,
-                });
-            }
+        if scattered.is_none() {
+            scattered = Some(record.spawn_ray(reflected));
+        }
+
+        scattered.unwrap()
+    }
 
+    impl Material for Dielectric {
+        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
             Some((
                 Vector3 {
                     x: 1.0,
                     y: 1.0,
                     z: 1.0,
                 },
-                scattered.unwrap(),
+                dielectric_scatter(ray, record, self.ref_idx),
             ))
         }
     }
+
+    pub struct DispersiveDielectric {
+        pub ior: RefractiveIndex,
+    }
+
+    impl Material for DispersiveDielectric {
+        fn scatter(&self, ray: &Ray, record: &HitRecord) -> Option<(Vector3<Float>, Ray)> {
+            Some((
+                Vector3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+                dielectric_scatter(ray, record, self.ior.at(550.0)),
+            ))
+        }
+
+        fn scatter_spectral(
+            &self,
+            ray: &Ray,
+            record: &HitRecord,
+            wavelengths: &mut SampledWavelengths,
+        ) -> Option<(SampledSpectrum, Ray)> {
+            if self.ior.is_dispersive() {
+                wavelengths.terminate_secondary();
+            }
+            let ref_idx = self.ior.at(wavelengths.lambda[0]);
+            Some((
+                SampledSpectrum::constant(1.0),
+                dielectric_scatter(ray, record, ref_idx),
+            ))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn samplers_stay_inside_the_unit_ball() {
+            for _ in 0..1000 {
+                assert!(random_in_unit_sphere().magnitude2() < 1.0);
+                let p = random_in_unit_disk();
+                assert!(p.magnitude2() < 1.0 && p.z == 0.0);
+            }
+        }
+
+        fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+            Vector3 { x, y, z }
+        }
+
+        // Every ray through a pixel meets the others where the plane of focus
+        // crosses it. Tilted 30 degrees about the horizontal axis, the plane
+        // goes through the focus point with the normal w cos 30° + v sin 30°.
+        #[test]
+        fn tilted_focal_planes_pass_through_the_focus_point() {
+            let mut camera = ThinLensCamera::new(
+                point(0.0, 0.0, 0.0),
+                point(0.0, 0.0, -1.0),
+                point(0.0, 1.0, 0.0),
+                40.0,
+                1.5,
+                0.5,
+                4.0,
+            );
+            camera.tilt(30.0, 0.0);
+            let angle = (30.0 as Float).to_radians();
+            let normal = point(0.0, angle.sin(), angle.cos());
+            let focus = point(0.0, 0.0, -4.0);
+            let mut depths = Vec::new();
+            for &(s, t) in [(0.5, 0.5), (0.5, 0.1), (0.5, 0.9), (0.2, 0.7)].iter() {
+                let converge = camera.get_ray(s, t).unwrap().point_at_parameter(1.0);
+                for _ in 0..8 {
+                    let ray = camera.get_ray(s, t).unwrap();
+                    assert!((ray.point_at_parameter(1.0) - converge).magnitude() < 1e-4);
+                }
+                assert!((converge - focus).dot(normal).abs() < 1e-4);
+                depths.push(-converge.z);
+            }
+            assert!((depths[0] - 4.0).abs() < 1e-4);
+            // The near part of the plane is down, the far part up.
+            assert!(depths[1] < 4.0 && depths[2] > 4.0);
+        }
+    }
 }
@@ -0,0 +1,419 @@
+use crate::bvh::Bvh;
+use crate::lib::{abs, gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3};
+use std::collections::HashMap;
+
+// Polygon mesh with faces as lists of vertex indices, counter-clockwise when
+// seen from outside.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub positions: Vec<Vector3<Float>>,
+    pub faces: Vec<Vec<usize>>,
+}
+
+fn zero() -> Vector3<Float> {
+    Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    }
+}
+
+fn average(points: &[Vector3<Float>]) -> Vector3<Float> {
+    points.iter().fold(zero(), |sum, &p| sum + p) / points.len() as Float
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vector3<Float>>, faces: Vec<Vec<usize>>) -> Mesh {
+        Mesh { positions, faces }
+    }
+
+    // One step of Catmull-Clark subdivision. Every face becomes one quad per
+    // corner. Open boundaries use the cubic B-spline curve rules, so a flat
+    // sheet stays flat and its border stays smooth.
+    pub fn subdivide(&self) -> Mesh {
+        let face_points: Vec<Vector3<Float>> = self
+            .faces
+            .iter()
+            .map(|face| average(&face.iter().map(|&i| self.positions[i]).collect::<Vec<_>>()))
+            .collect();
+
+        let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut edge_faces: Vec<Vec<usize>> = Vec::new();
+        let mut edge_list: Vec<(usize, usize)> = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            for (i, &a) in face.iter().enumerate() {
+                let key = edge_key(a, face[(i + 1) % face.len()]);
+                let edge = *edges.entry(key).or_insert_with(|| {
+                    edge_list.push(key);
+                    edge_faces.push(Vec::new());
+                    edge_list.len() - 1
+                });
+                edge_faces[edge].push(f);
+            }
+        }
+
+        let edge_points: Vec<Vector3<Float>> = edge_list
+            .iter()
+            .zip(edge_faces.iter())
+            .map(|(&(a, b), faces)| {
+                let (a, b) = (self.positions[a], self.positions[b]);
+                if faces.len() == 2 {
+                    (a + b + face_points[faces[0]] + face_points[faces[1]]) / 4.0
+                } else {
+                    (a + b) / 2.0
+                }
+            })
+            .collect();
+
+        let mut vertex_faces = vec![Vec::new(); self.positions.len()];
+        for (f, face) in self.faces.iter().enumerate() {
+            for &i in face.iter() {
+                vertex_faces[i].push(f);
+            }
+        }
+        let mut vertex_edges = vec![Vec::new(); self.positions.len()];
+        for (e, &(a, b)) in edge_list.iter().enumerate() {
+            vertex_edges[a].push(e);
+            vertex_edges[b].push(e);
+        }
+
+        let vertex_points = self.positions.iter().enumerate().map(|(i, &p)| {
+            let boundary: Vec<usize> = vertex_edges[i]
+                .iter()
+                .filter(|&&e| edge_faces[e].len() != 2)
+                .map(|&e| {
+                    if edge_list[e].0 == i {
+                        edge_list[e].1
+                    } else {
+                        edge_list[e].0
+                    }
+                })
+                .collect();
+            let n = vertex_edges[i].len() as Float;
+            match boundary.len() {
+                0 if n >= 3.0 => {
+                    let f = average(
+                        &vertex_faces[i]
+                            .iter()
+                            .map(|&f| face_points[f])
+                            .collect::<Vec<_>>(),
+                    );
+                    let r = average(
+                        &vertex_edges[i]
+                            .iter()
+                            .map(|&e| {
+                                (self.positions[edge_list[e].0] + self.positions[edge_list[e].1])
+                                    / 2.0
+                            })
+                            .collect::<Vec<_>>(),
+                    );
+                    (f + r * 2.0 + p * (n - 3.0)) / n
+                }
+                2 => p * 0.75 + (self.positions[boundary[0]] + self.positions[boundary[1]]) * 0.125,
+                // Corners and non-manifold vertices stay where they are.
+                _ => p,
+            }
+        });
+
+        let edge_offset = self.positions.len();
+        let face_offset = edge_offset + edge_list.len();
+        let mut positions: Vec<Vector3<Float>> = vertex_points.collect();
+        positions.extend(edge_points);
+        positions.extend(face_points);
+
+        let mut faces = Vec::new();
+        for (f, face) in self.faces.iter().enumerate() {
+            let n = face.len();
+            for i in 0..n {
+                let previous = face[(i + n - 1) % n];
+                let next = face[(i + 1) % n];
+                faces.push(vec![
+                    face[i],
+                    edge_offset + edges[&edge_key(face[i], next)],
+                    face_offset + f,
+                    edge_offset + edges[&edge_key(previous, face[i])],
+                ]);
+            }
+        }
+        Mesh { positions, faces }
+    }
+
+    pub fn catmull_clark(&self, levels: usize) -> Mesh {
+        (0..levels).fold(self.clone(), |mesh, _| mesh.subdivide())
+    }
+
+    // Fans every polygon into triangles, with vertex normals averaged from
+    // the faces around each vertex for smooth shading.
+    pub fn triangulate(&self, material: Box<dyn Material>) -> TriangleMesh {
+        let mut triangles = Vec::new();
+        for face in self.faces.iter() {
+            for i in 1..face.len().saturating_sub(1) {
+                triangles.push([face[0], face[i], face[i + 1]]);
+            }
+        }
+        TriangleMesh::new(self.positions.clone(), triangles, material)
+    }
+}
+
+// Möller-Trumbore, returning the distance and the barycentrics of p1 and p2.
+pub fn intersect_triangle(
+    ray: &Ray,
+    p0: Vector3<Float>,
+    p1: Vector3<Float>,
+    p2: Vector3<Float>,
+) -> Option<(Float, Float, Float)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let pvec = ray.direction().cross(e2);
+    let det = e1.dot(pvec);
+    if det == 0.0 {
+        return None;
+    }
+    let tvec = ray.origin() - p0;
+    let u = tvec.dot(pvec) / det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let qvec = tvec.cross(e1);
+    let v = ray.direction().dot(qvec) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    Some((e2.dot(qvec) / det, u, v))
+}
+
+// Flips a geometric normal onto the side of the shading normal.
+fn same_side(geometric: Vector3<Float>, shading: Vector3<Float>) -> Vector3<Float> {
+    if geometric.dot(shading) < 0.0 {
+        -geometric
+    } else {
+        geometric
+    }
+}
+
+// The point with barycentrics u and v of p1 and p2, and its error bound.
+// Interpolating the vertices is more accurate than following the ray.
+pub fn barycentric_point(
+    p0: Vector3<Float>,
+    p1: Vector3<Float>,
+    p2: Vector3<Float>,
+    u: Float,
+    v: Float,
+) -> (Vector3<Float>, Vector3<Float>) {
+    let w = 1.0 - u - v;
+    let p = p0 * w + p1 * u + p2 * v;
+    let error = (abs(p0 * w) + abs(p1 * u) + abs(p2 * v)) * gamma(7);
+    (p, error)
+}
+
+pub struct TriangleMesh {
+    pub positions: Vec<Vector3<Float>>,
+    pub normals: Vec<Vector3<Float>>,
+    pub triangles: Vec<[usize; 3]>,
+    pub material: Box<dyn Material>,
+    bvh: Bvh,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vector3<Float>>,
+        triangles: Vec<[usize; 3]>,
+        material: Box<dyn Material>,
+    ) -> TriangleMesh {
+        // Unnormalized cross products weight each face by its area.
+        let mut normals = vec![zero(); positions.len()];
+        for t in triangles.iter() {
+            let normal =
+                (positions[t[1]] - positions[t[0]]).cross(positions[t[2]] - positions[t[0]]);
+            for &i in t.iter() {
+                normals[i] += normal;
+            }
+        }
+        for normal in normals.iter_mut() {
+            if normal.magnitude2() > 0.0 {
+                *normal = normal.normalize();
+            }
+        }
+
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|t| Aabb::from_points(&[positions[t[0]], positions[t[1]], positions[t[2]]]))
+            .collect();
+        TriangleMesh {
+            bvh: Bvh::new(&bounds),
+            positions,
+            normals,
+            triangles,
+            material,
+        }
+    }
+
+    fn intersect(&self, triangle: usize, ray: &Ray) -> Option<(Float, Float, Float)> {
+        let [a, b, c] = self.triangles[triangle];
+        intersect_triangle(ray, self.positions[a], self.positions[b], self.positions[c])
+    }
+}
+
+impl Hitable for TriangleMesh {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hit = None;
+        self.bvh.traverse(ray, t_min, t_max, |triangle, t_max| {
+            let (t, u, v) = self.intersect(triangle, ray)?;
+            if t > t_min && t < t_max {
+                hit = Some((triangle, t, u, v));
+                Some(t)
+            } else {
+                None
+            }
+        });
+        let (triangle, t, u, v) = hit?;
+        let [a, b, c] = self.triangles[triangle];
+        let geometric_normal = (self.positions[b] - self.positions[a])
+            .cross(self.positions[c] - self.positions[a])
+            .normalize();
+        let mut normal =
+            self.normals[a] * (1.0 - u - v) + self.normals[b] * u + self.normals[c] * v;
+        if normal.magnitude2() == 0.0 {
+            normal = geometric_normal;
+        }
+        let normal = normal.normalize();
+        let (p, error) = barycentric_point(
+            self.positions[a],
+            self.positions[b],
+            self.positions[c],
+            u,
+            v,
+        );
+        Some(HitRecord {
+            t,
+            p,
+            normal,
+            geometric_normal: same_side(geometric_normal, normal),
+            tangent: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            error,
+            u,
+            v,
+            material: &*self.material,
+            object_id: 0,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // A ridge along z whose smoothed normals point straight up at the top.
+    fn tent() -> TriangleMesh {
+        TriangleMesh::new(
+            vec![
+                point(-1.0, 0.0, -1.0),
+                point(-1.0, 0.0, 1.0),
+                point(0.0, 1.0, -1.0),
+                point(0.0, 1.0, 1.0),
+                point(1.0, 0.0, -1.0),
+                point(1.0, 0.0, 1.0),
+            ],
+            vec![[0, 3, 2], [0, 1, 3], [4, 2, 3], [4, 3, 5]],
+            Box::new(Lambertian {
+                albedo: point(0.5, 0.5, 0.5),
+            }),
+        )
+    }
+
+    // Near the ridge the shading normal is close to vertical while the face
+    // slopes at 45 degrees, so a ray can leave above the face while heading
+    // below the shading normal.
+    #[test]
+    fn rays_leave_along_the_geometric_normal() {
+        let mesh = tent();
+        let ray = Ray::new(point(-0.05, 5.0, 0.1), point(0.0, -1.0, 0.0));
+        let record = mesh.hit(&ray, 0.0, Float::MAX).unwrap();
+        let face = point(-1.0, 1.0, 0.0).normalize();
+        assert!((record.geometric_normal - face).magnitude() < 1e-6);
+        assert!(record.normal.dot(face) < 0.8);
+
+        let direction = point(-1.0, -0.2, 0.0);
+        assert!(direction.dot(record.normal) < 0.0);
+        assert!(direction.dot(face) > 0.0);
+        assert!(mesh
+            .hit(&record.spawn_ray(direction), 0.0, Float::MAX)
+            .is_none());
+        // And the other way, a ray into the face has to reach the inside.
+        let direction = point(1.0, 0.2, 0.0);
+        let spawned = record.spawn_ray(direction);
+        assert!((spawned.origin() - point(-1.0, 0.0, 0.0)).dot(face) < 0.0);
+    }
+
+    // One step on a cube with corners at ±1, checked against the values
+    // worked out by hand from the Catmull-Clark rules.
+    #[test]
+    fn cubes_subdivide_to_the_known_points() {
+        let positions = (0..8)
+            .map(|i| {
+                let sign = |bit: usize| if i & bit != 0 { 1.0 } else { -1.0 };
+                point(sign(1), sign(2), sign(4))
+            })
+            .collect();
+        let faces = vec![
+            vec![0, 2, 6, 4],
+            vec![1, 5, 7, 3],
+            vec![0, 4, 5, 1],
+            vec![2, 3, 7, 6],
+            vec![0, 1, 3, 2],
+            vec![4, 6, 7, 5],
+        ];
+        let cube = Mesh { positions, faces };
+        let smooth = cube.subdivide();
+        assert_eq!(smooth.positions.len(), 8 + 12 + 6);
+        assert_eq!(smooth.faces.len(), 24);
+
+        // Corners move to (F + 2R) / 3, with F the average of the three
+        // face centers and R of the three edge midpoints: 5/9 of the way.
+        for (old, new) in cube.positions.iter().zip(smooth.positions.iter()) {
+            assert!((new - old * (5.0 / 9.0)).magnitude() < 1e-6);
+        }
+        // Edge points average the ends and the two face centers, as
+        // (1, 1, 0) becomes (3/4, 3/4, 0).
+        for p in smooth.positions[8..20].iter() {
+            let mut coordinates = [p.x.abs(), p.y.abs(), p.z.abs()];
+            coordinates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert!(coordinates[0] < 1e-6);
+            assert!((coordinates[1] - 0.75).abs() < 1e-6);
+            assert!((coordinates[2] - 0.75).abs() < 1e-6);
+        }
+        // Face points are the face centers.
+        for p in smooth.positions[20..].iter() {
+            assert!((p.magnitude() - 1.0).abs() < 1e-6);
+            assert!((p.x.abs() + p.y.abs() + p.z.abs() - 1.0).abs() < 1e-6);
+        }
+        // Every new face is a quad with one old corner, two edge points
+        // and a face point.
+        for face in smooth.faces.iter() {
+            assert_eq!(face.len(), 4);
+            assert!(face[0] < 8 && face[2] >= 20);
+            assert!((8..20).contains(&face[1]) && (8..20).contains(&face[3]));
+        }
+    }
+}
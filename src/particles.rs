@@ -0,0 +1,602 @@
+use crate::bvh::Bvh;
+use crate::lib::{
+    abs, gamma, sphere_uv, to_f64, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray,
+    Vector3,
+};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read};
+
+// Many spheres stored as flat arrays, for particle simulations and point
+// clouds. Materials are shared: every particle uses the first one unless
+// `material_indices` picks one per particle.
+pub struct Particles {
+    pub positions: Vec<Vector3<Float>>,
+    pub radii: Vec<Float>,
+    pub materials: Vec<Box<dyn Material>>,
+    pub material_indices: Vec<u32>,
+    bvh: Bvh,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// What a loader found, before any materials are attached.
+struct Columns {
+    positions: Vec<Vector3<Float>>,
+    radii: Vec<Float>,
+    material_indices: Vec<u32>,
+}
+
+fn is_radius(name: &str) -> bool {
+    name == "radius" || name == "scale" || name == "pscale"
+}
+
+fn is_material(name: &str) -> bool {
+    name == "material" || name == "material_index"
+}
+
+// Material indices come from the file as numbers of any type, so anything
+// negative, fractional or past the last material is rejected rather than
+// converted.
+fn material_index(value: f64, materials: usize) -> Option<u32> {
+    if value.fract() != 0.0 || value < 0.0 || value >= materials as f64 {
+        return None;
+    }
+    u32::try_from(value as u64).ok()
+}
+
+// Headers can claim any number of rows, so only this many are reserved up
+// front and the rest grow as they are read.
+const MAX_RESERVE: usize = 1 << 16;
+
+impl Particles {
+    pub fn new(
+        positions: Vec<Vector3<Float>>,
+        radii: Vec<Float>,
+        material: Box<dyn Material>,
+    ) -> io::Result<Particles> {
+        Particles::with_materials(positions, radii, vec![material], Vec::new())
+    }
+
+    // Fails with InvalidInput unless there is a radius for every position,
+    // and either no material indices or a valid one for every position.
+    pub fn with_materials(
+        positions: Vec<Vector3<Float>>,
+        radii: Vec<Float>,
+        materials: Vec<Box<dyn Material>>,
+        material_indices: Vec<u32>,
+    ) -> io::Result<Particles> {
+        let mismatch = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if materials.is_empty() {
+            return mismatch("particles need a material".to_string());
+        }
+        if radii.len() != positions.len() {
+            return mismatch(format!(
+                "{} radii for {} particles",
+                radii.len(),
+                positions.len()
+            ));
+        }
+        if !material_indices.is_empty() && material_indices.len() != positions.len() {
+            return mismatch(format!(
+                "{} material indices for {} particles",
+                material_indices.len(),
+                positions.len()
+            ));
+        }
+        if let Some(&index) = material_indices
+            .iter()
+            .find(|&&index| index as usize >= materials.len())
+        {
+            return mismatch(format!("no material {}", index));
+        }
+        let bounds: Vec<Aabb> = positions
+            .iter()
+            .zip(radii.iter())
+            .map(|(&center, &radius)| {
+                let extent = Vector3 {
+                    x: radius.abs(),
+                    y: radius.abs(),
+                    z: radius.abs(),
+                };
+                Aabb::new(center - extent, center + extent)
+            })
+            .collect();
+        Ok(Particles {
+            bvh: Bvh::new(&bounds),
+            positions,
+            radii,
+            materials,
+            material_indices,
+        })
+    }
+
+    // Reads the vertex element of an ASCII or binary PLY file. Besides x, y
+    // and z it uses a `radius`, `scale` or `pscale` property when there is
+    // one, and a `material` or `material_index` property to index
+    // `materials`.
+    pub fn from_ply<R: BufRead>(
+        input: &mut R,
+        default_radius: Float,
+        materials: Vec<Box<dyn Material>>,
+    ) -> io::Result<Particles> {
+        let columns = read_ply(input, default_radius, materials.len())?;
+        Particles::from_columns(columns, materials)
+    }
+
+    // Reads comma separated x, y, z and optionally radius and material
+    // index. With a header line the columns can come in any order, named as
+    // for PLY files.
+    pub fn from_csv<R: BufRead>(
+        input: &mut R,
+        default_radius: Float,
+        materials: Vec<Box<dyn Material>>,
+    ) -> io::Result<Particles> {
+        let columns = read_csv(input, default_radius, materials.len())?;
+        Particles::from_columns(columns, materials)
+    }
+
+    fn from_columns(columns: Columns, materials: Vec<Box<dyn Material>>) -> io::Result<Particles> {
+        Particles::with_materials(
+            columns.positions,
+            columns.radii,
+            materials,
+            columns.material_indices,
+        )
+    }
+
+    fn material(&self, particle: usize) -> &dyn Material {
+        match self.material_indices.get(particle) {
+            Some(&index) => &*self.materials[index as usize],
+            None => &*self.materials[0],
+        }
+    }
+}
+
+impl Hitable for Particles {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hit = None;
+        let a = ray.direction().magnitude2();
+        self.bvh.traverse(ray, t_min, t_max, |particle, t_max| {
+            let oc = ray.origin() - self.positions[particle];
+            let b = oc.dot(ray.direction());
+            let radius = self.radii[particle];
+            let discriminant = b * b - a * (oc.magnitude2() - radius * radius);
+            if discriminant <= 0.0 {
+                return None;
+            }
+            let root = discriminant.sqrt();
+            [(-b - root) / a, (-b + root) / a]
+                .iter()
+                .cloned()
+                .find(|&t| t > t_min && t < t_max)
+                .map(|t| {
+                    hit = Some((particle, t));
+                    t
+                })
+        });
+
+        let (particle, t) = hit?;
+        // Projected back onto the sphere, as for `Sphere`.
+        let center = self.positions[particle];
+        let radius = self.radii[particle];
+        let offset = ray.point_at_parameter(t) - center;
+        let offset = offset * (radius.abs() / offset.magnitude());
+        let normal = offset / radius;
+        let (u, v) = sphere_uv(normal);
+        Some(HitRecord {
+            t,
+            p: center + offset,
+            normal,
+            geometric_normal: normal,
+            tangent: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            error: abs(offset) * gamma(5) + abs(center) * gamma(1),
+            u,
+            v,
+            material: self.material(particle),
+            object_id: 0,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.extend(self.materials.iter().map(|material| &**material));
+    }
+}
+
+fn read_csv<R: BufRead>(
+    input: &mut R,
+    default_radius: Float,
+    materials: usize,
+) -> io::Result<Columns> {
+    let mut columns = Columns {
+        positions: Vec::new(),
+        radii: Vec::new(),
+        material_indices: Vec::new(),
+    };
+    // Column of x, y, z, radius and material.
+    let mut layout = [Some(0), Some(1), Some(2), Some(3), Some(4)];
+    let mut has_materials = None;
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if number == 0 && fields[0].parse::<Float>().is_err() {
+            let find = |test: &dyn Fn(&str) -> bool| {
+                fields.iter().position(|name| test(&name.to_lowercase()))
+            };
+            layout = [
+                find(&|name| name == "x"),
+                find(&|name| name == "y"),
+                find(&|name| name == "z"),
+                find(&is_radius),
+                find(&is_material),
+            ];
+            if layout[..3].iter().any(|column| column.is_none()) {
+                return Err(invalid("CSV header lacks x, y or z".to_string()));
+            }
+            continue;
+        }
+
+        let value = |column: Option<usize>| -> io::Result<Option<Float>> {
+            match column.and_then(|column| fields.get(column)) {
+                Some(field) => field
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| invalid(format!("line {}: bad number {}", number + 1, field))),
+                None => Ok(None),
+            }
+        };
+        let coordinate = |column| {
+            value(column)?
+                .ok_or_else(|| invalid(format!("line {}: missing coordinate", number + 1)))
+        };
+        columns.positions.push(Vector3 {
+            x: coordinate(layout[0])?,
+            y: coordinate(layout[1])?,
+            z: coordinate(layout[2])?,
+        });
+        columns
+            .radii
+            .push(value(layout[3])?.unwrap_or(default_radius));
+        let material = value(layout[4])?;
+        // Material indices are all or nothing, decided by the first row.
+        if *has_materials.get_or_insert(material.is_some()) {
+            let material = material
+                .ok_or_else(|| invalid(format!("line {}: missing material", number + 1)))?;
+            let index = material_index(to_f64(material), materials)
+                .ok_or_else(|| invalid(format!("line {}: no material {}", number + 1, material)))?;
+            columns.material_indices.push(index);
+        }
+    }
+    Ok(columns)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+struct Property {
+    name: String,
+    // Size in bytes, or None for lists.
+    size: Option<usize>,
+    kind: String,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn ply_size(kind: &str) -> Option<usize> {
+    match kind {
+        "char" | "uchar" | "int8" | "uint8" => Some(1),
+        "short" | "ushort" | "int16" | "uint16" => Some(2),
+        "int" | "uint" | "int32" | "uint32" | "float" | "float32" => Some(4),
+        "double" | "float64" => Some(8),
+        _ => None,
+    }
+}
+
+fn ply_value(kind: &str, bytes: &[u8], format: Format) -> f64 {
+    let mut buffer = [0; 8];
+    let size = bytes.len();
+    buffer[..size].copy_from_slice(bytes);
+    if format == Format::BigEndian {
+        buffer[..size].reverse();
+    }
+    match kind {
+        "char" | "int8" => buffer[0] as i8 as f64,
+        "uchar" | "uint8" => buffer[0] as f64,
+        "short" | "int16" => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+        "ushort" | "uint16" => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+        "int" | "int32" => i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+        "uint" | "uint32" => {
+            u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64
+        }
+        "float" | "float32" => {
+            f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64
+        }
+        _ => f64::from_le_bytes(buffer),
+    }
+}
+
+fn read_ply<R: BufRead>(
+    input: &mut R,
+    default_radius: Float,
+    materials: usize,
+) -> io::Result<Columns> {
+    let mut line = String::new();
+    let mut next_line = |input: &mut R| -> io::Result<String> {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated PLY header".to_string()));
+        }
+        Ok(line.trim().to_string())
+    };
+
+    if next_line(input)? != "ply" {
+        return Err(invalid("not a PLY file".to_string()));
+    }
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    loop {
+        let line = next_line(input)?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["end_header"] => break,
+            ["format", name, _] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => return Err(invalid(format!("unknown PLY format {}", name))),
+                })
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid(format!("bad element count {}", count)))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", _, _, name] => match elements.last_mut() {
+                Some(element) => element.properties.push(Property {
+                    name: name.to_string(),
+                    size: None,
+                    kind: String::new(),
+                }),
+                None => return Err(invalid("property before element".to_string())),
+            },
+            ["property", kind, name] => {
+                let size =
+                    ply_size(kind).ok_or_else(|| invalid(format!("unknown PLY type {}", kind)))?;
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(Property {
+                        name: name.to_string(),
+                        size: Some(size),
+                        kind: kind.to_string(),
+                    }),
+                    None => return Err(invalid("property before element".to_string())),
+                }
+            }
+            _ => {}
+        }
+    }
+    let format = format.ok_or_else(|| invalid("PLY header lacks a format".to_string()))?;
+
+    // Elements before the vertices have to be skipped, which in binary files
+    // needs them to have a fixed size.
+    let vertex = elements
+        .iter()
+        .position(|element| element.name == "vertex")
+        .ok_or_else(|| invalid("PLY file has no vertex element".to_string()))?;
+    for element in elements[..vertex].iter() {
+        if format == Format::Ascii {
+            for _ in 0..element.count {
+                next_line(input)?;
+            }
+        } else {
+            let size: Option<usize> = element.properties.iter().map(|p| p.size).sum();
+            let size = size.ok_or_else(|| {
+                invalid(format!("can't skip list properties of {}", element.name))
+            })?;
+            let length = size
+                .checked_mul(element.count)
+                .ok_or_else(|| invalid(format!("bad element count {}", element.count)))?;
+            let skipped = io::copy(&mut input.by_ref().take(length as u64), &mut io::sink())?;
+            if skipped < length as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated PLY data",
+                ));
+            }
+        }
+    }
+
+    let element = &elements[vertex];
+    let find = |test: &dyn Fn(&str) -> bool| element.properties.iter().position(|p| test(&p.name));
+    let (x, y, z) = match (
+        find(&|name| name == "x"),
+        find(&|name| name == "y"),
+        find(&|name| name == "z"),
+    ) {
+        (Some(x), Some(y), Some(z)) => (x, y, z),
+        _ => return Err(invalid("PLY vertices lack x, y or z".to_string())),
+    };
+    let radius = find(&is_radius);
+    let material = find(&is_material);
+    if element.properties.iter().any(|p| p.size.is_none()) {
+        return Err(invalid("list properties on vertices".to_string()));
+    }
+
+    let mut columns = Columns {
+        positions: Vec::with_capacity(element.count.min(MAX_RESERVE)),
+        radii: Vec::with_capacity(element.count.min(MAX_RESERVE)),
+        material_indices: Vec::new(),
+    };
+    let mut values = vec![0.0; element.properties.len()];
+    let mut bytes = [0; 8];
+    for index in 0..element.count {
+        if format == Format::Ascii {
+            let line = next_line(input)?;
+            let mut words = line.split_whitespace();
+            for value in values.iter_mut() {
+                *value = words
+                    .next()
+                    .and_then(|word| word.parse().ok())
+                    .ok_or_else(|| invalid(format!("bad vertex line {}", line)))?;
+            }
+        } else {
+            for (value, property) in values.iter_mut().zip(element.properties.iter()) {
+                let size = property.size.unwrap();
+                input.read_exact(&mut bytes[..size])?;
+                *value = ply_value(&property.kind, &bytes[..size], format);
+            }
+        }
+        columns.positions.push(Vector3 {
+            x: values[x] as Float,
+            y: values[y] as Float,
+            z: values[z] as Float,
+        });
+        columns
+            .radii
+            .push(radius.map_or(default_radius, |radius| values[radius] as Float));
+        if let Some(material) = material {
+            let value = values[material];
+            let material = material_index(value, materials)
+                .ok_or_else(|| invalid(format!("vertex {}: no material {}", index, value)))?;
+            columns.material_indices.push(material);
+        }
+    }
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn materials(count: usize) -> Vec<Box<dyn Material>> {
+        (0..count)
+            .map(|_| -> Box<dyn Material> {
+                Box::new(Lambertian {
+                    albedo: Vector3 {
+                        x: 0.5,
+                        y: 0.5,
+                        z: 0.5,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn csv(text: &str) -> io::Result<Particles> {
+        Particles::from_csv(&mut text.as_bytes(), 0.1, materials(2))
+    }
+
+    fn ply(bytes: &[u8]) -> io::Result<Particles> {
+        Particles::from_ply(&mut &bytes[..], 0.1, materials(2))
+    }
+
+    #[test]
+    fn reads_material_indices() {
+        let particles = csv("x,y,z,material\n0,0,0,1\n1,0,0,0\n").unwrap();
+        assert_eq!(particles.material_indices, vec![1, 0]);
+        let particles = ply(
+            b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\n\
+              property float y\nproperty float z\nproperty uchar material\nend_header\n\
+              0 0 0 1\n1 0 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(particles.material_indices, vec![1, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_material_indices() {
+        for index in ["-1", "2", "0.5", "4294967296", "nan"].iter() {
+            let text = format!("x,y,z,material\n0,0,0,0\n1,0,0,{}\n", index);
+            let error = csv(&text).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", index);
+            assert!(error.to_string().starts_with("line 3"), "{}", error);
+        }
+        let mut file = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\n\
+              property float x\nproperty float y\nproperty float z\nproperty int material\n\
+              end_header\n"
+            .to_vec();
+        for value in [0f32, 0.0, 0.0].iter() {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+        file.extend_from_slice(&(-3i32).to_le_bytes());
+        assert_eq!(
+            ply(&file).err().unwrap().to_string(),
+            "vertex 0: no material -3"
+        );
+    }
+
+    #[test]
+    fn huge_counts_fail_without_allocating() {
+        let error = ply(b"ply\nformat binary_little_endian 1.0\n\
+              element vertex 1000000000000000\nproperty float x\nproperty float y\n\
+              property float z\nend_header\n\x00\x00")
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let error = ply(b"ply\nformat binary_little_endian 1.0\n\
+              element face 18446744073709551615\nproperty int a\n\
+              element vertex 1\nproperty float x\nproperty float y\nproperty float z\n\
+              end_header\n")
+        .err()
+        .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mismatched_arrays_are_errors() {
+        let origin = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let cases: Vec<(usize, usize, Vec<u32>)> = vec![
+            (2, 0, Vec::new()),
+            (1, 2, vec![0]),
+            (2, 2, vec![0, 1, 0]),
+            (2, 2, vec![0, 2]),
+        ];
+        for (count, materials_len, indices) in cases {
+            let error = Particles::with_materials(
+                vec![origin; count],
+                vec![0.1; 2],
+                materials(materials_len),
+                indices,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", error);
+        }
+        let error = Particles::from_csv(&mut &b"0,0,0\n"[..], 0.1, Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(
+            Particles::with_materials(vec![origin; 2], vec![0.1; 2], materials(2), vec![1, 0])
+                .is_ok()
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use crate::bvh::Bvh;
+use crate::lib::{gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3};
+use crate::shapes::facing;
+
+type Curve = [Vector3<Float>; 4];
+
+fn bernstein(t: Float) -> [Float; 4] {
+    let s = 1.0 - t;
+    [s * s * s, 3.0 * s * s * t, 3.0 * s * t * t, t * t * t]
+}
+
+fn bernstein_derivative(t: Float) -> [Float; 4] {
+    let s = 1.0 - t;
+    [
+        -3.0 * s * s,
+        3.0 * s * s - 6.0 * s * t,
+        6.0 * s * t - 3.0 * t * t,
+        3.0 * t * t,
+    ]
+}
+
+fn lerp(a: Vector3<Float>, b: Vector3<Float>, t: Float) -> Vector3<Float> {
+    a + (b - a) * t
+}
+
+// De Casteljau split at t, returning the two halves.
+fn split(p: &Curve, t: Float) -> (Curve, Curve) {
+    let ab = lerp(p[0], p[1], t);
+    let bc = lerp(p[1], p[2], t);
+    let cd = lerp(p[2], p[3], t);
+    let abc = lerp(ab, bc, t);
+    let bcd = lerp(bc, cd, t);
+    let m = lerp(abc, bcd, t);
+    ([p[0], ab, abc, m], [m, bcd, cd, p[3]])
+}
+
+// Control points of the part of the curve between t0 and t1.
+fn segment(p: &Curve, t0: Float, t1: Float) -> Curve {
+    let (left, _) = split(p, t1);
+    if t1 > 0.0 {
+        split(&left, t0 / t1).1
+    } else {
+        left
+    }
+}
+
+// Largest coordinate of any control point, which rounding errors scale with.
+fn magnitude(control: &[[Vector3<Float>; 4]; 4]) -> Float {
+    let mut magnitude: Float = 0.0;
+    for c in control.iter().flat_map(|row| row.iter()) {
+        magnitude = magnitude.max(c.x.abs()).max(c.y.abs()).max(c.z.abs());
+    }
+    magnitude
+}
+
+// Bicubic Bezier patch, intersected directly instead of being tessellated.
+// Its parameter square is cut into cells whose control points bound them;
+// a ray is then solved for with Newton's method starting in every cell whose
+// box it crosses.
+pub struct BezierPatch {
+    pub control: [[Vector3<Float>; 4]; 4],
+    pub material: Box<dyn Material>,
+    resolution: usize,
+    bvh: Bvh,
+}
+
+impl BezierPatch {
+    // `control[i][j]` is the point at u = i/3 and v = j/3.
+    pub fn new(control: [[Vector3<Float>; 4]; 4], material: Box<dyn Material>) -> BezierPatch {
+        BezierPatch::with_resolution(control, 8, material)
+    }
+
+    // More cells cost memory but make Newton's method converge from closer,
+    // which matters for strongly curved patches and grazing rays.
+    pub fn with_resolution(
+        control: [[Vector3<Float>; 4]; 4],
+        resolution: usize,
+        material: Box<dyn Material>,
+    ) -> BezierPatch {
+        let n = resolution as Float;
+        // Splitting off a cell rounds its control points by an amount that
+        // scales with the patch, so its box is widened by as much.
+        let padding = magnitude(&control) * gamma(16);
+        let padding = Vector3 {
+            x: padding,
+            y: padding,
+            z: padding,
+        };
+        let mut bounds = Vec::with_capacity(resolution * resolution);
+        for i in 0..resolution {
+            // The patch restricted to this strip of u, one curve per v row.
+            let strip: Vec<Curve> = (0..4)
+                .map(|k| {
+                    let curve = [control[0][k], control[1][k], control[2][k], control[3][k]];
+                    segment(&curve, i as Float / n, (i + 1) as Float / n)
+                })
+                .collect();
+            for j in 0..resolution {
+                let points: Vec<Vector3<Float>> = (0..4)
+                    .flat_map(|m| {
+                        let curve = [strip[0][m], strip[1][m], strip[2][m], strip[3][m]];
+                        segment(&curve, j as Float / n, (j + 1) as Float / n).to_vec()
+                    })
+                    .collect();
+                let aabb = Aabb::from_points(&points);
+                bounds.push(Aabb::new(aabb.min - padding, aabb.max + padding));
+            }
+        }
+        BezierPatch {
+            control,
+            material,
+            resolution,
+            bvh: Bvh::new(&bounds),
+        }
+    }
+
+    // Point and partial derivatives at (u, v).
+    pub fn evaluate(&self, u: Float, v: Float) -> (Vector3<Float>, Vector3<Float>, Vector3<Float>) {
+        let (bu, du) = (bernstein(u), bernstein_derivative(u));
+        let (bv, dv) = (bernstein(v), bernstein_derivative(v));
+        let zero = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let (mut p, mut pu, mut pv) = (zero, zero, zero);
+        for i in 0..4 {
+            for j in 0..4 {
+                let c = self.control[i][j];
+                p += c * (bu[i] * bv[j]);
+                pu += c * (du[i] * bv[j]);
+                pv += c * (bu[i] * dv[j]);
+            }
+        }
+        (p, pu, pv)
+    }
+
+    // Solves for the (u, v) where the surface meets the two planes whose
+    // intersection is the ray, starting from the middle of a cell.
+    fn solve(&self, cell: usize, ray: &Ray) -> Option<(Float, Float, Float)> {
+        let n = self.resolution as Float;
+        let (i, j) = (cell / self.resolution, cell % self.resolution);
+        let direction = ray.direction();
+        let n1 =
+            if direction.x.abs() > direction.y.abs() && direction.x.abs() > direction.z.abs() {
+                Vector3 {
+                    x: direction.y,
+                    y: -direction.x,
+                    z: 0.0,
+                }
+            } else {
+                Vector3 {
+                    x: 0.0,
+                    y: direction.z,
+                    z: -direction.y,
+                }
+            }
+            .normalize();
+        let n2 = direction.cross(n1).normalize();
+        let d1 = -n1.dot(ray.origin());
+        let d2 = -n2.dot(ray.origin());
+
+        let (mut u, mut v) = ((i as Float + 0.5) / n, (j as Float + 0.5) / n);
+        for _ in 0..12 {
+            let (p, pu, pv) = self.evaluate(u, v);
+            let f1 = n1.dot(p) + d1;
+            let f2 = n2.dot(p) + d2;
+            let (a, b, c, d) = (n1.dot(pu), n1.dot(pv), n2.dot(pu), n2.dot(pv));
+            let det = a * d - b * c;
+            if det == 0.0 {
+                return None;
+            }
+            let du = (d * f1 - b * f2) / det;
+            let dv = (a * f2 - c * f1) / det;
+            u -= du;
+            v -= dv;
+            if du.abs() < 1e-6 && dv.abs() < 1e-6 {
+                // Hits that wandered out of the cell belong to its neighbour.
+                let margin = 1e-3 / n;
+                let inside = |x: Float, k: usize| {
+                    x >= k as Float / n - margin && x <= (k + 1) as Float / n + margin
+                };
+                if !inside(u, i)
+                    || !inside(v, j)
+                    || !(0.0..=1.0).contains(&u)
+                    || !(0.0..=1.0).contains(&v)
+                {
+                    return None;
+                }
+                let (p, _, _) = self.evaluate(u, v);
+                let t = (p - ray.origin()).dot(direction) / direction.magnitude2();
+                return Some((t, u, v));
+            }
+        }
+        None
+    }
+}
+
+impl Hitable for BezierPatch {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hit = None;
+        self.bvh.traverse(ray, t_min, t_max, |cell, t_max| {
+            let (t, u, v) = self.solve(cell, ray)?;
+            if t > t_min && t < t_max {
+                hit = Some((t, u, v));
+                Some(t)
+            } else {
+                None
+            }
+        });
+        let (t, u, v) = hit?;
+        let (p, pu, pv) = self.evaluate(u, v);
+        let normal = pu.cross(pv);
+        if normal.magnitude2() == 0.0 {
+            return None;
+        }
+        let normal = facing(normal.normalize(), ray.direction());
+        // Rounding in the Bernstein sums scales with the control points, and
+        // Newton's method stops within its tolerance in u and v.
+        let tolerance =
+            magnitude(&self.control) * gamma(16) + (pu.magnitude() + pv.magnitude()) * 1e-5;
+        Some(HitRecord {
+            t,
+            p,
+            // Patches are open surfaces, so like disks they are two sided.
+            normal,
+            geometric_normal: normal,
+            tangent: pu.normalize(),
+            error: Vector3 {
+                x: tolerance,
+                y: tolerance,
+                z: tolerance,
+            },
+            u,
+            v,
+            material: &*self.material,
+            object_id: 0,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounds()
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // A dome over the square from -1 to 1 in x and z. With the control
+    // points evenly spaced in x and z, u and v follow x and z linearly.
+    fn dome() -> BezierPatch {
+        let mut control = [[point(0.0, 0.0, 0.0); 4]; 4];
+        for (i, row) in control.iter_mut().enumerate() {
+            for (j, c) in row.iter_mut().enumerate() {
+                let inner = (1..3).contains(&i) && (1..3).contains(&j);
+                *c = point(
+                    i as Float * 2.0 / 3.0 - 1.0,
+                    if inner { 1.0 } else { 0.0 },
+                    j as Float * 2.0 / 3.0 - 1.0,
+                );
+            }
+        }
+        BezierPatch::new(
+            control,
+            Box::new(Lambertian {
+                albedo: point(0.5, 0.5, 0.5),
+            }),
+        )
+    }
+
+    #[test]
+    fn rays_hit_the_surface_at_their_parameters() {
+        let patch = dome();
+        // The top is 3/8 + 3/8 squared above the corners.
+        let ray = Ray::new(point(0.0, 5.0, 0.0), point(0.0, -1.0, 0.0));
+        let record = patch.hit(&ray, 0.0, Float::MAX).unwrap();
+        assert!((record.t - (5.0 - 0.5625)).abs() < 1e-4);
+        assert!((record.normal - point(0.0, 1.0, 0.0)).magnitude() < 1e-4);
+
+        for &(x, z) in [(0.3, -0.6), (-0.9, 0.2), (0.7, 0.7)].iter() {
+            let ray = Ray::new(point(x, 5.0, z), point(0.0, -1.0, 0.0));
+            let record = patch.hit(&ray, 0.0, Float::MAX).unwrap();
+            assert!((record.u - (x + 1.0) / 2.0).abs() < 1e-4);
+            assert!((record.v - (z + 1.0) / 2.0).abs() < 1e-4);
+            let (p, pu, pv) = patch.evaluate(record.u, record.v);
+            assert!((record.p - p).magnitude() <= record.error.magnitude());
+            assert!((record.p - ray.point_at_parameter(record.t)).magnitude() < 1e-4);
+            assert!(record.normal.dot(pu.cross(pv)).abs() > 0.99 * pu.cross(pv).magnitude());
+            assert!(record.normal.y > 0.0);
+
+            // From below the same point is hit, with the normal facing down.
+            let ray = Ray::new(point(x, -5.0, z), point(0.0, 1.0, 0.0));
+            let below = patch.hit(&ray, 0.0, Float::MAX).unwrap();
+            assert!((below.p - record.p).magnitude() < 1e-4);
+            assert!(below.normal.y < 0.0);
+        }
+    }
+
+    #[test]
+    fn rays_beside_the_patch_miss() {
+        let patch = dome();
+        for &(x, z) in [(1.2, 0.0), (0.0, -1.1), (1.05, 1.05)].iter() {
+            let ray = Ray::new(point(x, 5.0, z), point(0.0, -1.0, 0.0));
+            assert!(patch.hit(&ray, 0.0, Float::MAX).is_none());
+        }
+        let ray = Ray::new(point(0.0, 5.0, 0.0), point(0.0, -1.0, 0.0));
+        assert!(patch.hit(&ray, 0.0, 4.0).is_none());
+    }
+}
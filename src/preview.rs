@@ -0,0 +1,121 @@
+use crate::lib::{Float, Hitable, InnerSpace, ThinLensCamera, Vector3};
+use crate::render::{accumulate_pass, to_rgb8, Accumulator, Settings};
+use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
+
+// A camera circling a target, as driven by the mouse in the preview. Angles
+// are in degrees, with the camera at yaw 0 on the +z side of the target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orbit {
+    pub target: Vector3<Float>,
+    pub distance: Float,
+    pub yaw: Float,
+    pub pitch: Float,
+    pub vfov: Float,
+}
+
+impl Orbit {
+    pub fn new(lookfrom: Vector3<Float>, lookat: Vector3<Float>, vfov: Float) -> Orbit {
+        let offset = lookfrom - lookat;
+        let distance = offset.magnitude();
+        Orbit {
+            target: lookat,
+            distance,
+            yaw: offset.x.atan2(offset.z).to_degrees(),
+            pitch: (offset.y / distance).asin().to_degrees(),
+            vfov,
+        }
+    }
+
+    pub fn lookfrom(&self) -> Vector3<Float> {
+        let (yaw, pitch) = (self.yaw.to_radians(), self.pitch.to_radians());
+        self.target
+            + Vector3 {
+                x: yaw.sin() * pitch.cos(),
+                y: pitch.sin(),
+                z: yaw.cos() * pitch.cos(),
+            } * self.distance
+    }
+
+    pub fn camera(&self, aspect: Float) -> ThinLensCamera {
+        ThinLensCamera::new(
+            self.lookfrom(),
+            self.target,
+            Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            self.vfov,
+            aspect,
+            0.0,
+            self.distance,
+        )
+    }
+}
+
+// Opens a window showing the render as it accumulates, one pass per frame,
+// until it has `settings.samples` passes. Dragging with the left button
+// orbits the camera and the wheel zooms, both starting the accumulation
+// over. Returns the orbit the window was closed at, so a final render can
+// pick up from there.
+pub fn preview(
+    world: &dyn Hitable,
+    orbit: Orbit,
+    settings: &Settings,
+) -> Result<Orbit, minifb::Error> {
+    let mut orbit = orbit;
+    let mut window = Window::new(
+        "Preview",
+        settings.width,
+        settings.height,
+        WindowOptions::default(),
+    )?;
+    window.set_target_fps(60);
+    let mut camera = orbit.camera(settings.aspect());
+    let mut accumulator = Accumulator::new(settings);
+    let mut buffer = vec![0u32; settings.width * settings.height];
+    let mut last_mouse: Option<(f32, f32)> = None;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let mut changed = false;
+        let mouse = window.get_mouse_pos(MouseMode::Pass);
+        if window.get_mouse_down(MouseButton::Left) {
+            if let (Some((x0, y0)), Some((x1, y1))) = (last_mouse, mouse) {
+                if (x0, y0) != (x1, y1) {
+                    // Dragging across the whole window turns half way round.
+                    orbit.yaw -= (x1 - x0) as Float * 180.0 / settings.width as Float;
+                    orbit.pitch = (orbit.pitch
+                        + (y1 - y0) as Float * 90.0 / settings.height as Float)
+                        .clamp(-89.0, 89.0);
+                    changed = true;
+                }
+            }
+            last_mouse = mouse;
+        } else {
+            last_mouse = None;
+        }
+        if let Some((_, scroll)) = window.get_scroll_wheel() {
+            if scroll != 0.0 {
+                orbit.distance *= (0.9 as Float).powf(scroll as Float);
+                changed = true;
+            }
+        }
+        if changed {
+            camera = orbit.camera(settings.aspect());
+            accumulator = Accumulator::new(settings);
+        }
+
+        if accumulator.passes < settings.samples {
+            accumulate_pass(world, &camera, settings, &mut accumulator);
+            let image = accumulator.image();
+            for (pixel, color) in buffer.iter_mut().zip(image.pixels.iter()) {
+                let [r, g, b] = to_rgb8(*color);
+                *pixel = (r as u32) << 16 | (g as u32) << 8 | b as u32;
+            }
+            window.update_with_buffer(&buffer, settings.width, settings.height)?;
+        } else {
+            window.update();
+        }
+    }
+    Ok(orbit)
+}
@@ -0,0 +1,518 @@
+use crate::aov::{Aov, AovPass, Layer};
+use crate::lib::{Camera, ElementWise, Float, Hitable, InnerSpace, Ray, Vector3};
+use crate::rng;
+use crate::spectral::{
+    equal_energy_white, rgb_to_spectrum, xyz_to_balanced_srgb, SampledSpectrum, SampledWavelengths,
+};
+use std::io::{self, Read, Write};
+
+pub fn sky(ray: &Ray) -> Vector3<Float> {
+    let unit_direction = ray.direction().normalize();
+    let t = 0.5 * (unit_direction.y + 1.0);
+    (1.0 - t)
+        * Vector3 {
+            x: 1.0,
+            y: 1.0,
+            z: 1.0,
+        }
+        + t * Vector3 {
+            x: 0.5,
+            y: 0.7,
+            z: 1.0,
+        }
+}
+
+pub fn color(ray: &Ray, world: &dyn Hitable, depth: i32, max_depth: i32) -> Vector3<Float> {
+    let zero = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    if let Some(record) = world.hit(ray, 0.0, Float::MAX) {
+        if depth < max_depth {
+            if let Some((attenuation, scattered)) = record.material.scatter(ray, &record) {
+                return attenuation.mul_element_wise(color(
+                    &scattered,
+                    world,
+                    depth + 1,
+                    max_depth,
+                ));
+            }
+        }
+        zero
+    } else {
+        sky(ray)
+    }
+}
+
+pub fn spectral_color(
+    ray: &Ray,
+    world: &dyn Hitable,
+    wavelengths: &mut SampledWavelengths,
+    depth: i32,
+    max_depth: i32,
+) -> SampledSpectrum {
+    if let Some(record) = world.hit(ray, 0.0, Float::MAX) {
+        if depth < max_depth {
+            if let Some((attenuation, scattered)) =
+                record.material.scatter_spectral(ray, &record, wavelengths)
+            {
+                return attenuation
+                    * spectral_color(&scattered, world, wavelengths, depth + 1, max_depth);
+            }
+        }
+        SampledSpectrum::constant(0.0)
+    } else {
+        rgb_to_spectrum(sky(ray), wavelengths)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Rgb,
+    Spectral,
+}
+
+// Rectangle of pixels, measured from the top left corner of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    // The part of the region inside an image of the given size.
+    pub fn clamp(&self, width: usize, height: usize) -> Region {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Region {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub max_depth: i32,
+    pub mode: Mode,
+    pub aovs: Vec<Aov>,
+    pub seed: u64,
+    // Only the pixels in the region are traced, and they come out exactly as
+    // in a render of the whole frame. The rest stay black.
+    pub region: Option<Region>,
+}
+
+impl Settings {
+    pub fn new(width: usize, height: usize, samples: usize) -> Settings {
+        Settings {
+            width,
+            height,
+            samples,
+            max_depth: 50,
+            mode: Mode::Rgb,
+            aovs: Vec::new(),
+            seed: 0,
+            region: None,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        let full = Region {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.region.unwrap_or(full).clamp(self.width, self.height)
+    }
+
+    pub fn aspect(&self) -> Float {
+        self.width as Float / self.height as Float
+    }
+}
+
+// Linear color, stored top row first.
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vector3<Float>>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                };
+                width * height
+            ],
+        }
+    }
+
+    pub fn crop(&self, region: Region) -> Image {
+        let region = region.clamp(self.width, self.height);
+        let mut image = Image::new(region.width, region.height);
+        for y in 0..region.height {
+            for x in 0..region.width {
+                image.set(x, y, self.get(region.x + x, region.y + y));
+            }
+        }
+        image
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vector3<Float> {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Vector3<Float>) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "P3")?;
+        writeln!(out, "{} {}", self.width, self.height)?;
+        writeln!(out, "255")?;
+        for col in self.pixels.iter() {
+            let [r, g, b] = to_rgb8(*col);
+            writeln!(out, "{} {} {}", r, g, b)?;
+        }
+        Ok(())
+    }
+
+    // Reads a binary or plain PGM or PPM with 8 or 16 bit samples. Values
+    // are scaled to [0, 1] but not gamma decoded, since the files read this
+    // way hold data such as heights rather than colors.
+    pub fn read_pnm<R: Read>(input: &mut R) -> io::Result<Image> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+
+        // Header fields are separated by whitespace and `#` comments, with a
+        // single whitespace byte before binary data.
+        let mut position = 0;
+        let mut fields = Vec::new();
+        while fields.len() < 4 {
+            while position < bytes.len()
+                && (bytes[position].is_ascii_whitespace() || bytes[position] == b'#')
+            {
+                if bytes[position] == b'#' {
+                    while position < bytes.len() && bytes[position] != b'\n' {
+                        position += 1;
+                    }
+                } else {
+                    position += 1;
+                }
+            }
+            let start = position;
+            while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+                position += 1;
+            }
+            if start == position {
+                return Err(invalid("truncated header"));
+            }
+            fields.push(String::from_utf8_lossy(&bytes[start..position]).into_owned());
+        }
+        position += 1;
+
+        let (channels, binary) = match fields[0].as_str() {
+            "P2" => (1, false),
+            "P3" => (3, false),
+            "P5" => (1, true),
+            "P6" => (3, true),
+            _ => return Err(invalid("not a PGM or PPM file")),
+        };
+        let number = |field: &str| field.parse::<usize>().map_err(|_| invalid("bad header"));
+        let width = number(&fields[1])?;
+        let height = number(&fields[2])?;
+        let max = number(&fields[3])?;
+        if max == 0 || max > 65535 {
+            return Err(invalid("bad maximum value"));
+        }
+
+        // The sizes come from the file, so they may overflow.
+        let count = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(channels))
+            .ok_or_else(|| invalid("bad header"))?;
+        let samples: Vec<usize> = if binary {
+            let size = if max > 255 { 2 } else { 1 };
+            let end = count
+                .checked_mul(size)
+                .and_then(|length| length.checked_add(position))
+                .ok_or_else(|| invalid("bad header"))?;
+            let data = bytes
+                .get(position..end)
+                .ok_or_else(|| invalid("truncated data"))?;
+            data.chunks(size)
+                .map(|sample| {
+                    sample
+                        .iter()
+                        .fold(0, |value, &byte| value << 8 | byte as usize)
+                })
+                .collect()
+        } else {
+            String::from_utf8_lossy(&bytes[position.min(bytes.len())..])
+                .split_whitespace()
+                .take(count)
+                .map(number)
+                .collect::<io::Result<_>>()?
+        };
+        if samples.len() < count {
+            return Err(invalid("truncated data"));
+        }
+
+        let mut image = Image::new(width, height);
+        for (pixel, sample) in image.pixels.iter_mut().zip(samples.chunks(channels)) {
+            let value = |c: usize| sample[c.min(channels - 1)] as Float / max as Float;
+            *pixel = Vector3 {
+                x: value(0),
+                y: value(1),
+                z: value(2),
+            };
+        }
+        Ok(image)
+    }
+}
+
+// Gamma 2 encoding, as in the chapters.
+pub fn to_rgb8(col: Vector3<Float>) -> [u8; 3] {
+    let encode = |c: Float| (c.clamp(0.0, 1.0).sqrt() * 255.99) as u8;
+    [encode(col.x), encode(col.y), encode(col.z)]
+}
+
+pub fn radiance(
+    ray: &Ray,
+    world: &dyn Hitable,
+    settings: &Settings,
+    white: Vector3<Float>,
+) -> Vector3<Float> {
+    match settings.mode {
+        Mode::Rgb => color(ray, world, 0, settings.max_depth),
+        Mode::Spectral => {
+            let mut wavelengths = SampledWavelengths::sample_uniform(rng::random());
+            let radiance = spectral_color(ray, world, &mut wavelengths, 0, settings.max_depth);
+            xyz_to_balanced_srgb(radiance.to_xyz(&wavelengths), white)
+        }
+    }
+}
+
+pub fn primary_ray(camera: &dyn Camera, settings: &Settings, i: usize, j: usize) -> Option<Ray> {
+    let u = (i as Float + rng::random()) / settings.width as Float;
+    let v = (j as Float + rng::random()) / settings.height as Float;
+    camera.get_ray(u, v)
+}
+
+pub fn render(world: &dyn Hitable, camera: &dyn Camera, settings: &Settings) -> Image {
+    render_passes(world, camera, settings).0
+}
+
+// Running sums of a progressive render. Every pass adds one sample to each
+// pixel, seeded from the pixel and the pass number, so the result only
+// depends on the settings and not on how the passes were scheduled.
+pub struct Accumulator {
+    pub width: usize,
+    pub height: usize,
+    pub passes: usize,
+    pub beauty: Vec<Vector3<Float>>,
+    pub aovs: AovPass,
+}
+
+impl Accumulator {
+    pub fn new(settings: &Settings) -> Accumulator {
+        Accumulator {
+            width: settings.width,
+            height: settings.height,
+            passes: 0,
+            beauty: Image::new(settings.width, settings.height).pixels,
+            aovs: AovPass::new(&settings.aovs, settings.width, settings.height),
+        }
+    }
+
+    pub fn image(&self) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        let passes = self.passes.max(1) as Float;
+        for (pixel, sum) in image.pixels.iter_mut().zip(self.beauty.iter()) {
+            *pixel = sum / passes;
+        }
+        image
+    }
+
+    pub fn layers(&self) -> Vec<Layer> {
+        self.aovs.layers()
+    }
+}
+
+pub fn accumulate_pass(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    settings: &Settings,
+    accumulator: &mut Accumulator,
+) {
+    let white = equal_energy_white();
+    let region = settings.region();
+    for y in region.y..region.y + region.height {
+        let j = settings.height - 1 - y;
+        for i in region.x..region.x + region.width {
+            let index = y * settings.width + i;
+            rng::seed_sample(settings.seed, index, accumulator.passes);
+            // A blocked ray adds nothing to the beauty pass, but the AOVs
+            // describe the scene and only average over the rays that see it.
+            if let Some(ray) = primary_ray(camera, settings, i, j) {
+                accumulator.beauty[index] +=
+                    radiance(&ray, world, settings, white) * camera.exposure();
+                if !accumulator.aovs.is_empty() {
+                    accumulator.aovs.add_sample(index, &ray, world);
+                }
+            }
+        }
+    }
+    accumulator.passes += 1;
+}
+
+// Renders the beauty pass together with the AOVs requested in the settings,
+// returned in the same order.
+pub fn render_passes(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    settings: &Settings,
+) -> (Image, Vec<Layer>) {
+    let mut accumulator = Accumulator::new(settings);
+    while accumulator.passes < settings.samples {
+        accumulate_pass(world, camera, settings, &mut accumulator);
+    }
+    (accumulator.image(), accumulator.layers())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{Lambertian, Metal, Sphere, ThinLensCamera};
+
+    fn read(bytes: &[u8]) -> io::Result<Image> {
+        Image::read_pnm(&mut &bytes[..])
+    }
+
+    #[test]
+    fn reads_binary_and_plain_files() {
+        let image = read(b"P5\n# comment\n2 1\n255\n\x00\xff").unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.get(0, 0).x, 0.0);
+        assert_eq!(image.get(1, 0).z, 1.0);
+
+        let image = read(b"P6 1 1 65535 \xff\xff\x00\x00\x80\x00").unwrap();
+        assert_eq!(image.get(0, 0).x, 1.0);
+        assert_eq!(image.get(0, 0).y, 0.0);
+
+        let image = read(b"P2 2 2 10  0 5 10 5").unwrap();
+        assert_eq!(image.get(1, 1).y, 0.5);
+    }
+
+    #[test]
+    fn rejects_sizes_that_overflow() {
+        let huge = format!("P6 {} {} 255\n", usize::MAX / 2, 3);
+        let error = read(huge.as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "bad header");
+
+        // Fits as a sample count, but not once two bytes per sample.
+        let wide = format!("P5 {} 1 65535\n", usize::MAX / 2 + 1);
+        let error = read(wide.as_bytes()).err().unwrap();
+        assert_eq!(error.to_string(), "bad header");
+
+        let error = read(b"P5 1000 1000 255\n\x00").err().unwrap();
+        assert_eq!(error.to_string(), "truncated data");
+    }
+
+    fn vector(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    fn find<'a>(layers: &'a [Layer], name: &str) -> &'a Layer {
+        layers.iter().find(|layer| layer.name == name).unwrap()
+    }
+
+    // The sphere listed first is on the right, so the left one is the first
+    // a full render sees.
+    #[test]
+    fn region_matches_the_full_render() {
+        let world = vec![
+            Sphere {
+                center: vector(0.8, 0.0, -2.0),
+                radius: 0.5,
+                material: Box::new(Lambertian {
+                    albedo: vector(0.8, 0.2, 0.2),
+                }),
+            },
+            Sphere {
+                center: vector(-0.8, 0.0, -2.0),
+                radius: 0.5,
+                material: Box::new(Metal {
+                    fuzz: 0.1,
+                    albedo: vector(0.6, 0.6, 0.6),
+                }),
+            },
+        ];
+        let camera = ThinLensCamera::new(
+            vector(0.0, 0.0, 0.0),
+            vector(0.0, 0.0, -1.0),
+            vector(0.0, 1.0, 0.0),
+            60.0,
+            16.0 / 12.0,
+            0.0,
+            1.0,
+        );
+        let mut settings = Settings::new(16, 12, 2);
+        settings.aovs = Aov::ALL.to_vec();
+        let (full, full_layers) = render_passes(&world, &camera, &settings);
+
+        let region = Region {
+            x: 9,
+            y: 2,
+            width: 7,
+            height: 8,
+        };
+        settings.region = Some(region);
+        let (image, layers) = render_passes(&world, &camera, &settings);
+
+        for y in 0..settings.height {
+            for x in 0..settings.width {
+                let inside = x >= region.x
+                    && x < region.x + region.width
+                    && y >= region.y
+                    && y < region.y + region.height;
+                if inside {
+                    assert_eq!(image.get(x, y), full.get(x, y), "pixel {} {}", x, y);
+                } else {
+                    assert_eq!(image.get(x, y), vector(0.0, 0.0, 0.0), "pixel {} {}", x, y);
+                }
+            }
+        }
+        for (layer, full) in layers.iter().zip(full_layers.iter()) {
+            assert_eq!(
+                layer.crop(region).data,
+                full.crop(region).data,
+                "{}",
+                layer.name
+            );
+        }
+        // The region only sees the sphere listed first.
+        assert_eq!(find(&layers, "object_id").pixel(12, 6)[0], 0.0);
+        assert_eq!(find(&layers, "material_id").pixel(12, 6)[0], 0.0);
+        assert_eq!(find(&full_layers, "material_id").pixel(5, 6)[0], 1.0);
+    }
+}
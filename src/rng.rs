@@ -0,0 +1,57 @@
+use crate::lib::Float;
+use std::cell::Cell;
+
+// Thread-local splitmix64 generator. Unlike the thread_rng from rand it can
+// be reseeded, which lets the renderer derive every sample from its pixel and
+// pass so that an interrupted render resumes to exactly the same image.
+thread_local!(static STATE: Cell<u64> = const { Cell::new(0x853c_49e6_748f_ea9b) });
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(s);
+        mix(s)
+    })
+}
+
+pub fn seed(seed: u64) {
+    STATE.with(|state| state.set(mix(seed)));
+}
+
+// Seeds the generator for one sample of one pixel.
+pub fn seed_sample(seed: u64, pixel: usize, pass: usize) {
+    self::seed(mix(seed ^ mix(pixel as u64 + 1)).wrapping_add(pass as u64));
+}
+
+// Uniform in [0, 1).
+pub fn random() -> Float {
+    (next_u64() >> 40) as Float / (1u64 << 24) as Float
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(seed: u64, pixel: usize, pass: usize) -> Vec<Float> {
+        seed_sample(seed, pixel, pass);
+        (0..8).map(|_| random()).collect()
+    }
+
+    #[test]
+    fn samples_depend_only_on_seed_pixel_and_pass() {
+        let first = draws(3, 10, 4);
+        // Anything drawn in between doesn't matter once reseeded.
+        draws(9, 1, 1);
+        assert_eq!(draws(3, 10, 4), first);
+        assert_ne!(draws(3, 10, 5), first);
+        assert_ne!(draws(3, 11, 4), first);
+        assert_ne!(draws(4, 10, 4), first);
+        assert!(first.iter().all(|&x| (0.0..1.0).contains(&x)));
+    }
+}
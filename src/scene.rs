@@ -0,0 +1,303 @@
+use crate::lib::{
+    Dielectric, Float, Hitable, InnerSpace, Lambertian, Material, Metal, Sphere, ThinLensCamera,
+    Vector3,
+};
+use crate::render::{Mode, Settings};
+use crate::shapes::Cuboid;
+use std::io::{self, BufRead};
+
+// A scene read from text, for scenes that don't come from code. One item per
+// line, with `#` starting a comment:
+//
+//   size 400 200
+//   samples 64
+//   depth 50
+//   seed 7
+//   mode spectral
+//   camera 0 1 5  0 0 0  30 0.1 5      lookfrom, lookat, vfov[, aperture, focus]
+//   sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5
+//   sphere 0 1 0 1 metal 0.8 0.8 0.8 0.1
+//   cuboid 1 0 -1 2 1 0 dielectric 1.5
+//
+// Materials are `lambertian r g b`, `metal r g b fuzz` and `dielectric eta`.
+// Scenes can come from anyone who can reach the render server, so sizes,
+// sample counts and depths past the limits below are rejected.
+pub struct Scene {
+    pub world: Vec<Box<dyn Hitable>>,
+    pub camera: ThinLensCamera,
+    pub settings: Settings,
+}
+
+const MAX_PIXELS: usize = 1 << 24;
+const MAX_SAMPLES: usize = 1 << 16;
+const MAX_DEPTH: i32 = 1000;
+// Samples over the whole image, about 500 per pixel at 1920x1080.
+const MAX_WORK: usize = 1 << 30;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The words of a line, consumed as numbers and names.
+struct Words<'a> {
+    words: std::str::SplitWhitespace<'a>,
+    line: usize,
+}
+
+impl<'a> Words<'a> {
+    fn word(&mut self) -> io::Result<&'a str> {
+        self.words
+            .next()
+            .ok_or_else(|| invalid(format!("line {}: missing value", self.line)))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self) -> io::Result<T> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| invalid(format!("line {}: bad number {}", self.line, word)))
+    }
+
+    fn optional_number<T: std::str::FromStr>(&mut self) -> io::Result<Option<T>> {
+        match self.words.next() {
+            Some(word) => word
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(format!("line {}: bad number {}", self.line, word))),
+            None => Ok(None),
+        }
+    }
+
+    fn vector(&mut self) -> io::Result<Vector3<Float>> {
+        Ok(Vector3 {
+            x: self.number()?,
+            y: self.number()?,
+            z: self.number()?,
+        })
+    }
+
+    fn material(&mut self) -> io::Result<Box<dyn Material>> {
+        match self.word()? {
+            "lambertian" => Ok(Box::new(Lambertian {
+                albedo: self.vector()?,
+            })),
+            "metal" => Ok(Box::new(Metal {
+                albedo: self.vector()?,
+                fuzz: self.number()?,
+            })),
+            "dielectric" => Ok(Box::new(Dielectric {
+                ref_idx: self.number()?,
+            })),
+            other => Err(invalid(format!(
+                "line {}: unknown material {}",
+                self.line, other
+            ))),
+        }
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        match self.words.next() {
+            Some(word) => Err(invalid(format!("line {}: unexpected {}", self.line, word))),
+            None => Ok(()),
+        }
+    }
+}
+
+struct CameraLine {
+    lookfrom: Vector3<Float>,
+    lookat: Vector3<Float>,
+    vfov: Float,
+    aperture: Float,
+    focus_dist: Option<Float>,
+}
+
+impl Scene {
+    pub fn read<R: BufRead>(input: &mut R) -> io::Result<Scene> {
+        let mut world: Vec<Box<dyn Hitable>> = Vec::new();
+        let mut settings = Settings::new(200, 100, 16);
+        let mut camera = None;
+        for (number, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = Words {
+                words: line.split_whitespace(),
+                line: number + 1,
+            };
+            let keyword = match words.words.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+            match keyword {
+                "size" => {
+                    settings.width = words.number()?;
+                    settings.height = words.number()?;
+                    if settings.width == 0 || settings.height == 0 {
+                        return Err(invalid(format!("line {}: empty image", number + 1)));
+                    }
+                    match settings.width.checked_mul(settings.height) {
+                        Some(pixels) if pixels <= MAX_PIXELS => (),
+                        _ => return Err(invalid(format!("line {}: image too large", number + 1))),
+                    }
+                }
+                "samples" => {
+                    settings.samples = words.number()?;
+                    if settings.samples == 0 || settings.samples > MAX_SAMPLES {
+                        return Err(invalid(format!(
+                            "line {}: samples must be 1 to {}",
+                            number + 1,
+                            MAX_SAMPLES
+                        )));
+                    }
+                }
+                "depth" => {
+                    settings.max_depth = words.number()?;
+                    if settings.max_depth < 0 || settings.max_depth > MAX_DEPTH {
+                        return Err(invalid(format!(
+                            "line {}: depth must be 0 to {}",
+                            number + 1,
+                            MAX_DEPTH
+                        )));
+                    }
+                }
+                "seed" => settings.seed = words.number()?,
+                "mode" => {
+                    settings.mode = match words.word()? {
+                        "rgb" => Mode::Rgb,
+                        "spectral" => Mode::Spectral,
+                        other => {
+                            return Err(invalid(format!(
+                                "line {}: unknown mode {}",
+                                number + 1,
+                                other
+                            )))
+                        }
+                    }
+                }
+                "camera" => {
+                    let lookfrom = words.vector()?;
+                    let lookat = words.vector()?;
+                    let vfov = words.number()?;
+                    let aperture = words.optional_number()?.unwrap_or(0.0);
+                    let focus_dist = words.optional_number()?;
+                    camera = Some(CameraLine {
+                        lookfrom,
+                        lookat,
+                        vfov,
+                        aperture,
+                        focus_dist,
+                    });
+                }
+                "sphere" => {
+                    let center = words.vector()?;
+                    let radius = words.number()?;
+                    world.push(Box::new(Sphere {
+                        center,
+                        radius,
+                        material: words.material()?,
+                    }));
+                }
+                "cuboid" => {
+                    let p0 = words.vector()?;
+                    let p1 = words.vector()?;
+                    world.push(Box::new(Cuboid::new(p0, p1, words.material()?)));
+                }
+                other => {
+                    return Err(invalid(format!(
+                        "line {}: unknown item {}",
+                        number + 1,
+                        other
+                    )))
+                }
+            }
+            words.end()?;
+        }
+
+        // Size and samples can come in either order, so their product is
+        // checked once both are known.
+        match (settings.width * settings.height).checked_mul(settings.samples) {
+            Some(work) if work <= MAX_WORK => (),
+            _ => {
+                return Err(invalid(format!(
+                    "image size times samples is over {}",
+                    MAX_WORK
+                )))
+            }
+        }
+        let camera = camera.ok_or_else(|| invalid("scene has no camera".to_string()))?;
+        let focus_dist = camera
+            .focus_dist
+            .unwrap_or_else(|| (camera.lookfrom - camera.lookat).magnitude());
+        let camera = ThinLensCamera::new(
+            camera.lookfrom,
+            camera.lookat,
+            Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            camera.vfov,
+            settings.aspect(),
+            camera.aperture,
+            focus_dist,
+        );
+        Ok(Scene {
+            world,
+            camera,
+            settings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> io::Result<Scene> {
+        Scene::read(&mut text.as_bytes())
+    }
+
+    #[test]
+    fn reads_settings_and_objects() {
+        let scene = read(
+            "size 40 20\nsamples 4\ndepth 8\n# comment\ncamera 0 1 5  0 0 0  30\n\
+             sphere 0 -1000 0 1000 lambertian 0.5 0.5 0.5\ncuboid 1 0 -1 2 1 0 dielectric 1.5\n",
+        )
+        .unwrap();
+        assert_eq!((scene.settings.width, scene.settings.height), (40, 20));
+        assert_eq!(scene.settings.samples, 4);
+        assert_eq!(scene.settings.max_depth, 8);
+        assert_eq!(scene.world.len(), 2);
+    }
+
+    #[test]
+    fn rejects_settings_past_the_limits() {
+        let camera = "camera 0 0 1 0 0 0 40\n";
+        for (line, message) in [
+            ("size 100000 100000", "line 1: image too large"),
+            ("size 18446744073709551615 2", "line 1: image too large"),
+            ("samples 0", "line 1: samples must be 1 to 65536"),
+            ("samples 1000000", "line 1: samples must be 1 to 65536"),
+            ("depth -1", "line 1: depth must be 0 to 1000"),
+            ("depth 1000000", "line 1: depth must be 0 to 1000"),
+        ]
+        .iter()
+        {
+            let error = read(&format!("{}\n{}", line, camera)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), *message);
+        }
+        for scene in [
+            "size 4096 4096\nsamples 65536\n",
+            "samples 65536\nsize 4096 4096\n",
+        ]
+        .iter()
+        {
+            let error = read(&format!("{}{}", scene, camera)).err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                "image size times samples is over 1073741824"
+            );
+        }
+        assert!(read(&format!("size 4096 4096\nsamples 64\n{}", camera)).is_ok());
+        assert!(read(&format!("size 64 64\nsamples 65536\n{}", camera)).is_ok());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::lib::{abs, gamma, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Vector3};
+
+// A surface given by a signed distance function, found by sphere tracing:
+// the distance at a point is a step that can't cross the surface. Distance
+// functions that only bound the distance (twists, fractals) need a
+// `step_scale` below one to avoid overshooting.
+pub struct SdfHitable {
+    pub distance: Box<dyn Fn(Vector3<Float>) -> Float>,
+    pub epsilon: Float,
+    pub max_steps: usize,
+    pub step_scale: Float,
+    pub bounds: Option<Aabb>,
+    pub material: Box<dyn Material>,
+}
+
+impl SdfHitable {
+    pub fn new<F>(distance: F, material: Box<dyn Material>) -> SdfHitable
+    where
+        F: Fn(Vector3<Float>) -> Float + 'static,
+    {
+        SdfHitable {
+            distance: Box::new(distance),
+            epsilon: 1e-4,
+            max_steps: 256,
+            step_scale: 1.0,
+            bounds: None,
+            material,
+        }
+    }
+
+    pub fn normal(&self, p: Vector3<Float>) -> Vector3<Float> {
+        gradient(&*self.distance, p, self.epsilon).normalize()
+    }
+}
+
+// Central differences with step h.
+pub fn gradient(
+    f: &dyn Fn(Vector3<Float>) -> Float,
+    p: Vector3<Float>,
+    h: Float,
+) -> Vector3<Float> {
+    let dx = Vector3 {
+        x: h,
+        y: 0.0,
+        z: 0.0,
+    };
+    let dy = Vector3 {
+        x: 0.0,
+        y: h,
+        z: 0.0,
+    };
+    let dz = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: h,
+    };
+    Vector3 {
+        x: f(p + dx) - f(p - dx),
+        y: f(p + dy) - f(p - dy),
+        z: f(p + dz) - f(p - dz),
+    } / (2.0 * h)
+}
+
+impl Hitable for SdfHitable {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let (t_start, t_end) = match self.bounds {
+            Some(bounds) => bounds.intersect(ray, t_min, t_max)?,
+            None => (t_min, t_max),
+        };
+        let speed = ray.direction().magnitude();
+
+        // A ray leaving the surface starts within epsilon of it; it has to
+        // get clear before a crossing counts, or it would hit itself.
+        let mut t = t_start;
+        let mut clear = (self.distance)(ray.point_at_parameter(t)).abs() >= self.epsilon;
+        for _ in 0..self.max_steps {
+            let p = ray.point_at_parameter(t);
+            let distance = (self.distance)(p).abs();
+            if distance < self.epsilon {
+                if clear {
+                    let normal = self.normal(p);
+                    return Some(HitRecord {
+                        t,
+                        p,
+                        normal,
+                        geometric_normal: normal,
+                        tangent: Vector3 {
+                            x: 0.0,
+                            y: 0.0,
+                            z: 0.0,
+                        },
+                        // Anywhere within epsilon counts as on the surface.
+                        error: abs(p) * gamma(2)
+                            + Vector3 {
+                                x: self.epsilon,
+                                y: self.epsilon,
+                                z: self.epsilon,
+                            },
+                        u: 0.0,
+                        v: 0.0,
+                        material: &*self.material,
+                        object_id: 0,
+                    });
+                }
+            } else {
+                clear = true;
+            }
+            t += distance.max(self.epsilon) * self.step_scale / speed;
+            if t >= t_end {
+                break;
+            }
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounds
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+pub fn sphere(center: Vector3<Float>, radius: Float) -> impl Fn(Vector3<Float>) -> Float {
+    move |p| (p - center).magnitude() - radius
+}
+
+pub fn cuboid(
+    center: Vector3<Float>,
+    half_size: Vector3<Float>,
+) -> impl Fn(Vector3<Float>) -> Float {
+    move |p| {
+        let d = p - center;
+        let q = Vector3 {
+            x: d.x.abs() - half_size.x,
+            y: d.y.abs() - half_size.y,
+            z: d.z.abs() - half_size.z,
+        };
+        let outside = Vector3 {
+            x: q.x.max(0.0),
+            y: q.y.max(0.0),
+            z: q.z.max(0.0),
+        };
+        outside.magnitude() + q.x.max(q.y.max(q.z)).min(0.0)
+    }
+}
+
+// Torus around the y axis.
+pub fn torus(
+    center: Vector3<Float>,
+    major_radius: Float,
+    minor_radius: Float,
+) -> impl Fn(Vector3<Float>) -> Float {
+    move |p| {
+        let d = p - center;
+        let ring = (d.x * d.x + d.z * d.z).sqrt() - major_radius;
+        (ring * ring + d.y * d.y).sqrt() - minor_radius
+    }
+}
+
+// Distance estimator of the Mandelbulb fractal, which for power 8 fits in
+// a sphere of radius 1.2 around the origin. It takes at least one
+// iteration.
+pub fn mandelbulb(power: Float, iterations: usize) -> impl Fn(Vector3<Float>) -> Float {
+    move |p| {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = 0.0;
+        for _ in 0..iterations.max(1) {
+            r = z.magnitude();
+            if r > 2.0 {
+                break;
+            }
+            // At 0 the power is 0 whatever the angles.
+            let theta = if r > 0.0 {
+                (z.z / r).acos() * power
+            } else {
+                0.0
+            };
+            let phi = z.y.atan2(z.x) * power;
+            dr = r.powf(power - 1.0) * power * dr + 1.0;
+            let zr = r.powf(power);
+            z =
+                zr * Vector3 {
+                    x: theta.sin() * phi.cos(),
+                    y: phi.sin() * theta.sin(),
+                    z: theta.cos(),
+                } + p;
+        }
+        // r ln r goes to 0 with r, where the orbit stays at the origin.
+        if r > 0.0 {
+            0.5 * r.ln() * r / dr
+        } else {
+            0.0
+        }
+    }
+}
+
+// Polynomial smooth minimum; `k` is the size of the blend region.
+pub fn smooth_union<A, B>(a: A, b: B, k: Float) -> impl Fn(Vector3<Float>) -> Float
+where
+    A: Fn(Vector3<Float>) -> Float,
+    B: Fn(Vector3<Float>) -> Float,
+{
+    move |p| {
+        let da = a(p);
+        let db = b(p);
+        let h = (0.5 + 0.5 * (db - da) / k).clamp(0.0, 1.0);
+        db + (da - db) * h - k * h * (1.0 - h)
+    }
+}
+
+// Infinite copies of a shape on a grid with the given cell size. Shapes
+// should fit inside their cell.
+pub fn repeat<F>(f: F, period: Vector3<Float>) -> impl Fn(Vector3<Float>) -> Float
+where
+    F: Fn(Vector3<Float>) -> Float,
+{
+    let wrap = |x: Float, period: Float| {
+        if period > 0.0 {
+            x - period * (x / period).round()
+        } else {
+            x
+        }
+    };
+    move |p| {
+        f(Vector3 {
+            x: wrap(p.x, period.x),
+            y: wrap(p.y, period.y),
+            z: wrap(p.z, period.z),
+        })
+    }
+}
+
+// Twists a shape around the y axis by `rate` radians per unit of height.
+pub fn twist<F>(f: F, rate: Float) -> impl Fn(Vector3<Float>) -> Float
+where
+    F: Fn(Vector3<Float>) -> Float,
+{
+    move |p| {
+        let (s, c) = (rate * p.y).sin_cos();
+        f(Vector3 {
+            x: c * p.x - s * p.z,
+            y: p.y,
+            z: s * p.x + c * p.z,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // Sphere tracing the exact distance to a sphere against its closed form.
+    #[test]
+    fn sphere_hits_match_the_analytic_sphere() {
+        let center = point(0.0, 0.0, -3.0);
+        let surface = SdfHitable::new(
+            sphere(center, 1.0),
+            Box::new(Lambertian {
+                albedo: point(0.5, 0.5, 0.5),
+            }),
+        );
+        for &x in [0.0, 0.6, -0.9].iter() {
+            let ray = Ray::new(point(x, 0.0, 0.0), point(0.0, 0.0, -1.0));
+            let record = surface.hit(&ray, 0.0, Float::MAX).unwrap();
+            let t = 3.0 - (1.0 - x * x).sqrt();
+            assert!((record.t - t).abs() < 1e-3, "t = {} for {}", record.t, t);
+            let normal = ray.point_at_parameter(t) - center;
+            assert!((record.normal - normal).magnitude() < 1e-3);
+            assert!(((record.p - center).magnitude() - 1.0).abs() <= record.error.magnitude());
+
+            // Leaving the surface it doesn't hit itself.
+            let out = record.spawn_ray(normal);
+            assert!(surface.hit(&out, 0.0, Float::MAX).is_none());
+        }
+        let ray = Ray::new(point(1.1, 0.0, 0.0), point(0.0, 0.0, -1.0));
+        assert!(surface.hit(&ray, 0.0, Float::MAX).is_none());
+        let ray = Ray::new(point(0.0, 0.0, 0.0), point(0.0, 0.0, -1.0));
+        assert!(surface.hit(&ray, 0.0, 1.5).is_none());
+    }
+
+    #[test]
+    fn mandelbulb_is_finite_everywhere() {
+        for &iterations in [0, 1, 8].iter() {
+            let bulb = mandelbulb(8.0, iterations);
+            for &p in [
+                point(0.0, 0.0, 0.0),
+                point(0.0, 0.0, 0.5),
+                point(0.3, -0.2, 0.1),
+                point(3.0, 0.0, 0.0),
+            ]
+            .iter()
+            {
+                assert!(bulb(p).is_finite(), "{:?} after {}", p, iterations);
+            }
+            // Outside the bounding sphere the estimate is a positive
+            // distance no larger than the true one.
+            let d = bulb(point(3.0, 0.0, 0.0));
+            assert!(d > 0.0 && d < 3.0 - 1.0, "{}", d);
+        }
+        assert_eq!(
+            mandelbulb(8.0, 0)(point(0.4, 0.1, 0.2)),
+            mandelbulb(8.0, 1)(point(0.4, 0.1, 0.2))
+        );
+    }
+}
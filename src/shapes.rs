@@ -0,0 +1,1168 @@
+use crate::lib::{
+    abs, consts, gamma, to_f64, Aabb, Float, HitRecord, Hitable, InnerSpace, Material, Ray, Solid,
+    Span, Vector3,
+};
+use std::f64;
+use std::rc::Rc;
+
+// Orthonormal frame with `w` along a shape's axis. Shapes intersect in this
+// local space, where their equations are simplest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub origin: Vector3<Float>,
+    pub u: Vector3<Float>,
+    pub v: Vector3<Float>,
+    pub w: Vector3<Float>,
+}
+
+impl Frame {
+    pub fn new(origin: Vector3<Float>, axis: Vector3<Float>) -> Frame {
+        let w = axis.normalize();
+        let a = if w.x.abs() > 0.9 {
+            Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }
+        } else {
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }
+        };
+        let v = w.cross(a).normalize();
+        let u = v.cross(w);
+        Frame { origin, u, v, w }
+    }
+
+    pub fn to_local(&self, p: Vector3<Float>) -> Vector3<Float> {
+        self.to_local_vector(p - self.origin)
+    }
+
+    pub fn to_local_vector(&self, d: Vector3<Float>) -> Vector3<Float> {
+        Vector3 {
+            x: d.dot(self.u),
+            y: d.dot(self.v),
+            z: d.dot(self.w),
+        }
+    }
+
+    pub fn to_world(&self, p: Vector3<Float>) -> Vector3<Float> {
+        self.origin + self.to_world_vector(p)
+    }
+
+    pub fn to_world_vector(&self, d: Vector3<Float>) -> Vector3<Float> {
+        self.u * d.x + self.v * d.y + self.w * d.z
+    }
+
+    pub fn to_local_ray(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.to_local(ray.origin()),
+            self.to_local_vector(ray.direction()),
+        )
+    }
+
+    // World space box around a local space box.
+    pub fn bounding_box(&self, min: Vector3<Float>, max: Vector3<Float>) -> Aabb {
+        let mut corners = Vec::with_capacity(8);
+        for &x in [min.x, max.x].iter() {
+            for &y in [min.y, max.y].iter() {
+                for &z in [min.z, max.z].iter() {
+                    corners.push(self.to_world(Vector3 { x, y, z }));
+                }
+            }
+        }
+        Aabb::from_points(&corners)
+    }
+}
+
+// A candidate intersection in local space.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalHit {
+    pub t: Float,
+    pub normal: Vector3<Float>,
+    pub u: Float,
+    pub v: Float,
+}
+
+fn to_record<'a>(
+    hit: &LocalHit,
+    frame: &Frame,
+    ray: &Ray,
+    material: &'a dyn Material,
+) -> HitRecord<'a> {
+    // Rounding in the change of frame moves the ray by an amount that scales
+    // with the distances involved, along whichever world axes the frame maps
+    // it to. The roots themselves are found in double precision.
+    let p = ray.point_at_parameter(hit.t);
+    let normal = frame.to_world_vector(hit.normal).normalize();
+    let scale =
+        ((ray.origin() - frame.origin).magnitude() + (p - ray.origin()).magnitude()) * gamma(8);
+    HitRecord {
+        t: hit.t,
+        p,
+        normal,
+        geometric_normal: normal,
+        tangent: Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        error: (abs(ray.origin()) + abs(p)) * gamma(2)
+            + Vector3 {
+                x: scale,
+                y: scale,
+                z: scale,
+            },
+        u: hit.u,
+        v: hit.v,
+        material,
+        object_id: 0,
+    }
+}
+
+pub fn nearest_hit<'a>(
+    candidates: &[LocalHit],
+    frame: &Frame,
+    ray: &Ray,
+    t_min: Float,
+    t_max: Float,
+    material: &'a dyn Material,
+) -> Option<HitRecord<'a>> {
+    candidates
+        .iter()
+        .filter(|hit| hit.t > t_min && hit.t < t_max)
+        .min_by(|a, b| a.t.total_cmp(&b.t))
+        .map(|hit| to_record(hit, frame, ray, material))
+}
+
+// A ray crosses a convex shape at most once; extra candidates only show up
+// where surfaces meet, such as the rim of a cylinder.
+pub fn convex_spans<'a>(
+    candidates: Vec<LocalHit>,
+    frame: &Frame,
+    ray: &Ray,
+    material: &'a dyn Material,
+) -> Vec<Span<'a>> {
+    let by_t = |a: &&LocalHit, b: &&LocalHit| a.t.total_cmp(&b.t);
+    let valid = || candidates.iter().filter(|hit| !hit.t.is_nan());
+    match (valid().min_by(by_t), valid().max_by(by_t)) {
+        (Some(enter), Some(exit)) if enter.t < exit.t => vec![Span {
+            enter: to_record(enter, frame, ray, material),
+            exit: to_record(exit, frame, ray, material),
+        }],
+        _ => Vec::new(),
+    }
+}
+
+// Pairs up the sorted crossings of a closed, possibly non-convex surface.
+pub fn paired_spans<'a>(
+    mut candidates: Vec<LocalHit>,
+    frame: &Frame,
+    ray: &Ray,
+    material: &'a dyn Material,
+) -> Vec<Span<'a>> {
+    candidates.retain(|hit| !hit.t.is_nan());
+    candidates.sort_by(|a, b| a.t.total_cmp(&b.t));
+    candidates
+        .chunks_exact(2)
+        .map(|pair| Span {
+            enter: to_record(&pair[0], frame, ray, material),
+            exit: to_record(&pair[1], frame, ray, material),
+        })
+        .collect()
+}
+
+fn azimuth(p: Vector3<Float>) -> Float {
+    let phi = p.y.atan2(p.x);
+    (if phi < 0.0 {
+        phi + 2.0 * consts::PI
+    } else {
+        phi
+    }) / (2.0 * consts::PI)
+}
+
+fn z_axis() -> Vector3<Float> {
+    Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    }
+}
+
+// Planes and disks have no inside, so their normal faces the incoming ray.
+pub(crate) fn facing(normal: Vector3<Float>, direction: Vector3<Float>) -> Vector3<Float> {
+    if normal.dot(direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+// Roots of c[2]x² + c[1]x + c[0].
+pub fn solve_quadratic(c: [f64; 3]) -> Vec<f64> {
+    if c[2] == 0.0 {
+        return if c[1] == 0.0 {
+            Vec::new()
+        } else {
+            vec![-c[0] / c[1]]
+        };
+    }
+    let discriminant = c[1] * c[1] - 4.0 * c[2] * c[0];
+    if discriminant < 0.0 {
+        return Vec::new();
+    }
+    // Avoids the cancellation of the textbook formula.
+    let q = -0.5 * (c[1] + c[1].signum() * discriminant.sqrt());
+    if q == 0.0 {
+        // Double root at zero, reported twice like any other double root.
+        return vec![0.0, 0.0];
+    }
+    vec![q / c[2], c[0] / q]
+}
+
+const EPSILON: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EPSILON
+}
+
+// Rewrites a polynomial in x, lowest coefficient first, as a monic one in
+// y = x / scale, where the scale is a bound on the size of the roots. Its
+// roots are then of order one, which is what the fixed epsilon of the
+// closed form solutions below assumes.
+fn normalize(c: &[f64]) -> (Vec<f64>, f64) {
+    let n = c.len() - 1;
+    let scale = (0..n)
+        .map(|i| (c[i] / c[n]).abs().powf(1.0 / (n - i) as f64))
+        .fold(0.0, f64::max);
+    let scale = if scale > 0.0 && scale.is_finite() {
+        scale
+    } else {
+        1.0
+    };
+    let scaled = (0..=n)
+        .map(|i| c[i] / c[n] * scale.powi(i as i32 - n as i32))
+        .collect();
+    (scaled, scale)
+}
+
+// Roots of c[3]x³ + c[2]x² + c[1]x + c[0] (Schwarze, Graphics Gems I).
+pub fn solve_cubic(c: [f64; 4]) -> Vec<f64> {
+    let (c, scale) = normalize(&c);
+    let a = c[2] / c[3];
+    let b = c[1] / c[3];
+    let c0 = c[0] / c[3];
+
+    let sq_a = a * a;
+    let p = (-sq_a / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * sq_a - a * b / 3.0 + c0) / 2.0;
+    let cb_p = p * p * p;
+    let d = q * q + cb_p;
+
+    let mut roots = if is_zero(d) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if d < 0.0 {
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        vec![
+            t * phi.cos(),
+            -t * (phi + f64::consts::PI / 3.0).cos(),
+            -t * (phi - f64::consts::PI / 3.0).cos(),
+        ]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    for root in roots.iter_mut() {
+        *root = (*root - a / 3.0) * scale;
+    }
+    roots
+}
+
+// Roots of c[4]x⁴ + ... + c[0] (Schwarze, Graphics Gems I), polished with a
+// few Newton steps since the closed form loses precision quickly.
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    let (scaled, scale) = normalize(&c);
+    let a = scaled[3];
+    let b = scaled[2];
+    let c1 = scaled[1];
+    let d = scaled[0];
+
+    let sq_a = a * a;
+    let p = -3.0 / 8.0 * sq_a + b;
+    let q = sq_a * a / 8.0 - a * b / 2.0 + c1;
+    let r = -3.0 / 256.0 * sq_a * sq_a + sq_a * b / 16.0 - a * c1 / 4.0 + d;
+
+    let mut roots = if is_zero(r) {
+        let mut roots = solve_cubic([q, p, 0.0, 1.0]);
+        roots.push(0.0);
+        roots
+    } else {
+        let z = solve_cubic([r * p / 2.0 - q * q / 8.0, -r, -p / 2.0, 1.0])[0];
+        let mut u = z * z - r;
+        let mut v = 2.0 * z - p;
+        if is_zero(u) {
+            u = 0.0;
+        } else if u > 0.0 {
+            u = u.sqrt();
+        } else {
+            return Vec::new();
+        }
+        if is_zero(v) {
+            v = 0.0;
+        } else if v > 0.0 {
+            v = v.sqrt();
+        } else {
+            return Vec::new();
+        }
+        let mut roots = solve_quadratic([z - u, if q < 0.0 { -v } else { v }, 1.0]);
+        roots.extend(solve_quadratic([z + u, if q < 0.0 { v } else { -v }, 1.0]));
+        roots
+    };
+
+    for root in roots.iter_mut() {
+        *root = (*root - a / 4.0) * scale;
+        for _ in 0..2 {
+            let x = *root;
+            let f = (((c[4] * x + c[3]) * x + c[2]) * x + c[1]) * x + c[0];
+            let df = ((4.0 * c[4] * x + 3.0 * c[3]) * x + 2.0 * c[2]) * x + c[1];
+            if df != 0.0 {
+                *root = x - f / df;
+            }
+        }
+    }
+    roots
+}
+
+fn roots_float(roots: Vec<f64>) -> impl Iterator<Item = Float> {
+    roots.into_iter().map(|t| t as Float)
+}
+
+// Infinite plane. Its UVs are planar coordinates in world units.
+pub struct Plane {
+    pub point: Vector3<Float>,
+    pub normal: Vector3<Float>,
+    pub material: Box<dyn Material>,
+}
+
+impl Hitable for Plane {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = Frame::new(self.point, self.normal);
+        let local = frame.to_local_ray(ray);
+        if local.direction().z == 0.0 {
+            return None;
+        }
+        let t = -local.origin().z / local.direction().z;
+        let p = local.point_at_parameter(t);
+        let hit = LocalHit {
+            t,
+            normal: facing(z_axis(), local.direction()),
+            u: p.x,
+            v: p.y,
+        };
+        nearest_hit(&[hit], &frame, ray, t_min, t_max, &*self.material)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+fn disk_extent(normal: Vector3<Float>, radius: Float) -> Vector3<Float> {
+    let n = normal.normalize();
+    Vector3 {
+        x: radius * (1.0 - n.x * n.x).max(0.0).sqrt(),
+        y: radius * (1.0 - n.y * n.y).max(0.0).sqrt(),
+        z: radius * (1.0 - n.z * n.z).max(0.0).sqrt(),
+    }
+}
+
+// UVs are polar: u is the angle, v the distance from the center.
+pub struct Disk {
+    pub center: Vector3<Float>,
+    pub normal: Vector3<Float>,
+    pub radius: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl Hitable for Disk {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = Frame::new(self.center, self.normal);
+        let local = frame.to_local_ray(ray);
+        if local.direction().z == 0.0 {
+            return None;
+        }
+        let t = -local.origin().z / local.direction().z;
+        let p = local.point_at_parameter(t);
+        let r2 = p.x * p.x + p.y * p.y;
+        if r2 > self.radius * self.radius {
+            return None;
+        }
+        let hit = LocalHit {
+            t,
+            normal: facing(z_axis(), local.direction()),
+            u: azimuth(p),
+            v: r2.sqrt() / self.radius,
+        };
+        nearest_hit(&[hit], &frame, ray, t_min, t_max, &*self.material)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = disk_extent(self.normal, self.radius);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+// Caps of cylinders and cones, at local height z facing along ±w.
+fn cap(local: &Ray, z: Float, radius: Float, normal_z: Float) -> Option<LocalHit> {
+    if local.direction().z == 0.0 {
+        return None;
+    }
+    let t = (z - local.origin().z) / local.direction().z;
+    let p = local.point_at_parameter(t);
+    let r2 = p.x * p.x + p.y * p.y;
+    if r2 > radius * radius {
+        return None;
+    }
+    Some(LocalHit {
+        t,
+        normal: Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: normal_z,
+        },
+        u: azimuth(p),
+        v: r2.sqrt() / radius,
+    })
+}
+
+// Closed cylinder from `base` along `axis`. On the side u is the angle
+// around the axis and v the height, on the caps they are polar.
+pub struct Cylinder {
+    pub base: Vector3<Float>,
+    pub axis: Vector3<Float>,
+    pub radius: Float,
+    pub height: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl Cylinder {
+    fn frame(&self) -> Frame {
+        Frame::new(self.base, self.axis)
+    }
+
+    fn local_hits(&self, local: &Ray) -> Vec<LocalHit> {
+        let o = local.origin();
+        let d = local.direction();
+
+        let mut candidates = Vec::with_capacity(4);
+        let a = to_f64(d.x * d.x + d.y * d.y);
+        let b = 2.0 * to_f64(o.x * d.x + o.y * d.y);
+        let c = to_f64(o.x * o.x + o.y * o.y - self.radius * self.radius);
+        for t in roots_float(solve_quadratic([c, b, a])) {
+            let p = local.point_at_parameter(t);
+            if p.z >= 0.0 && p.z <= self.height {
+                candidates.push(LocalHit {
+                    t,
+                    normal: Vector3 {
+                        x: p.x,
+                        y: p.y,
+                        z: 0.0,
+                    },
+                    u: azimuth(p),
+                    v: p.z / self.height,
+                });
+            }
+        }
+        candidates.extend(cap(local, 0.0, self.radius, -1.0));
+        candidates.extend(cap(local, self.height, self.radius, 1.0));
+        candidates
+    }
+}
+
+impl Hitable for Cylinder {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        nearest_hit(&candidates, &frame, ray, t_min, t_max, &*self.material)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = disk_extent(self.axis, self.radius);
+        let top = self.base + self.axis.normalize() * self.height;
+        Some(
+            Aabb::new(self.base - extent, self.base + extent)
+                .union(&Aabb::new(top - extent, top + extent)),
+        )
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+impl Solid for Cylinder {
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        convex_spans(candidates, &frame, ray, &*self.material)
+    }
+}
+
+// Closed cone with its base disk at `base` and its apex `height` along
+// `axis`. UVs follow the cylinder.
+pub struct Cone {
+    pub base: Vector3<Float>,
+    pub axis: Vector3<Float>,
+    pub radius: Float,
+    pub height: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl Cone {
+    fn frame(&self) -> Frame {
+        Frame::new(self.base, self.axis)
+    }
+
+    fn local_hits(&self, local: &Ray) -> Vec<LocalHit> {
+        let o = local.origin();
+        let d = local.direction();
+
+        // x² + y² = k²(h - z)²
+        let k = self.radius / self.height;
+        let k2 = to_f64(k * k);
+        let h = to_f64(self.height - o.z);
+        let dz = to_f64(d.z);
+        let a = to_f64(d.x * d.x + d.y * d.y) - k2 * dz * dz;
+        let b = 2.0 * (to_f64(o.x * d.x + o.y * d.y) + k2 * h * dz);
+        let c = to_f64(o.x * o.x + o.y * o.y) - k2 * h * h;
+
+        let mut candidates = Vec::with_capacity(3);
+        for t in roots_float(solve_quadratic([c, b, a])) {
+            let p = local.point_at_parameter(t);
+            if p.z >= 0.0 && p.z <= self.height {
+                // The apex has no well defined normal, use the axis there.
+                let normal = if p.x == 0.0 && p.y == 0.0 {
+                    z_axis()
+                } else {
+                    Vector3 {
+                        x: p.x,
+                        y: p.y,
+                        z: k * k * (self.height - p.z),
+                    }
+                };
+                candidates.push(LocalHit {
+                    t,
+                    normal,
+                    u: azimuth(p),
+                    v: p.z / self.height,
+                });
+            }
+        }
+        candidates.extend(cap(local, 0.0, self.radius, -1.0));
+        candidates
+    }
+}
+
+impl Hitable for Cone {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        nearest_hit(&candidates, &frame, ray, t_min, t_max, &*self.material)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let extent = disk_extent(self.axis, self.radius);
+        let apex = self.base + self.axis.normalize() * self.height;
+        Some(Aabb::new(self.base - extent, self.base + extent).union(&Aabb::new(apex, apex)))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+impl Solid for Cone {
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        convex_spans(candidates, &frame, ray, &*self.material)
+    }
+}
+
+// Torus around `axis`. u runs around the axis, v around the tube.
+pub struct Torus {
+    pub center: Vector3<Float>,
+    pub axis: Vector3<Float>,
+    pub major_radius: Float,
+    pub minor_radius: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl Torus {
+    fn frame(&self) -> Frame {
+        Frame::new(self.center, self.axis)
+    }
+
+    fn local_hits(&self, local: &Ray) -> Vec<LocalHit> {
+        // Solved in units of the major radius along a unit direction, so the
+        // coefficients are of order one whatever the scale of the scene.
+        let scale = to_f64(self.major_radius);
+        let length = to_f64(local.direction().magnitude());
+        let o = local.origin().cast::<f64>().unwrap() / scale;
+        let d = local.direction().cast::<f64>().unwrap() / length;
+        let r2 = 1.0;
+        let s2 = to_f64(self.minor_radius).powi(2) / (scale * scale);
+
+        // (|p|² + R² - r²)² = 4R²(x² + y²)
+        let dd = d.dot(d);
+        let e = o.dot(o) - r2 - s2;
+        let f = o.dot(d);
+        let four_r2 = 4.0 * r2;
+        let coefficients = [
+            e * e - four_r2 * (s2 - o.z * o.z),
+            4.0 * f * e + 2.0 * four_r2 * o.z * d.z,
+            2.0 * dd * e + 4.0 * f * f + four_r2 * d.z * d.z,
+            4.0 * dd * f,
+            dd * dd,
+        ];
+
+        let roots = solve_quartic(coefficients)
+            .into_iter()
+            .map(|t| t * scale / length)
+            .collect();
+        roots_float(roots)
+            .map(|t| {
+                let p = local.point_at_parameter(t);
+                let sum = p.dot(p)
+                    - self.major_radius * self.major_radius
+                    - self.minor_radius * self.minor_radius;
+                let ring = (p.x * p.x + p.y * p.y).sqrt();
+                LocalHit {
+                    t,
+                    normal: Vector3 {
+                        x: p.x * sum,
+                        y: p.y * sum,
+                        z: p.z * (sum + 2.0 * self.major_radius * self.major_radius),
+                    },
+                    u: azimuth(p),
+                    v: azimuth(Vector3 {
+                        x: ring - self.major_radius,
+                        y: p.z,
+                        z: 0.0,
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Hitable for Torus {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        nearest_hit(&candidates, &frame, ray, t_min, t_max, &*self.material)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let outer = self.major_radius + self.minor_radius;
+        let frame = Frame::new(self.center, self.axis);
+        Some(frame.bounding_box(
+            Vector3 {
+                x: -outer,
+                y: -outer,
+                z: -self.minor_radius,
+            },
+            Vector3 {
+                x: outer,
+                y: outer,
+                z: self.minor_radius,
+            },
+        ))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+impl Solid for Torus {
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        let frame = self.frame();
+        let candidates = self.local_hits(&frame.to_local_ray(ray));
+        paired_spans(candidates, &frame, ray, &*self.material)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn index(self) -> usize {
+        match self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+
+    // The two axes spanning the plane perpendicular to this one.
+    pub fn others(self) -> (usize, usize) {
+        match self {
+            Axis::X => (1, 2),
+            Axis::Y => (0, 2),
+            Axis::Z => (0, 1),
+        }
+    }
+
+    pub fn unit(self) -> Vector3<Float> {
+        let mut unit = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        unit[self.index()] = 1.0;
+        unit
+    }
+}
+
+// Rectangle in the plane `axis = k`, spanning [a0, a1] and [b0, b1] along
+// the other two axes in x, y, z order. Like the disk it is two sided.
+pub struct AxisRect {
+    pub axis: Axis,
+    pub a0: Float,
+    pub a1: Float,
+    pub b0: Float,
+    pub b1: Float,
+    pub k: Float,
+    pub material: Box<dyn Material>,
+}
+
+impl Hitable for AxisRect {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let axis = self.axis.index();
+        let (a, b) = self.axis.others();
+        let o = ray.origin();
+        let d = ray.direction();
+        let t = (self.k - o[axis]) / d[axis];
+        if !(t > t_min && t < t_max) {
+            return None;
+        }
+        let mut p = ray.point_at_parameter(t);
+        if p[a] < self.a0 || p[a] > self.a1 || p[b] < self.b0 || p[b] > self.b1 {
+            return None;
+        }
+        // Exactly on the plane, so only the other two axes are uncertain.
+        p[axis] = self.k;
+        let mut error = (abs(o) + abs(p)) * gamma(4);
+        error[axis] = 0.0;
+        let normal = facing(self.axis.unit(), d);
+        Some(HitRecord {
+            t,
+            p,
+            normal,
+            geometric_normal: normal,
+            tangent: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            error,
+            u: (p[a] - self.a0) / (self.a1 - self.a0),
+            v: (p[b] - self.b0) / (self.b1 - self.b0),
+            material: &*self.material,
+            object_id: 0,
+        })
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let axis = self.axis.index();
+        let (a, b) = self.axis.others();
+        let mut min = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let mut max = min;
+        min[axis] = self.k;
+        max[axis] = self.k;
+        min[a] = self.a0;
+        max[a] = self.a1;
+        min[b] = self.b0;
+        max[b] = self.b1;
+        Some(Aabb::new(min, max))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        materials.push(&*self.material);
+    }
+}
+
+// Axis-aligned box between two corners, made of six rectangles sharing one
+// material. Wrap it in a `Transform` to orient it.
+pub struct Cuboid {
+    pub min: Vector3<Float>,
+    pub max: Vector3<Float>,
+    pub faces: Vec<AxisRect>,
+}
+
+impl Cuboid {
+    pub fn new(p0: Vector3<Float>, p1: Vector3<Float>, material: Box<dyn Material>) -> Cuboid {
+        let min = Vector3 {
+            x: p0.x.min(p1.x),
+            y: p0.y.min(p1.y),
+            z: p0.z.min(p1.z),
+        };
+        let max = Vector3 {
+            x: p0.x.max(p1.x),
+            y: p0.y.max(p1.y),
+            z: p0.z.max(p1.z),
+        };
+        let material: Rc<dyn Material> = Rc::from(material);
+        let mut faces = Vec::with_capacity(6);
+        for &axis in [Axis::X, Axis::Y, Axis::Z].iter() {
+            let (a, b) = axis.others();
+            for &k in [min[axis.index()], max[axis.index()]].iter() {
+                faces.push(AxisRect {
+                    axis,
+                    a0: min[a],
+                    a1: max[a],
+                    b0: min[b],
+                    b1: max[b],
+                    k,
+                    material: Box::new(material.clone()),
+                });
+            }
+        }
+        Cuboid { min, max, faces }
+    }
+
+    // Faces report normals towards the ray, a closed box wants them outward.
+    fn outward<'a>(&self, mut record: HitRecord<'a>, face: &AxisRect) -> HitRecord<'a> {
+        let axis = face.axis.index();
+        let center = (self.min[axis] + self.max[axis]) / 2.0;
+        record.normal = face.axis.unit() * if face.k > center { 1.0 } else { -1.0 };
+        record.geometric_normal = record.normal;
+        record
+    }
+}
+
+impl Hitable for Cuboid {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let mut hit = None;
+        let mut closest_so_far = t_max;
+        for face in self.faces.iter() {
+            if let Some(record) = face.hit(ray, t_min, closest_so_far) {
+                closest_so_far = record.t;
+                hit = Some(self.outward(record, face));
+            }
+        }
+        hit
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+
+    fn materials<'a>(&'a self, materials: &mut Vec<&'a dyn Material>) {
+        self.faces.materials(materials);
+    }
+}
+
+impl Solid for Cuboid {
+    fn spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        let mut records: Vec<HitRecord> = self
+            .faces
+            .iter()
+            .filter_map(|face| {
+                face.hit(ray, Float::MIN, Float::MAX)
+                    .map(|record| self.outward(record, face))
+            })
+            .filter(|record| !record.t.is_nan())
+            .collect();
+        records.sort_by(|a, b| a.t.total_cmp(&b.t));
+        match (records.first(), records.last()) {
+            (Some(&enter), Some(&exit)) if enter.t < exit.t => vec![Span { enter, exit }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::Lambertian;
+
+    fn sorted(mut roots: Vec<f64>) -> Vec<f64> {
+        roots.sort_by(|a, b| a.total_cmp(b));
+        roots
+    }
+
+    fn assert_roots(roots: Vec<f64>, expected: &[f64], tolerance: f64) {
+        let roots = sorted(roots);
+        assert_eq!(roots.len(), expected.len(), "roots {:?}", roots);
+        for (root, expected) in roots.iter().zip(expected.iter()) {
+            assert!(
+                (root - expected).abs() <= tolerance * expected.abs().max(1.0),
+                "roots {:?}, expected {:?}",
+                roots,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn quadratic_roots() {
+        assert_roots(solve_quadratic([-6.0, 1.0, 1.0]), &[-3.0, 2.0], 1e-12);
+        assert_roots(solve_quadratic([1.0, -2.0, 1.0]), &[1.0, 1.0], 1e-12);
+        assert_roots(solve_quadratic([1.0, 0.0, 1.0]), &[], 0.0);
+        assert_roots(solve_quadratic([4.0, -2.0, 0.0]), &[2.0], 1e-12);
+        assert_roots(solve_quadratic([0.0, 0.0, 1.0]), &[0.0, 0.0], 0.0);
+        // The small root survives next to a large one.
+        assert_roots(solve_quadratic([1.0, -1e8, 1.0]), &[1e-8, 1e8], 1e-12);
+    }
+
+    #[test]
+    fn cubic_roots() {
+        // (x - 1)(x - 2)(x - 3)
+        assert_roots(solve_cubic([-6.0, 11.0, -6.0, 1.0]), &[1.0, 2.0, 3.0], 1e-9);
+        // (x - 1)²(x - 2), with the double root once
+        assert_roots(solve_cubic([-2.0, 5.0, -4.0, 1.0]), &[1.0, 2.0], 1e-6);
+        // (x - 1)³
+        assert_roots(solve_cubic([-1.0, 3.0, -3.0, 1.0]), &[1.0], 1e-6);
+        // (x - 0.001)(x - 0.002)(x - 0.003)
+        assert_roots(
+            solve_cubic([-6e-9, 11e-6, -6e-3, 1.0]),
+            &[1e-3, 2e-3, 3e-3],
+            1e-9,
+        );
+        // (x - 1)(x² + 1)
+        assert_roots(solve_cubic([-1.0, 1.0, -1.0, 1.0]), &[1.0], 1e-9);
+        // 2(x + 0.5)(x - 4)(x - 10)
+        assert_roots(
+            solve_cubic([40.0, 66.0, -27.0, 2.0]),
+            &[-0.5, 4.0, 10.0],
+            1e-9,
+        );
+    }
+
+    #[test]
+    fn quartic_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(
+            solve_quartic([24.0, -50.0, 35.0, -10.0, 1.0]),
+            &[1.0, 2.0, 3.0, 4.0],
+            1e-9,
+        );
+        // (x - 1)²(x - 3)²
+        assert_roots(
+            solve_quartic([9.0, -24.0, 22.0, -8.0, 1.0]),
+            &[1.0, 1.0, 3.0, 3.0],
+            1e-6,
+        );
+        // (x² + 1)(x - 1)(x + 2)
+        assert_roots(
+            solve_quartic([-2.0, 1.0, -1.0, 1.0, 1.0]),
+            &[-2.0, 1.0],
+            1e-9,
+        );
+        // (x - 100)(x - 200)(x - 300)(x - 400) and the same scaled down
+        assert_roots(
+            solve_quartic([2.4e9, -5e7, 3.5e5, -1e3, 1.0]),
+            &[100.0, 200.0, 300.0, 400.0],
+            1e-9,
+        );
+        assert_roots(
+            solve_quartic([2.4e-7, -5e-5, 3.5e-3, -1e-1, 1.0]),
+            &[0.01, 0.02, 0.03, 0.04],
+            1e-9,
+        );
+        // x⁴ + 1
+        assert_roots(solve_quartic([1.0, 0.0, 0.0, 0.0, 1.0]), &[], 0.0);
+        // x²(x - 1)(x + 1), a double root at zero
+        assert_roots(
+            solve_quartic([0.0, 0.0, -1.0, 0.0, 1.0]),
+            &[-1.0, 0.0, 0.0, 1.0],
+            1e-6,
+        );
+    }
+
+    fn torus(scale: Float) -> Torus {
+        Torus {
+            center: Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            axis: z_axis(),
+            major_radius: scale,
+            minor_radius: 0.25 * scale,
+            material: Box::new(Lambertian {
+                albedo: Vector3 {
+                    x: 0.5,
+                    y: 0.5,
+                    z: 0.5,
+                },
+            }),
+        }
+    }
+
+    fn ray(origin: [Float; 3], direction: [Float; 3]) -> Ray {
+        Ray::new(
+            Vector3 {
+                x: origin[0],
+                y: origin[1],
+                z: origin[2],
+            },
+            Vector3 {
+                x: direction[0],
+                y: direction[1],
+                z: direction[2],
+            },
+        )
+    }
+
+    #[test]
+    fn torus_hits_at_any_scale() {
+        for &scale in [1e-3, 1.0, 1e4].iter() {
+            let torus = torus(scale);
+            let s = scale;
+            // Down the axis, through the hole.
+            let down = ray([0.0, 0.0, 5.0 * s], [0.0, 0.0, -1.0]);
+            assert!(torus.hit(&down, 0.0, Float::MAX).is_none());
+
+            // Parallel to the axis, through the middle of the tube.
+            let through = ray([s, 0.0, 5.0 * s], [0.0, 0.0, -1.0]);
+            let record = torus.hit(&through, 0.0, Float::MAX).unwrap();
+            assert!((record.t - 4.75 * s).abs() < 1e-4 * s);
+            assert!((record.normal.z - 1.0).abs() < 1e-4);
+            assert_eq!(torus.spans(&through).len(), 1);
+
+            // Across, in the plane of the ring.
+            let across = ray([-5.0 * s, 0.0, 0.0], [1.0, 0.0, 0.0]);
+            let spans = torus.spans(&across);
+            assert_eq!(spans.len(), 2);
+            let expected = [3.75, 4.25, 5.75, 6.25];
+            let found = [
+                spans[0].enter.t,
+                spans[0].exit.t,
+                spans[1].enter.t,
+                spans[1].exit.t,
+            ];
+            for (t, expected) in found.iter().zip(expected.iter()) {
+                assert!((t - expected * s).abs() < 1e-4 * s, "{:?}", found);
+            }
+        }
+    }
+
+    #[test]
+    fn torus_grazing_rays() {
+        let torus = torus(1.0);
+        // Along the axis, where the odd coefficients vanish.
+        let axis = ray([0.0, 0.0, -5.0], [0.0, 0.0, 1.0]);
+        assert!(torus.hit(&axis, 0.0, Float::MAX).is_none());
+        assert!(torus.spans(&axis).is_empty());
+
+        // Touching the top of the tube on both sides, double roots at t = 4
+        // and t = 6. Whether a tangent counts as a hit is up to rounding, but
+        // if it does it has to be in the right place.
+        let top = ray([-5.0, 0.0, 0.25], [1.0, 0.0, 0.0]);
+        if let Some(record) = torus.hit(&top, 0.0, Float::MAX) {
+            assert!((record.t - 4.0).abs() < 1e-2, "t = {}", record.t);
+        }
+
+        // Touching the outer equator, a double root at t = 5.
+        let outer = ray([1.25, -5.0, 0.0], [0.0, 1.0, 0.0]);
+        if let Some(record) = torus.hit(&outer, 0.0, Float::MAX) {
+            assert!((record.t - 5.0).abs() < 1e-2, "t = {}", record.t);
+        }
+
+        // Touching the inner equator on its way through the tube, which it
+        // enters at y = -1.
+        let inner = ray([0.75, -5.0, 0.0], [0.0, 1.0, 0.0]);
+        let record = torus.hit(&inner, 0.0, Float::MAX).unwrap();
+        assert!((record.t - 4.0).abs() < 1e-4, "t = {}", record.t);
+
+        // Just outside the tube everywhere.
+        let above = ray([-5.0, 0.0, 0.2501], [1.0, 0.0, 0.0]);
+        assert!(torus.hit(&above, 0.0, Float::MAX).is_none());
+    }
+
+    #[test]
+    fn span_helpers_skip_nan() {
+        let torus = torus(1.0);
+        let frame = torus.frame();
+        let across = ray([-5.0, 0.0, 0.0], [1.0, 0.0, 0.0]);
+        let hit = |t: Float| LocalHit {
+            t,
+            normal: z_axis(),
+            u: 0.0,
+            v: 0.0,
+        };
+        let candidates = vec![hit(Float::NAN), hit(2.0), hit(1.0), hit(Float::NAN)];
+        let material = &*torus.material;
+        let spans = convex_spans(candidates.clone(), &frame, &across, material);
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].enter.t, spans[0].exit.t), (1.0, 2.0));
+        let spans = paired_spans(candidates.clone(), &frame, &across, material);
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].enter.t, spans[0].exit.t), (1.0, 2.0));
+        let record = nearest_hit(&candidates, &frame, &across, 0.0, Float::MAX, material);
+        assert_eq!(record.unwrap().t, 1.0);
+    }
+
+    // Flat boxes have no thickness to pad at large scales, and padding
+    // would swamp the shape at small ones.
+    #[test]
+    fn rectangles_in_a_bvh_hit_at_any_scale() {
+        for &scale in [1e-6, 1.0, 1e6].iter() {
+            let rect = |k: Float| AxisRect {
+                axis: Axis::Y,
+                a0: -scale,
+                a1: scale,
+                b0: -scale,
+                b1: scale,
+                k,
+                material: Box::new(Lambertian {
+                    albedo: Vector3 {
+                        x: 0.5,
+                        y: 0.5,
+                        z: 0.5,
+                    },
+                }),
+            };
+            let world = crate::bvh::BvhHitable::new(vec![rect(0.0), rect(2.0 * scale)]);
+            let above = ray([0.3 * scale, 5.0 * scale, 0.2 * scale], [0.1, -1.0, 0.05]);
+            assert!(rect(0.0)
+                .bounding_box()
+                .unwrap()
+                .hit(&above, 0.0, Float::MAX));
+            let record = world.hit(&above, 0.0, Float::MAX).unwrap();
+            assert_eq!(record.object_id, 1);
+            assert_eq!(record.p.y, 2.0 * scale);
+            let between = ray([0.3 * scale, scale, 0.2 * scale], [-0.1, -1.0, 0.0]);
+            let record = world.hit(&between, 0.0, Float::MAX).unwrap();
+            assert_eq!(record.object_id, 0);
+        }
+    }
+}
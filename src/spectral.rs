@@ -0,0 +1,372 @@
+use crate::lib::{consts, ElementWise, Float, Vector3};
+use std::ops::{Add, AddAssign, Div, Mul};
+
+pub const LAMBDA_MIN: Float = 380.0;
+pub const LAMBDA_MAX: Float = 780.0;
+pub const N_SPECTRUM_SAMPLES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledSpectrum(pub [Float; N_SPECTRUM_SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn constant(value: Float) -> SampledSpectrum {
+        SampledSpectrum([value; N_SPECTRUM_SAMPLES])
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.0.iter().all(|&v| v == 0.0)
+    }
+
+    // Converts the estimate carried by a path into CIE XYZ, normalized so
+    // that a constant spectrum of 1.0 has Y = 1.0.
+    pub fn to_xyz(&self, wavelengths: &SampledWavelengths) -> Vector3<Float> {
+        let mut xyz = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        for i in 0..N_SPECTRUM_SAMPLES {
+            if wavelengths.pdf[i] == 0.0 {
+                continue;
+            }
+            xyz += cie_xyz(wavelengths.lambda[i]) * (self.0[i] / wavelengths.pdf[i]);
+        }
+        xyz / (N_SPECTRUM_SAMPLES as Float * cie_y_integral())
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn add(mut self, other: SampledSpectrum) -> SampledSpectrum {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: SampledSpectrum) {
+        for i in 0..N_SPECTRUM_SAMPLES {
+            self.0[i] += other.0[i];
+        }
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(mut self, other: SampledSpectrum) -> SampledSpectrum {
+        for i in 0..N_SPECTRUM_SAMPLES {
+            self.0[i] *= other.0[i];
+        }
+        self
+    }
+}
+
+impl Mul<Float> for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn mul(mut self, scale: Float) -> SampledSpectrum {
+        for v in self.0.iter_mut() {
+            *v *= scale;
+        }
+        self
+    }
+}
+
+impl Div<Float> for SampledSpectrum {
+    type Output = SampledSpectrum;
+
+    fn div(self, scale: Float) -> SampledSpectrum {
+        self * (1.0 / scale)
+    }
+}
+
+// Hero wavelength sampling: the first wavelength is uniform over the
+// visible range and the others are spaced evenly from it, wrapping around.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [Float; N_SPECTRUM_SAMPLES],
+    pub pdf: [Float; N_SPECTRUM_SAMPLES],
+}
+
+impl SampledWavelengths {
+    pub fn sample_uniform(u: Float) -> SampledWavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let delta = range / N_SPECTRUM_SAMPLES as Float;
+        let mut lambda = [0.0; N_SPECTRUM_SAMPLES];
+        lambda[0] = LAMBDA_MIN + u * range;
+        for i in 1..N_SPECTRUM_SAMPLES {
+            lambda[i] = lambda[i - 1] + delta;
+            if lambda[i] > LAMBDA_MAX {
+                lambda[i] -= range;
+            }
+        }
+        SampledWavelengths {
+            lambda,
+            pdf: [1.0 / range; N_SPECTRUM_SAMPLES],
+        }
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&pdf| pdf == 0.0)
+    }
+
+    // Wavelength-dependent scattering (dispersion) sends each wavelength in
+    // a different direction, so only the hero wavelength can follow the path.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= N_SPECTRUM_SAMPLES as Float;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefractiveIndex {
+    Constant(Float),
+    // n = a + b / λ², λ in micrometers
+    Cauchy { a: Float, b: Float },
+    // n² = 1 + Σ bᵢλ² / (λ² - cᵢ), λ in micrometers
+    Sellmeier { b: [Float; 3], c: [Float; 3] },
+}
+
+impl RefractiveIndex {
+    pub fn bk7() -> RefractiveIndex {
+        RefractiveIndex::Sellmeier {
+            b: [1.039_612, 0.231_792_34, 1.010_469_4],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    pub fn diamond() -> RefractiveIndex {
+        RefractiveIndex::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030_625, 0.011_236, 0.0],
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            RefractiveIndex::Constant(_) => false,
+            RefractiveIndex::Cauchy { b, .. } => b != 0.0,
+            RefractiveIndex::Sellmeier { .. } => true,
+        }
+    }
+
+    pub fn at(&self, lambda: Float) -> Float {
+        let um = lambda / 1000.0;
+        let um2 = um * um;
+        match *self {
+            RefractiveIndex::Constant(n) => n,
+            RefractiveIndex::Cauchy { a, b } => a + b / um2,
+            RefractiveIndex::Sellmeier { b, c } => {
+                let mut n2 = 1.0;
+                for i in 0..3 {
+                    n2 += b[i] * um2 / (um2 - c[i]);
+                }
+                n2.sqrt()
+            }
+        }
+    }
+}
+
+fn smoothstep(edge0: Float, edge1: Float, x: Float) -> Float {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Upsamples an RGB reflectance into a smooth spectrum. The three basis
+// functions sum to one, so white stays exactly flat.
+pub fn rgb_to_spectrum(rgb: Vector3<Float>, wavelengths: &SampledWavelengths) -> SampledSpectrum {
+    let mut spectrum = SampledSpectrum::constant(0.0);
+    for i in 0..N_SPECTRUM_SAMPLES {
+        let lambda = wavelengths.lambda[i];
+        let blue = 1.0 - smoothstep(470.0, 520.0, lambda);
+        let red = smoothstep(570.0, 620.0, lambda);
+        let green = 1.0 - blue - red;
+        spectrum.0[i] = rgb.x * red + rgb.y * green + rgb.z * blue;
+    }
+    spectrum
+}
+
+fn lobe(x: Float, mu: Float, sigma1: Float, sigma2: Float) -> Float {
+    let t = (x - mu) / if x < mu { sigma1 } else { sigma2 };
+    (-0.5 * t * t).exp()
+}
+
+// Multi-lobe fit of the CIE 1931 2° observer (Wyman, Sloan and Shirley 2013).
+pub fn cie_xyz(lambda: Float) -> Vector3<Float> {
+    Vector3 {
+        x: 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * lobe(lambda, 501.1, 20.4, 26.2),
+        y: 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1),
+        z: 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8),
+    }
+}
+
+fn lobe_integral(sigma1: Float, sigma2: Float) -> Float {
+    (consts::PI / 2.0).sqrt() * (sigma1 + sigma2)
+}
+
+// Closed-form integrals of the fit above; the tails outside the visible
+// range are negligible.
+fn cie_integrals() -> Vector3<Float> {
+    Vector3 {
+        x: 1.056 * lobe_integral(37.9, 31.0) + 0.362 * lobe_integral(16.0, 26.7)
+            - 0.065 * lobe_integral(20.4, 26.2),
+        y: 0.821 * lobe_integral(46.9, 40.5) + 0.286 * lobe_integral(16.3, 31.1),
+        z: 1.217 * lobe_integral(11.8, 36.0) + 0.681 * lobe_integral(26.0, 13.8),
+    }
+}
+
+fn cie_y_integral() -> Float {
+    cie_integrals().y
+}
+
+pub fn xyz_to_linear_srgb(xyz: Vector3<Float>) -> Vector3<Float> {
+    Vector3 {
+        x: 3.240_454_2 * xyz.x - 1.537_138_5 * xyz.y - 0.498_531_4 * xyz.z,
+        y: -0.969_266 * xyz.x + 1.876_010_8 * xyz.y + 0.041_556 * xyz.z,
+        z: 0.055_643_4 * xyz.x - 0.204_025_9 * xyz.y + 1.057_225_2 * xyz.z,
+    }
+}
+
+// Linear sRGB of a constant spectrum, used to white balance spectral renders
+// so that an RGB white material under a white sky comes out as RGB white.
+pub fn equal_energy_white() -> Vector3<Float> {
+    xyz_to_linear_srgb(cie_integrals() / cie_y_integral())
+}
+
+pub fn xyz_to_balanced_srgb(xyz: Vector3<Float>, white: Vector3<Float>) -> Vector3<Float> {
+    xyz_to_linear_srgb(xyz).div_element_wise(white)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{DispersiveDielectric, Hitable, InnerSpace, Ray, Sphere};
+
+    // The average XYZ over stratified hero wavelengths of a spectrum given
+    // at the sampled wavelengths.
+    fn average_xyz<F>(spectrum: F, terminate: bool) -> Vector3<Float>
+    where
+        F: Fn(&SampledWavelengths) -> SampledSpectrum,
+    {
+        let n = 1000;
+        let mut sum = Vector3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        for k in 0..n {
+            let mut wavelengths =
+                SampledWavelengths::sample_uniform((k as Float + 0.5) / n as Float);
+            if terminate {
+                wavelengths.terminate_secondary();
+            }
+            sum += spectrum(&wavelengths).to_xyz(&wavelengths);
+        }
+        sum / n as Float
+    }
+
+    #[test]
+    fn flat_spectra_are_white() {
+        let xyz = average_xyz(|_| SampledSpectrum::constant(1.0), false);
+        assert!((xyz.y - 1.0).abs() < 1e-3, "Y = {}", xyz.y);
+        // Equal energy white has x = y = 1/3, give or take the fit.
+        let sum = xyz.x + xyz.y + xyz.z;
+        assert!((xyz.x / sum - 1.0 / 3.0).abs() < 0.01);
+        assert!((xyz.y / sum - 1.0 / 3.0).abs() < 0.01);
+
+        let rgb = xyz_to_balanced_srgb(xyz, equal_energy_white());
+        for &c in [rgb.x, rgb.y, rgb.z].iter() {
+            assert!((c - 1.0).abs() < 1e-2, "{:?}", rgb);
+        }
+        let half = average_xyz(|_| SampledSpectrum::constant(0.5), false);
+        assert!((half * 2.0 - xyz).magnitude() < 1e-5);
+    }
+
+    // Catalogue indices of N-BK7 at the F, d and C lines.
+    #[test]
+    fn bk7_matches_its_catalogue_indices() {
+        let bk7 = RefractiveIndex::bk7();
+        for &(lambda, n) in [(486.13, 1.522_38), (587.56, 1.516_80), (656.27, 1.514_32)].iter() {
+            assert!(
+                (bk7.at(lambda) - n).abs() < 1e-4,
+                "{} at {}",
+                bk7.at(lambda),
+                lambda
+            );
+        }
+        assert!(bk7.is_dispersive());
+        assert!(!RefractiveIndex::Constant(1.5).is_dispersive());
+        assert!(!RefractiveIndex::Cauchy { a: 1.5, b: 0.0 }.is_dispersive());
+    }
+
+    #[test]
+    fn terminating_secondary_wavelengths_keeps_the_estimate() {
+        let mut wavelengths = SampledWavelengths::sample_uniform(0.3);
+        let hero = wavelengths.pdf[0];
+        wavelengths.terminate_secondary();
+        assert!(wavelengths.secondary_terminated());
+        assert_eq!(wavelengths.pdf[0], hero / N_SPECTRUM_SAMPLES as Float);
+        // A second dispersive hit on the same path changes nothing.
+        let terminated = wavelengths;
+        wavelengths.terminate_secondary();
+        assert_eq!(wavelengths, terminated);
+
+        // The hero wavelength alone, weighted up, estimates the same color.
+        let orange = Vector3 {
+            x: 0.9,
+            y: 0.5,
+            z: 0.1,
+        };
+        let spectrum = |wavelengths: &SampledWavelengths| rgb_to_spectrum(orange, wavelengths);
+        let all = average_xyz(spectrum, false);
+        let hero_only = average_xyz(spectrum, true);
+        assert!((all - hero_only).magnitude() < 1e-3 * all.magnitude());
+    }
+
+    #[test]
+    fn only_dispersive_hits_terminate_secondary_wavelengths() {
+        for &(ior, dispersive) in [
+            (RefractiveIndex::bk7(), true),
+            (RefractiveIndex::Constant(1.5), false),
+        ]
+        .iter()
+        {
+            let sphere = Sphere {
+                center: Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+                material: Box::new(DispersiveDielectric { ior }),
+            };
+            let ray = Ray::new(
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 5.0,
+                },
+                Vector3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -1.0,
+                },
+            );
+            let record = sphere.hit(&ray, 0.0, Float::MAX).unwrap();
+            let mut wavelengths = SampledWavelengths::sample_uniform(0.5);
+            record
+                .material
+                .scatter_spectral(&ray, &record, &mut wavelengths)
+                .unwrap();
+            assert_eq!(wavelengths.secondary_terminated(), dispersive);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::lib::{Camera, Float, Ray, ThinLensCamera};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoLayout {
+    // Left eye in the left half of the image.
+    SideBySide,
+    // Left eye in the top half of the image.
+    OverUnder,
+}
+
+// Two cameras a small distance apart, rendered into one image. The views are
+// parallel and converge by shifting their image windows instead of turning
+// inwards, so the frusta are off-axis and there is no vertical parallax.
+// Each eye sees half of the image, so the camera the rig is built from
+// should have the aspect ratio of that half.
+#[derive(Clone)]
+pub struct StereoRig {
+    pub left: ThinLensCamera,
+    pub right: ThinLensCamera,
+    pub layout: StereoLayout,
+}
+
+// `camera` moved along u by `offset`, with its window shifted so that it
+// still covers the center camera's view at the convergence distance.
+fn eye(camera: &ThinLensCamera, offset: Float, convergence: Float) -> ThinLensCamera {
+    let mut eye = camera.clone();
+    let shift = camera.u * offset;
+    eye.origin += shift;
+    eye.lower_left_corner += shift * (1.0 - camera.focus_dist() / convergence);
+    eye
+}
+
+impl StereoRig {
+    // Objects at `convergence` from the camera appear at screen depth, nearer
+    // ones in front of the screen and farther ones behind it. `interaxial`
+    // is the distance between the eyes, 0.065 for human eyes in meters.
+    pub fn new(
+        camera: &ThinLensCamera,
+        interaxial: Float,
+        convergence: Float,
+        layout: StereoLayout,
+    ) -> StereoRig {
+        StereoRig {
+            left: eye(camera, -interaxial / 2.0, convergence),
+            right: eye(camera, interaxial / 2.0, convergence),
+            layout,
+        }
+    }
+}
+
+impl Camera for StereoRig {
+    fn get_ray(&self, s: Float, t: Float) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_ray(s * 2.0, t),
+            StereoLayout::SideBySide => self.right.get_ray(s * 2.0 - 1.0, t),
+            StereoLayout::OverUnder if t >= 0.5 => self.left.get_ray(s, t * 2.0 - 1.0),
+            StereoLayout::OverUnder => self.right.get_ray(s, t * 2.0),
+        }
+    }
+
+    fn exposure(&self) -> Float {
+        self.left.exposure()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::{InnerSpace, Vector3};
+
+    fn point(x: Float, y: Float, z: Float) -> Vector3<Float> {
+        Vector3 { x, y, z }
+    }
+
+    // A pinhole focused at 2, so that the windows have to shift for the
+    // eyes to converge at 5.
+    fn rig(layout: StereoLayout) -> StereoRig {
+        let camera = ThinLensCamera::new(
+            point(0.0, 1.0, 0.0),
+            point(0.0, 1.0, -1.0),
+            point(0.0, 1.0, 0.0),
+            50.0,
+            1.0,
+            0.0,
+            2.0,
+        );
+        StereoRig::new(&camera, 0.2, 5.0, layout)
+    }
+
+    // Where a point appears in the image of a pinhole camera.
+    fn project(camera: &ThinLensCamera, p: Vector3<Float>) -> (Float, Float) {
+        let d = p - camera.origin;
+        let q = camera.origin + d * (camera.focus_dist() / -d.dot(camera.w));
+        let r = q - camera.lower_left_corner;
+        (
+            r.dot(camera.horizontal) / camera.horizontal.magnitude2(),
+            r.dot(camera.vertical) / camera.vertical.magnitude2(),
+        )
+    }
+
+    #[test]
+    fn points_at_the_convergence_distance_line_up() {
+        let rig = rig(StereoLayout::SideBySide);
+        for &(x, y) in [(0.0, 1.0), (1.2, 0.3), (-0.7, 2.1)].iter() {
+            let (left, right) = (
+                project(&rig.left, point(x, y, -5.0)),
+                project(&rig.right, point(x, y, -5.0)),
+            );
+            assert!((left.0 - right.0).abs() < 1e-5 && (left.1 - right.1).abs() < 1e-5);
+            // Nearer points cross in front of the screen, farther ones
+            // diverge behind it, and neither moves vertically.
+            for &(depth, crossed) in [(3.0, true), (20.0, false)].iter() {
+                let (left, right) = (
+                    project(&rig.left, point(x, y, -depth)),
+                    project(&rig.right, point(x, y, -depth)),
+                );
+                assert_eq!(left.0 > right.0, crossed);
+                assert!((left.1 - right.1).abs() < 1e-5);
+            }
+        }
+    }
+
+    fn same(a: Option<Ray>, b: Option<Ray>) -> bool {
+        let (a, b) = (a.unwrap(), b.unwrap());
+        (a.origin() - b.origin()).magnitude() < 1e-6
+            && (a.direction() - b.direction()).magnitude() < 1e-6
+    }
+
+    #[test]
+    fn layouts_route_samples_to_their_eye() {
+        let side_by_side = rig(StereoLayout::SideBySide);
+        let (left, right) = (&side_by_side.left, &side_by_side.right);
+        assert!(same(side_by_side.get_ray(0.1, 0.3), left.get_ray(0.2, 0.3)));
+        assert!(same(
+            side_by_side.get_ray(0.8, 0.3),
+            right.get_ray(0.6, 0.3)
+        ));
+        assert!(side_by_side.get_ray(0.1, 0.3).unwrap().origin().x < 0.0);
+
+        let over_under = rig(StereoLayout::OverUnder);
+        let (left, right) = (&over_under.left, &over_under.right);
+        assert!(same(over_under.get_ray(0.3, 0.9), left.get_ray(0.3, 0.8)));
+        assert!(same(over_under.get_ray(0.3, 0.2), right.get_ray(0.3, 0.4)));
+        assert!(over_under.get_ray(0.3, 0.9).unwrap().origin().x < 0.0);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::aov::Layer;
+use crate::lib::{Camera, Float, Hitable, Vector3};
+use crate::render::{accumulate_pass, to_rgb8, Accumulator, Image, Settings};
+use std::env;
+use std::io::{self, Write};
+
+const RAMP: &[u8] = b" .:-=+*#%@";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    // Upper half blocks with 24-bit foreground and background colors, two
+    // pixels per character.
+    HalfBlock,
+    // Characters of increasing density for terminals without colors.
+    Ascii,
+}
+
+impl Charset {
+    // Half blocks if the terminal says it has 24-bit color.
+    pub fn detect() -> Charset {
+        match env::var("COLORTERM") {
+            Ok(ref value) if value == "truecolor" || value == "24bit" => Charset::HalfBlock,
+            _ => Charset::Ascii,
+        }
+    }
+}
+
+// Draws images into the terminal, each over the previous one, so a render
+// can be watched over SSH. Terminal characters are about twice as tall as
+// they are wide, which the number of lines makes up for.
+pub struct TerminalPreview {
+    pub charset: Charset,
+    pub columns: usize,
+    lines: usize,
+}
+
+// The pixels of `size` that cell k of n covers, at least one. Integer
+// bounds keep cells that line up with pixels from taking in a neighbour.
+fn cell(k: usize, n: usize, size: usize) -> (usize, usize) {
+    let start = k * size / n;
+    let end = ((k + 1) * size + n - 1) / n;
+    (start, end.clamp(start + 1, size))
+}
+
+// Average of the pixels in the box from (i0, j0) up to (i1, j1).
+fn average(image: &Image, (i0, i1): (usize, usize), (j0, j1): (usize, usize)) -> Vector3<Float> {
+    let mut sum = Vector3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+    for j in j0..j1 {
+        for i in i0..i1 {
+            sum += image.get(i, j);
+        }
+    }
+    sum / ((i1 - i0) * (j1 - j0)) as Float
+}
+
+impl TerminalPreview {
+    pub fn new(charset: Charset, columns: usize) -> TerminalPreview {
+        TerminalPreview {
+            charset,
+            columns,
+            lines: 0,
+        }
+    }
+
+    // Images without pixels draw nothing.
+    pub fn draw<W: Write>(&mut self, image: &Image, out: &mut W) -> io::Result<()> {
+        if image.width == 0 || image.height == 0 {
+            return Ok(());
+        }
+        let columns = self.columns.clamp(1, image.width);
+        let lines = ((columns * image.height) as Float / (2 * image.width) as Float)
+            .round()
+            .max(1.0) as usize;
+        let mut text = String::new();
+        if self.lines > 0 {
+            // Back up to the top of the previous frame.
+            text.push_str(&format!("\x1b[{}A\r", self.lines));
+        }
+        for line in 0..lines {
+            for column in 0..columns {
+                let x = cell(column, columns, image.width);
+                match self.charset {
+                    Charset::HalfBlock => {
+                        let top = cell(2 * line, 2 * lines, image.height);
+                        let bottom = cell(2 * line + 1, 2 * lines, image.height);
+                        let [r0, g0, b0] = to_rgb8(average(image, x, top));
+                        let [r1, g1, b1] = to_rgb8(average(image, x, bottom));
+                        text.push_str(&format!(
+                            "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                            r0, g0, b0, r1, g1, b1
+                        ));
+                    }
+                    Charset::Ascii => {
+                        let y = cell(line, lines, image.height);
+                        let [r, g, b] = to_rgb8(average(image, x, y));
+                        let luminance =
+                            (0.2126 * r as Float + 0.7152 * g as Float + 0.0722 * b as Float)
+                                / 255.0;
+                        let index = (luminance * (RAMP.len() - 1) as Float).round() as usize;
+                        text.push(RAMP[index.min(RAMP.len() - 1)] as char);
+                    }
+                }
+            }
+            if self.charset == Charset::HalfBlock {
+                text.push_str("\x1b[0m");
+            }
+            text.push('\n');
+        }
+        self.lines = lines;
+        out.write_all(text.as_bytes())?;
+        out.flush()
+    }
+}
+
+// Like `render_passes`, but draws the image so far into the terminal every
+// `interval` passes and once more at the end.
+pub fn render_with_preview<W: Write>(
+    world: &dyn Hitable,
+    camera: &dyn Camera,
+    settings: &Settings,
+    preview: &mut TerminalPreview,
+    interval: usize,
+    out: &mut W,
+) -> io::Result<(Image, Vec<Layer>)> {
+    let mut accumulator = Accumulator::new(settings);
+    while accumulator.passes < settings.samples {
+        accumulate_pass(world, camera, settings, &mut accumulator);
+        if accumulator.passes % interval.max(1) == 0 && accumulator.passes < settings.samples {
+            preview.draw(&accumulator.image(), out)?;
+        }
+    }
+    let image = accumulator.image();
+    preview.draw(&image, out)?;
+    Ok((image, accumulator.layers()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(value: Float) -> Vector3<Float> {
+        Vector3 {
+            x: value,
+            y: value,
+            z: value,
+        }
+    }
+
+    fn draw(preview: &mut TerminalPreview, image: &Image) -> String {
+        let mut out = Vec::new();
+        preview.draw(image, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // Ten columns stepping through the ramp, two pixels tall so that each
+    // makes one character.
+    #[test]
+    fn ascii_follows_the_ramp() {
+        let mut image = Image::new(10, 2);
+        for i in 0..10 {
+            // Squared to undo the gamma of the preview.
+            let value = (i as Float / 9.0).powi(2);
+            image.set(i, 0, grey(value));
+            image.set(i, 1, grey(value));
+        }
+        let mut preview = TerminalPreview::new(Charset::Ascii, 10);
+        assert_eq!(draw(&mut preview, &image), " .:-=+*#%@\n");
+        // The next frame goes over this one.
+        assert_eq!(draw(&mut preview, &image), "\x1b[1A\r .:-=+*#%@\n");
+    }
+
+    #[test]
+    fn half_blocks_color_the_top_and_bottom_pixel() {
+        let mut image = Image::new(1, 2);
+        image.set(
+            0,
+            0,
+            Vector3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        );
+        image.set(
+            0,
+            1,
+            Vector3 {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        );
+        let mut preview = TerminalPreview::new(Charset::HalfBlock, 80);
+        assert_eq!(
+            draw(&mut preview, &image),
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn empty_images_draw_nothing() {
+        for &(width, height) in [(0, 4), (4, 0), (0, 0)].iter() {
+            for &charset in [Charset::Ascii, Charset::HalfBlock].iter() {
+                let mut preview = TerminalPreview::new(charset, 80);
+                assert_eq!(draw(&mut preview, &Image::new(width, height)), "");
+            }
+        }
+    }
+}